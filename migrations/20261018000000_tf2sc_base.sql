@@ -1,5 +1,17 @@
-CREATE TYPE item_slot AS ENUM ('primary', 'secondary', 'melee');
-CREATE TYPE merc AS ENUM ('Scout', 'Soldier', 'Pyro', 'Demoman', 'Heavy', 'Engineer', 'Medic', 'Sniper', 'Spy');
+-- the tf2sc tables as they were made by hand before there were migrations, so everything here has to work on top of them too
+DO $$
+BEGIN
+    CREATE TYPE item_slot AS ENUM ('primary', 'secondary', 'melee');
+EXCEPTION
+    WHEN duplicate_object THEN NULL;
+END $$;
+
+DO $$
+BEGIN
+    CREATE TYPE merc AS ENUM ('Scout', 'Soldier', 'Pyro', 'Demoman', 'Heavy', 'Engineer', 'Medic', 'Sniper', 'Spy');
+EXCEPTION
+    WHEN duplicate_object THEN NULL;
+END $$;
 
 CREATE TABLE IF NOT EXISTS weapons (
     id INT PRIMARY KEY,
@@ -24,7 +36,7 @@ CREATE TABLE IF NOT EXISTS weapon_per_class_loadout_slots (
     PRIMARY KEY (weapon_id, merc)
 );
 
-CREATE VIEW IF NOT EXISTS weapon_details AS
+CREATE OR REPLACE VIEW weapon_details AS
 SELECT
     w.id,
     w.name,
@@ -35,7 +47,7 @@ SELECT
     w.image_url_large,
     ubc.merc AS merc  -- can be NULL
 FROM weapons AS w
-LEFT JOIN weapon_used_by_classes AS ubc 
+LEFT JOIN weapon_used_by_classes AS ubc
 ON w.id = ubc.weapon_id
 LEFT JOIN weapon_per_class_loadout_slots AS pcls
 ON w.id = pcls.weapon_id
@@ -55,40 +67,48 @@ CREATE TABLE IF NOT EXISTS loadouts (
     updated_at TIMESTAMP WITH TIME ZONE DEFAULT CURRENT_TIMESTAMP
 );
 
-CREATE VIEW full_loadouts AS
-SELECT
-    l.*,
-    jsonb_build_object(
-        'id', wd_primary.id,
-        'name', wd_primary.name,
-        'stock', wd_primary.stock,
-        'item_name', wd_primary.item_name,
-        'item_slot', wd_primary.item_slot,
-        'image_url', wd_primary.image_url,
-        'image_url_large', wd_primary.image_url_large
-    ) AS primary_weapon,
-    jsonb_build_object(
-        'id', wd_secondary.id,
-        'name', wd_secondary.name,
-        'stock', wd_secondary.stock,
-        'item_name', wd_secondary.item_name,
-        'item_slot', wd_secondary.item_slot,
-        'image_url', wd_secondary.image_url,
-        'image_url_large', wd_secondary.image_url_large
-    ) AS secondary_weapon,
-    jsonb_build_object(
-        'id', wd_melee.id,
-        'name', wd_melee.name,
-        'stock', wd_melee.stock,
-        'item_name', wd_melee.item_name,
-        'item_slot', wd_melee.item_slot,
-        'image_url', wd_melee.image_url,
-        'image_url_large', wd_melee.image_url_large
-    ) AS melee_weapon
-FROM loadouts l
-LEFT JOIN weapons wd_primary ON l.primary = wd_primary.id
-LEFT JOIN weapons wd_secondary ON l.secondary = wd_secondary.id
-LEFT JOIN weapons wd_melee ON l.melee = wd_melee.id;
+-- made again by the moderation migration, once the loadouts have more columns
+DO $$
+BEGIN
+    IF to_regclass('full_loadouts') IS NOT NULL THEN
+        RETURN;
+    END IF;
+
+    CREATE VIEW full_loadouts AS
+    SELECT
+        l.*,
+        jsonb_build_object(
+            'id', wd_primary.id,
+            'name', wd_primary.name,
+            'stock', wd_primary.stock,
+            'item_name', wd_primary.item_name,
+            'item_slot', wd_primary.item_slot,
+            'image_url', wd_primary.image_url,
+            'image_url_large', wd_primary.image_url_large
+        ) AS primary_weapon,
+        jsonb_build_object(
+            'id', wd_secondary.id,
+            'name', wd_secondary.name,
+            'stock', wd_secondary.stock,
+            'item_name', wd_secondary.item_name,
+            'item_slot', wd_secondary.item_slot,
+            'image_url', wd_secondary.image_url,
+            'image_url_large', wd_secondary.image_url_large
+        ) AS secondary_weapon,
+        jsonb_build_object(
+            'id', wd_melee.id,
+            'name', wd_melee.name,
+            'stock', wd_melee.stock,
+            'item_name', wd_melee.item_name,
+            'item_slot', wd_melee.item_slot,
+            'image_url', wd_melee.image_url,
+            'image_url_large', wd_melee.image_url_large
+        ) AS melee_weapon
+    FROM loadouts l
+    LEFT JOIN weapons wd_primary ON l.primary = wd_primary.id
+    LEFT JOIN weapons wd_secondary ON l.secondary = wd_secondary.id
+    LEFT JOIN weapons wd_melee ON l.melee = wd_melee.id;
+END $$;
 
 
 CREATE OR REPLACE FUNCTION check_loadout_weapons() RETURNS TRIGGER AS $$
@@ -130,11 +150,13 @@ BEGIN
 END;
 $$ LANGUAGE plpgsql;
 
+DROP TRIGGER IF EXISTS validate_loadout_weapons_insert ON loadouts;
 CREATE TRIGGER validate_loadout_weapons_insert
 BEFORE INSERT ON loadouts
 FOR EACH ROW
 EXECUTE FUNCTION check_loadout_weapons();
 
+DROP TRIGGER IF EXISTS validate_loadout_weapons_update ON loadouts;
 CREATE TRIGGER validate_loadout_weapons_update
 BEFORE UPDATE ON loadouts
 FOR EACH ROW
@@ -147,7 +169,8 @@ BEGIN
 END;
 $$ LANGUAGE plpgsql;
 
+DROP TRIGGER IF EXISTS set_updated_at ON loadouts;
 CREATE TRIGGER set_updated_at
 BEFORE UPDATE ON loadouts
 FOR EACH ROW
-EXECUTE FUNCTION update_updated_at_column();
\ No newline at end of file
+EXECUTE FUNCTION update_updated_at_column();
@@ -0,0 +1,6 @@
+-- the jp2 quotes, used to live in supabase
+CREATE TABLE IF NOT EXISTS quote (
+    id BIGSERIAL PRIMARY KEY,
+    quote TEXT NOT NULL,
+    translation TEXT NOT NULL
+);
@@ -0,0 +1,8 @@
+-- metadata of the jp2 images, the files themselves are in the `jp2` bucket of the blob store
+-- (replaces the supabase `storage.objects` table)
+CREATE TABLE IF NOT EXISTS images (
+    id BIGSERIAL PRIMARY KEY,
+    name TEXT NOT NULL UNIQUE,
+    content_type TEXT NOT NULL,
+    created_at TIMESTAMPTZ NOT NULL DEFAULT now()
+);
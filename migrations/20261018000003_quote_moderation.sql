@@ -0,0 +1,16 @@
+-- submitted quotes wait for an admin, the ones from before submissions existed are approved already
+CREATE TYPE quote_status AS ENUM ('pending', 'approved', 'rejected');
+
+ALTER TABLE quote
+    ADD COLUMN status quote_status NOT NULL DEFAULT 'approved',
+    ADD COLUMN submitted_by TEXT,
+    ADD COLUMN moderated_by TEXT,
+    ADD COLUMN created_at TIMESTAMPTZ NOT NULL DEFAULT now(),
+    ADD COLUMN updated_at TIMESTAMPTZ NOT NULL DEFAULT now(),
+    -- `simple`, because the quotes are polish and the translations english
+    ADD COLUMN search tsvector GENERATED ALWAYS AS (to_tsvector('simple', quote || ' ' || translation)) STORED;
+
+ALTER TABLE quote ALTER COLUMN status SET DEFAULT 'pending';
+
+CREATE INDEX quote_search_idx ON quote USING GIN (search);
+CREATE INDEX quote_status_idx ON quote (status);
@@ -0,0 +1,11 @@
+-- uploaded jp2 images, the rows from before uploads have no dimensions, hash or uploader
+ALTER TABLE images
+    ADD COLUMN width INTEGER,
+    ADD COLUMN height INTEGER,
+    ADD COLUMN size_bytes BIGINT,
+    ADD COLUMN sha256 TEXT UNIQUE,
+    ADD COLUMN uploaded_by TEXT,
+    ADD COLUMN tags TEXT[] NOT NULL DEFAULT '{}';
+
+-- for the `tags @> ...` filters
+CREATE INDEX IF NOT EXISTS images_tags_idx ON images USING GIN (tags);
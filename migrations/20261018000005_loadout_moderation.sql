@@ -0,0 +1,42 @@
+-- hidden loadouts
+ALTER TABLE loadouts
+    ADD COLUMN IF NOT EXISTS hidden BOOLEAN NOT NULL DEFAULT false,
+    ADD COLUMN IF NOT EXISTS hidden_by TEXT,
+    ADD COLUMN IF NOT EXISTS hidden_reason TEXT;
+
+-- `l.*` was expanded when the view was made, so it has to be made again to get the new columns
+DROP VIEW IF EXISTS full_loadouts;
+CREATE VIEW full_loadouts AS
+SELECT
+    l.*,
+    jsonb_build_object(
+        'id', wd_primary.id,
+        'name', wd_primary.name,
+        'stock', wd_primary.stock,
+        'item_name', wd_primary.item_name,
+        'item_slot', wd_primary.item_slot,
+        'image_url', wd_primary.image_url,
+        'image_url_large', wd_primary.image_url_large
+    ) AS primary_weapon,
+    jsonb_build_object(
+        'id', wd_secondary.id,
+        'name', wd_secondary.name,
+        'stock', wd_secondary.stock,
+        'item_name', wd_secondary.item_name,
+        'item_slot', wd_secondary.item_slot,
+        'image_url', wd_secondary.image_url,
+        'image_url_large', wd_secondary.image_url_large
+    ) AS secondary_weapon,
+    jsonb_build_object(
+        'id', wd_melee.id,
+        'name', wd_melee.name,
+        'stock', wd_melee.stock,
+        'item_name', wd_melee.item_name,
+        'item_slot', wd_melee.item_slot,
+        'image_url', wd_melee.image_url,
+        'image_url_large', wd_melee.image_url_large
+    ) AS melee_weapon
+FROM loadouts l
+LEFT JOIN weapons wd_primary ON l.primary = wd_primary.id
+LEFT JOIN weapons wd_secondary ON l.secondary = wd_secondary.id
+LEFT JOIN weapons wd_melee ON l.melee = wd_melee.id;
@@ -0,0 +1,11 @@
+-- indexes for paging, filtering and searching the loadouts
+
+-- keyset paging sorts by one of the timestamps with the id breaking ties
+CREATE INDEX IF NOT EXISTS loadouts_created_at_id_idx ON loadouts (created_at, id);
+CREATE INDEX IF NOT EXISTS loadouts_updated_at_id_idx ON loadouts (updated_at, id);
+CREATE INDEX IF NOT EXISTS loadouts_user_id_idx ON loadouts (user_id);
+CREATE INDEX IF NOT EXISTS loadouts_merc_idx ON loadouts (merc);
+
+-- has to be the same expression as in the queries
+CREATE INDEX IF NOT EXISTS loadouts_search_idx ON loadouts
+    USING GIN (to_tsvector('simple', name || ' ' || playstyle));
@@ -0,0 +1,109 @@
+use poise::serenity_prelude::{CreateEmbed, CreateEmbedFooter, User};
+use poise::CreateReply;
+
+use crate::web::cats::{ids::CatId, model::CatForCreate, rarities::RarityTable, service};
+use super::{Context, Error};
+
+const COLLECTION_PREVIEW_SIZE: usize = 10;
+
+/// Discord users share the unbox inventories with JWT users, so their ids get a prefix to never collide.
+fn discord_user_id(user: &User) -> String {
+    format!("discord:{}", user.id)
+}
+
+
+/// Unbox a random cat
+#[poise::command(slash_command)]
+pub async fn unbox(ctx: Context<'_>) -> Result<(), Error> {
+    // the unboxing does a few requests, which can take longer than the 3s discord gives us
+    ctx.defer().await?;
+
+    let user_id = discord_user_id(ctx.author());
+    let cat = service::unbox_random_cat(&ctx.data().cats, Some(&user_id)).await?;
+
+    ctx.send(CreateReply::default().embed(cat_embed(&cat, &ctx.data().cats.rarities))).await?;
+    Ok(())
+}
+
+/// Look up a previously unboxed cat
+#[poise::command(slash_command)]
+pub async fn cat(
+    ctx: Context<'_>,
+    #[description = "The id of the cat"] id: String
+) -> Result<(), Error> {
+    let id = match CatId::parse(&id) {
+        Ok(id) => id,
+        Err(e) => {
+            ctx.send(CreateReply::default().content(e.to_string()).ephemeral(true)).await?;
+            return Ok(());
+        }
+    };
+
+    match service::get_cat(&ctx.data().cats, id.as_str()).await {
+        Ok(cat) => {
+            ctx.send(CreateReply::default().embed(cat_embed(&cat.into(), &ctx.data().cats.rarities))).await?;
+        },
+        Err(crate::web::cats::Error::NotFound { id }) => {
+            ctx.send(CreateReply::default().content(format!("No cat with id `{}` was unboxed yet", id)).ephemeral(true)).await?;
+        },
+        Err(e) => return Err(e.into())
+    }
+
+    Ok(())
+}
+
+/// Show the cats someone has unboxed
+#[poise::command(slash_command)]
+pub async fn collection(
+    ctx: Context<'_>,
+    #[description = "Whose collection to show, yours by default"] user: Option<User>
+) -> Result<(), Error> {
+    let user = user.as_ref().unwrap_or_else(|| ctx.author());
+    let inventory = service::get_inventory(&ctx.data().cats, &discord_user_id(user)).await?;
+
+    // newest first, the inventory itself is sorted oldest first
+    let description = if inventory.cats.is_empty() {
+        "No cats were unboxed yet, try `/unbox`".to_string()
+    } else {
+        inventory.cats.iter()
+            .rev()
+            .take(COLLECTION_PREVIEW_SIZE)
+            .map(|entry| format!("**{}** - {} x{} (`{}`)", entry.cat.full_name, entry.cat.rarity, entry.count, entry.cat._id))
+            .collect::<Vec<_>>()
+            .join("\n")
+    };
+
+    let rarities = inventory.rarity_totals.iter()
+        .map(|(rarity, total)| format!("{}: {}", rarity, total))
+        .collect::<Vec<_>>()
+        .join(", ");
+
+    let embed = CreateEmbed::new()
+        .title(format!("{}'s cat collection", user.name))
+        .description(description)
+        .footer(CreateEmbedFooter::new(format!("{} unboxes, {} unique cats. {}", inventory.total_unboxes, inventory.unique_cats, rarities)));
+
+    ctx.send(CreateReply::default().embed(embed)).await?;
+    Ok(())
+}
+
+
+fn cat_embed(cat: &CatForCreate, rarities: &RarityTable) -> CreateEmbed {
+    let temperament = if cat.breed.temperament.is_empty() {
+        "Unknown".to_string()
+    } else {
+        cat.breed.temperament.join(", ")
+    };
+
+    CreateEmbed::new()
+        .title(&cat.full_name)
+        .url(&cat.breed.wikipedia_url)
+        .description(&cat.breed.description)
+        .image(&cat.img_url)
+        .colour(rarities.get_rarity_colour(&cat.rarity))
+        .field("Rarity", &cat.rarity, true)
+        .field("Breed", &cat.breed.name, true)
+        .field("Origin", &cat.breed.origin, true)
+        .field("Temperament", temperament, false)
+        .footer(CreateEmbedFooter::new(format!("id: {}", cat._id)))
+}
@@ -2,20 +2,30 @@ use poise::serenity_prelude::{self as serenity, ClientBuilder};
 use shuttle_runtime::SecretStore;
 use shuttle_runtime::__internals::Context as ShuttleContext;
 
+use crate::web::{cats::CatsState, SharedResources};
+
 mod commands;
+mod cats;
 
-struct Data {}
+struct Data {
+    cats: CatsState,
+}
 type Error = Box<dyn std::error::Error + Send + Sync>;
 type Context<'a> = poise::Context<'a, Data, Error>;
 
-pub async fn setup_discord_bot(secret_store: &SecretStore) -> Result<serenity::Client, shuttle_runtime::Error> {
+pub async fn setup_discord_bot(secret_store: &SecretStore, shared: SharedResources) -> Result<serenity::Client, shuttle_runtime::Error> {
     let discord_token = secret_store
         .get("DISCORD_TOKEN")
         .context("discord token not found")?;
 
     let framework = poise::Framework::builder()
         .options(poise::FrameworkOptions {
-            commands: vec![self::commands::hello()],
+            commands: vec![
+                self::commands::hello(),
+                self::cats::unbox(),
+                self::cats::cat(),
+                self::cats::collection(),
+            ],
             prefix_options: poise::PrefixFrameworkOptions {
                 prefix: Some("!".into()),
                 mention_as_prefix: false,
@@ -23,7 +33,10 @@ pub async fn setup_discord_bot(secret_store: &SecretStore) -> Result<serenity::C
             },
             ..Default::default()
         })
-        .setup(move |_ctx, _ready, _framework| Box::pin(async move { Ok(Data {}) }))
+        .setup(move |ctx, _ready, framework| Box::pin(async move {
+            poise::builtins::register_globally(ctx, &framework.options().commands).await?;
+            Ok(Data { cats: shared.cats })
+        }))
         .build();
 
     let client = ClientBuilder::new(discord_token, serenity::GatewayIntents::non_privileged() 
@@ -35,4 +48,4 @@ pub async fn setup_discord_bot(secret_store: &SecretStore) -> Result<serenity::C
         .map_err(shuttle_runtime::CustomError::new)?;
 
     Ok(client)
-}
\ No newline at end of file
+}
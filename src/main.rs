@@ -1,16 +1,53 @@
+use std::str::FromStr;
 use axum::Router;
 use bot::setup_discord_bot;
+use poise::serenity_prelude as serenity;
 use shuttle_runtime::SecretStore;
-use axum::ServiceExt;
-use web::setup_web_server;
+use tokio::{sync::watch, task::JoinHandle};
+use tracing::{error, info};
+use web::{setup_shared_resources, setup_web_server};
 
 pub mod web;
 pub mod bot;
 pub mod helpers;
 
+/// Which parts of the service get started, read from the `RUN_MODE` secret.
+///
+/// Running only one of them is mostly useful for local development, e.g. to not log the bot in while working on the apis.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum RunMode {
+    #[default]
+    All,
+    Web,
+    Bot
+}
+
+impl RunMode {
+    pub fn runs_web(&self) -> bool {
+        matches!(self, Self::All | Self::Web)
+    }
+
+    pub fn runs_bot(&self) -> bool {
+        matches!(self, Self::All | Self::Bot)
+    }
+}
+
+impl FromStr for RunMode {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.trim().to_lowercase().as_str() {
+            "all" => Ok(Self::All),
+            "web" => Ok(Self::Web),
+            "bot" => Ok(Self::Bot),
+            other => Err(format!("invalid RUN_MODE `{}`, expected one of `all`, `web`, `bot`", other))
+        }
+    }
+}
+
 pub struct CustomService {
-    // discord_bot: serenity::Client,
-    router: Router,
+    discord_bot: Option<serenity::Client>,
+    router: Option<Router>,
 }
 
 
@@ -20,26 +57,83 @@ impl shuttle_runtime::Service for CustomService {
         mut self,
         addr: std::net::SocketAddr,
     ) -> Result<(), shuttle_runtime::Error> {
-        let router = self.router.into_service();
-
-        let listener = tokio::net::TcpListener::bind(&addr).await?;
-        
-        let serve_router = async move {
-            axum::serve(listener, router.into_make_service())
-                .await
-                .unwrap();
+        // flipped once either task is done, so that the other one can stop too
+        let (shutdown_tx, shutdown_rx) = watch::channel(false);
+
+        let mut web_task = match self.router {
+            Some(router) => {
+                let listener = tokio::net::TcpListener::bind(&addr).await?;
+                let mut shutdown_rx = shutdown_rx.clone();
+
+                Some(tokio::spawn(async move {
+                    axum::serve(listener, router.into_make_service_with_connect_info::<std::net::SocketAddr>())
+                        .with_graceful_shutdown(async move {
+                            let _ = shutdown_rx.changed().await;
+                        })
+                        .await
+                        .map_err(|e| format!("web server error: {}", e))
+                }))
+            },
+            None => None
         };
 
-        // let discord_bot_future = async {
-        //     self.discord_bot.start().await.unwrap();
-        // };
+        let shard_manager = self.discord_bot.as_ref().map(|client| client.shard_manager.clone());
+        let mut bot_task = self.discord_bot.take().map(|mut client| {
+            tokio::spawn(async move {
+                client.start()
+                    .await
+                    .map_err(|e| format!("discord bot error: {}", e))
+            })
+        });
+
+        info!("service started (web: {}, bot: {})", web_task.is_some(), bot_task.is_some());
 
-        tokio::select! {
-            // _ = discord_bot_future => {},
-            _ = serve_router => {}
+        // neither of these should ever finish on their own, so whichever one does is the reason to shut down
+        let reason = tokio::select! {
+            res = join_if_running(&mut web_task) => {
+                web_task = None;
+                task_exit_reason("web server", res)
+            },
+            res = join_if_running(&mut bot_task) => {
+                bot_task = None;
+                task_exit_reason("discord bot", res)
+            }
         };
 
-        Ok(())
+        error!("->> {}, shutting down the rest of the service", reason);
+
+        let _ = shutdown_tx.send(true);
+        if let Some(shard_manager) = shard_manager {
+            shard_manager.shutdown_all().await;
+        }
+
+        for (name, task) in [("web server", web_task), ("discord bot", bot_task)] {
+            if let Some(task) = task {
+                match task.await {
+                    Ok(Ok(())) => info!("{} shut down cleanly", name),
+                    Ok(Err(e)) => error!("->> {} failed while shutting down: {}", name, e),
+                    Err(e) => error!("->> {} panicked while shutting down: {}", name, e),
+                }
+            }
+        }
+
+        Err(shuttle_runtime::Error::Custom(shuttle_runtime::CustomError::msg(reason)))
+    }
+}
+
+/// Awaits the task if there is one, otherwise never resolves, so that a disabled task never wins a `select!`.
+async fn join_if_running<T>(task: &mut Option<JoinHandle<T>>) -> Result<T, tokio::task::JoinError> {
+    match task {
+        Some(task) => task.await,
+        None => std::future::pending().await
+    }
+}
+
+fn task_exit_reason(name: &str, res: Result<Result<(), String>, tokio::task::JoinError>) -> String {
+    match res {
+        Ok(Ok(())) => format!("{} stopped unexpectedly", name),
+        Ok(Err(e)) => e,
+        Err(e) => format!("{} panicked: {}", name, e),
     }
 }
 
@@ -50,11 +144,30 @@ impl shuttle_runtime::Service for CustomService {
 async fn main(
     #[shuttle_runtime::Secrets] secret_store: SecretStore,
 ) -> Result<CustomService, shuttle_runtime::Error> {
-    let _discord_bot = setup_discord_bot(&secret_store).await?;
-    let router = setup_web_server(&secret_store).await?;
+    let run_mode = secret_store
+        .get("RUN_MODE")
+        .map(|mode| mode.parse::<RunMode>())
+        .transpose()
+        .map_err(shuttle_runtime::CustomError::msg)?
+        .unwrap_or_default();
+    info!("run mode: {:?}", run_mode);
+
+    let shared = setup_shared_resources(&secret_store).await?;
+
+    let discord_bot = if run_mode.runs_bot() {
+        Some(setup_discord_bot(&secret_store, shared.clone()).await?)
+    } else {
+        None
+    };
+
+    let router = if run_mode.runs_web() {
+        Some(setup_web_server(&secret_store, shared).await?)
+    } else {
+        None
+    };
 
     Ok(CustomService {
-        // discord_bot: discord_bot,
+        discord_bot,
         router,
     })
-}
\ No newline at end of file
+}
@@ -0,0 +1,373 @@
+use std::{collections::HashMap, path::PathBuf, sync::Arc, time::Duration};
+use async_trait::async_trait;
+use jsonwebtoken::{jwk::JwkSet, DecodingKey};
+// tokio's, so that the tests can move time forward
+use tokio::{sync::{Mutex, RwLock}, time::Instant};
+use tracing::{info, warn};
+
+use crate::web::ClientWithKeys;
+
+/// How long fetched keys are fresh for if `AUTH_JWKS_TTL_SECS` is not set.
+pub const DEFAULT_JWKS_TTL_SECS: u32 = 600;
+/// Refetches happen at most this often, whatever the reason, so that tokens with made up `kid`s can't hammer the IdP.
+pub const MIN_REFRESH_INTERVAL: Duration = Duration::from_secs(30);
+/// How long past their ttl expired keys are still served while the IdP is down, revoked keys shouldn't work forever.
+const MAX_STALE_AGE: Duration = Duration::from_secs(24 * 60 * 60);
+
+#[derive(Debug, thiserror::Error)]
+pub enum JwksError {
+    #[error("Could not fetch the jwks: {0}")]
+    RequestError(#[from] reqwest::Error),
+    #[error("Could not read the jwks file: {0}")]
+    FileError(#[from] std::io::Error),
+    #[error("Could not parse the jwks: {0}")]
+    ParseError(#[from] serde_json::Error),
+    #[error("The jwks were last fetched {ago:?} ago, not refetching yet")]
+    RefreshThrottled { ago: Duration },
+}
+
+/// Where the signing keys come from.
+#[async_trait]
+pub trait JwksSource: Send + Sync + std::fmt::Debug {
+    async fn fetch(&self) -> Result<JwkSet, JwksError>;
+}
+
+/// `file://` urls are read from disk, for running without the IdP, everything else is fetched.
+pub fn jwks_source_from_config(url: &str, client: ClientWithKeys) -> Arc<dyn JwksSource> {
+    match url.strip_prefix("file://") {
+        Some(path) => Arc::new(FileJwks { path: PathBuf::from(path) }),
+        None => Arc::new(HttpJwks { url: url.to_string(), client })
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct HttpJwks {
+    url: String,
+    client: ClientWithKeys
+}
+
+#[async_trait]
+impl JwksSource for HttpJwks {
+    async fn fetch(&self) -> Result<JwkSet, JwksError> {
+        let jwks = self.client.client
+            .get(&self.url)
+            .send()
+            .await?
+            .error_for_status()?
+            .json::<JwkSet>()
+            .await?;
+
+        Ok(jwks)
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct FileJwks {
+    path: PathBuf
+}
+
+#[async_trait]
+impl JwksSource for FileJwks {
+    async fn fetch(&self) -> Result<JwkSet, JwksError> {
+        let text = tokio::fs::read_to_string(&self.path).await?;
+
+        Ok(serde_json::from_str(&text)?)
+    }
+}
+
+
+#[derive(Default)]
+struct CachedKeys {
+    keys: HashMap<String, DecodingKey>,
+    /// `None` until the first successful fetch.
+    fetched_at: Option<Instant>,
+    /// Successful or not.
+    attempted_at: Option<Instant>
+}
+
+/// The keys of the IdP, by `kid`.
+///
+/// - fresh keys (younger than the ttl) are used as they are
+/// - a `kid` that's not in the fresh keys triggers a refetch, in case the IdP rotated its keys
+/// - expired keys are still used while they get refetched in the background, and while the IdP is down, for up to `MAX_STALE_AGE`
+/// - after that they're dropped and every lookup waits for a refetch, failing while the IdP is still down
+///
+/// Every refetch counts towards `MIN_REFRESH_INTERVAL`, failed ones included.
+pub struct JwksCache {
+    source: Arc<dyn JwksSource>,
+    ttl: Duration,
+    cached: RwLock<CachedKeys>,
+    /// Held while fetching, so that concurrent requests don't all fetch at once.
+    fetching: Mutex<()>
+}
+
+impl std::fmt::Debug for JwksCache {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("JwksCache").field("source", &self.source).field("ttl", &self.ttl).finish_non_exhaustive()
+    }
+}
+
+impl JwksCache {
+    pub fn new(source: Arc<dyn JwksSource>, ttl: Duration) -> Arc<Self> {
+        Arc::new(Self { source, ttl, cached: RwLock::new(CachedKeys::default()), fetching: Mutex::new(()) })
+    }
+
+    /// The key for `kid`, `None` if the IdP doesn't have it (as far as the cache knows).
+    pub async fn get(self: &Arc<Self>, kid: &str) -> Result<Option<DecodingKey>, JwksError> {
+        let (key, is_fresh, has_keys, can_refresh) = {
+            let cached = self.cached.read().await;
+            let is_fresh = cached.fetched_at.is_some_and(|at| at.elapsed() < self.ttl);
+            let is_usable = cached.fetched_at.is_some_and(|at| at.elapsed() < self.ttl + MAX_STALE_AGE);
+            let can_refresh = cached.attempted_at.is_none_or(|at| at.elapsed() >= MIN_REFRESH_INTERVAL);
+            let key = cached.keys.get(kid).filter(|_| is_usable).cloned();
+            (key, is_fresh, is_usable, can_refresh)
+        };
+
+        match key {
+            Some(key) if is_fresh => Ok(Some(key)),
+            // stale while revalidate
+            Some(key) => {
+                if can_refresh {
+                    let cache = self.clone();
+                    tokio::spawn(async move {
+                        match cache.refresh().await {
+                            Ok(()) | Err(JwksError::RefreshThrottled { .. }) => {},
+                            Err(e) => warn!("could not refresh the jwks, using the expired keys: {}", e)
+                        }
+                    });
+                }
+                Ok(Some(key))
+            },
+            None => match self.refresh().await {
+                Ok(()) => Ok(self.cached.read().await.keys.get(kid).cloned()),
+                // a bad kid shouldn't look like the IdP being down, if there are keys to compare it to
+                Err(JwksError::RefreshThrottled { .. }) if has_keys => Ok(None),
+                Err(e) => Err(e)
+            }
+        }
+    }
+
+    /// Fetches the keys again, unless that was already tried less than `MIN_REFRESH_INTERVAL` ago.
+    pub async fn refresh(&self) -> Result<(), JwksError> {
+        let _fetching = self.fetching.lock().await;
+
+        // someone else might've fetched while this was waiting for the lock
+        if let Some(ago) = self.cached.read().await.attempted_at.map(|at| at.elapsed()) {
+            if ago < MIN_REFRESH_INTERVAL {
+                return Err(JwksError::RefreshThrottled { ago });
+            }
+        }
+
+        self.cached.write().await.attempted_at = Some(Instant::now());
+        let jwks = self.source.fetch().await?;
+
+        let keys = jwks.keys.iter()
+            .filter_map(|jwk| {
+                let kid = jwk.common.key_id.clone()?;
+                match DecodingKey::from_jwk(jwk) {
+                    Ok(key) => Some((kid, key)),
+                    Err(e) => {
+                        warn!("skipping jwk {}: {}", kid, e);
+                        None
+                    }
+                }
+            })
+            .collect::<HashMap<_, _>>();
+        info!("fetched {} jwks keys", keys.len());
+
+        let mut cached = self.cached.write().await;
+        cached.keys = keys;
+        cached.fetched_at = Some(Instant::now());
+
+        Ok(())
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+    use std::sync::{atomic::{AtomicUsize, Ordering}, Mutex as StdMutex};
+
+    use super::*;
+
+    const TTL: Duration = Duration::from_secs(600);
+
+    /// Serves the keys with these kids, or fails while they're `None`, and counts the fetches.
+    #[derive(Debug, Default)]
+    struct StubJwks {
+        kids: StdMutex<Option<Vec<&'static str>>>,
+        fetches: AtomicUsize
+    }
+
+    impl StubJwks {
+        fn serving(kids: &[&'static str]) -> Arc<Self> {
+            let stub = Arc::new(Self::default());
+            stub.serve(kids);
+            stub
+        }
+
+        fn serve(&self, kids: &[&'static str]) {
+            *self.kids.lock().unwrap() = Some(kids.to_vec());
+        }
+
+        fn go_down(&self) {
+            *self.kids.lock().unwrap() = None;
+        }
+
+        fn fetches(&self) -> usize {
+            self.fetches.load(Ordering::SeqCst)
+        }
+    }
+
+    #[async_trait]
+    impl JwksSource for StubJwks {
+        async fn fetch(&self) -> Result<JwkSet, JwksError> {
+            self.fetches.fetch_add(1, Ordering::SeqCst);
+
+            let kids = self.kids.lock().unwrap().clone()
+                .ok_or_else(|| JwksError::FileError(std::io::Error::other("the idp is down")))?;
+            let keys = kids.iter()
+                .map(|kid| serde_json::json!({ "kty": "oct", "kid": kid, "alg": "HS256", "k": "c2VjcmV0" }))
+                .collect::<Vec<_>>();
+
+            Ok(serde_json::from_value(serde_json::json!({ "keys": keys }))?)
+        }
+    }
+
+    fn cache(stub: &Arc<StubJwks>) -> Arc<JwksCache> {
+        JwksCache::new(stub.clone(), TTL)
+    }
+
+    /// Lets the background refreshes run.
+    async fn settle() {
+        for _ in 0..10 {
+            tokio::task::yield_now().await;
+        }
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn fresh_keys_are_only_fetched_once() {
+        let stub = StubJwks::serving(&["a", "b"]);
+        let cache = cache(&stub);
+
+        assert!(cache.get("a").await.unwrap().is_some());
+        assert!(cache.get("b").await.unwrap().is_some());
+
+        tokio::time::advance(TTL - Duration::from_secs(1)).await;
+        assert!(cache.get("a").await.unwrap().is_some());
+        settle().await;
+
+        assert_eq!(stub.fetches(), 1);
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn expired_keys_are_served_while_they_get_refetched() {
+        let stub = StubJwks::serving(&["a"]);
+        let cache = cache(&stub);
+        cache.get("a").await.unwrap();
+
+        stub.serve(&["b"]);
+        tokio::time::advance(TTL).await;
+
+        // the expired key is still served, the rotated ones arrive in the background
+        assert!(cache.get("a").await.unwrap().is_some());
+        settle().await;
+        assert_eq!(stub.fetches(), 2);
+
+        assert!(cache.get("a").await.unwrap().is_none());
+        assert!(cache.get("b").await.unwrap().is_some());
+        assert_eq!(stub.fetches(), 2);
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn an_unknown_kid_refetches() {
+        let stub = StubJwks::serving(&["a"]);
+        let cache = cache(&stub);
+        cache.get("a").await.unwrap();
+
+        stub.serve(&["a", "rotated"]);
+        tokio::time::advance(MIN_REFRESH_INTERVAL).await;
+
+        assert!(cache.get("rotated").await.unwrap().is_some());
+        assert_eq!(stub.fetches(), 2);
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn refetches_are_throttled() {
+        let stub = StubJwks::serving(&["a"]);
+        let cache = cache(&stub);
+        cache.get("a").await.unwrap();
+
+        // made up kids don't reach the source until the interval is over
+        for _ in 0..5 {
+            assert!(cache.get("made up").await.unwrap().is_none());
+        }
+        assert_eq!(stub.fetches(), 1);
+        assert!(matches!(cache.refresh().await, Err(JwksError::RefreshThrottled { .. })));
+
+        tokio::time::advance(MIN_REFRESH_INTERVAL).await;
+        assert!(cache.get("made up").await.unwrap().is_none());
+        assert!(cache.get("made up").await.unwrap().is_none());
+        assert_eq!(stub.fetches(), 2);
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn failed_fetches_are_throttled_too() {
+        let stub = Arc::new(StubJwks::default());
+        let cache = cache(&stub);
+
+        assert!(matches!(cache.get("a").await, Err(JwksError::FileError(_))));
+        // without any keys a throttled refetch is an error, not an unknown kid
+        assert!(matches!(cache.get("a").await, Err(JwksError::RefreshThrottled { .. })));
+        assert_eq!(stub.fetches(), 1);
+
+        stub.serve(&["a"]);
+        tokio::time::advance(MIN_REFRESH_INTERVAL).await;
+        assert!(cache.get("a").await.unwrap().is_some());
+        assert_eq!(stub.fetches(), 2);
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn stale_keys_are_kept_while_the_source_is_down() {
+        let stub = StubJwks::serving(&["a"]);
+        let cache = cache(&stub);
+        cache.get("a").await.unwrap();
+
+        stub.go_down();
+        for _ in 0..3 {
+            tokio::time::advance(TTL).await;
+            assert!(cache.get("a").await.unwrap().is_some());
+            settle().await;
+        }
+        assert_eq!(stub.fetches(), 4);
+
+        // an unknown kid can't be looked up while it's down
+        tokio::time::advance(MIN_REFRESH_INTERVAL).await;
+        assert!(matches!(cache.get("b").await, Err(JwksError::FileError(_))));
+        assert!(cache.get("a").await.unwrap().is_some());
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn stale_keys_are_dropped_after_the_max_stale_age() {
+        let stub = StubJwks::serving(&["a"]);
+        let cache = cache(&stub);
+        cache.get("a").await.unwrap();
+
+        stub.go_down();
+        tokio::time::advance(TTL + MAX_STALE_AGE - Duration::from_secs(1)).await;
+        assert!(cache.get("a").await.unwrap().is_some());
+        settle().await;
+
+        tokio::time::advance(Duration::from_secs(1)).await;
+        assert!(matches!(cache.get("a").await, Err(JwksError::RefreshThrottled { .. })));
+        // nor is an unknown kid just unknown, there's nothing to compare it to
+        assert!(matches!(cache.get("b").await, Err(JwksError::RefreshThrottled { .. })));
+
+        tokio::time::advance(MIN_REFRESH_INTERVAL).await;
+        assert!(matches!(cache.get("a").await, Err(JwksError::FileError(_))));
+
+        stub.serve(&["a"]);
+        tokio::time::advance(MIN_REFRESH_INTERVAL).await;
+        assert!(cache.get("a").await.unwrap().is_some());
+    }
+}
@@ -0,0 +1,294 @@
+//! Bearer token auth for every service, against a single IdP (auth0).
+//!
+//! Services opt in per route, with `from_fn_with_state(auth.clone(), auth::auth_mw)`, or `optional_auth_mw` for routes
+//! that also work without logging in. Handlers then take an `AuthUser` (or an `Option<AuthUser>`).
+
+use std::{collections::HashSet, sync::Arc};
+use async_trait::async_trait;
+use axum::{extract::{FromRequestParts, Request, State}, http::{HeaderMap, StatusCode}, middleware::Next, response::{IntoResponse, Response}};
+use jsonwebtoken::{decode, decode_header, Algorithm, Validation};
+use serde_json::{Map, Value};
+use tracing::warn;
+use utoipa::{openapi::security::{HttpAuthScheme, HttpBuilder, SecurityScheme}, Modify};
+
+use super::error::{problem_response, ApiError, Problem};
+
+pub mod jwks;
+
+use jwks::JwksCache;
+
+/// How many seconds of clock skew are tolerated on `exp` and `nbf` if `AUTH_LEEWAY_SECS` is not set.
+pub const DEFAULT_LEEWAY_SECS: u32 = 60;
+
+/// Name of the security scheme that the routes behind `auth_mw` reference in the openapi docs.
+pub const BEARER_AUTH: &str = "bearer_auth";
+
+/// Adds the auth0 bearer token scheme to the openapi docs.
+pub struct BearerAuth;
+
+impl Modify for BearerAuth {
+    fn modify(&self, openapi: &mut utoipa::openapi::OpenApi) {
+        let scheme = HttpBuilder::new()
+            .scheme(HttpAuthScheme::Bearer)
+            .bearer_format("JWT")
+            .description(Some("An auth0 access token for one of the `AUTH_AUDIENCE` audiences"))
+            .build();
+
+        openapi.components.get_or_insert_with(Default::default)
+            .add_security_scheme(BEARER_AUTH, SecurityScheme::Http(scheme));
+    }
+}
+
+
+#[derive(Debug, thiserror::Error)]
+pub enum AuthError {
+    #[error("Missing Token")]
+    MissingToken,
+    #[error("Missing Header")]
+    MissingHeader,
+    #[error("Invalid Header")]
+    InvalidHeader,
+    #[error("Invalid Token")]
+    InvalidToken,
+    /// No keys to check the token against, the IdP is down and nothing (recent enough) is cached.
+    #[error("Couldn't fetch auth stuff")]
+    FetchError,
+    #[error("Key Mismatch")]
+    KeyMismatch,
+    #[error("Missing the `{permission}` permission")]
+    MissingPermission { permission: &'static str }
+}
+
+impl ApiError for AuthError {
+    fn problem(&self) -> Problem {
+        match self {
+            Self::MissingPermission { permission: _ } => Problem::new(StatusCode::FORBIDDEN, "missing_permission", self),
+            // not the token's fault, it might be perfectly fine
+            Self::FetchError => Problem::new(StatusCode::SERVICE_UNAVAILABLE, "upstream_unavailable", "The identity provider is unavailable right now")
+                .with_retry_after(jwks::MIN_REFRESH_INTERVAL.as_secs()),
+            _ => Problem::new(StatusCode::UNAUTHORIZED, "unauthorized", "Unauthorized")
+        }
+    }
+}
+
+impl IntoResponse for AuthError {
+    fn into_response(self) -> axum::response::Response {
+        problem_response(&self)
+    }
+}
+
+/// What a token has to look like to be accepted.
+#[derive(Debug, Clone)]
+pub struct IssuerConfig {
+    /// Compared to `iss` as is, auth0 issuers end with a `/`.
+    pub issuer: String,
+    /// The token has to be for at least one of these.
+    pub audiences: Vec<String>,
+    pub algorithms: Vec<Algorithm>,
+    pub leeway_secs: u64,
+    /// Claims that have to be there, out of `exp`, `nbf`, `aud`, `iss` and `sub`.
+    pub required_claims: Vec<String>
+}
+
+impl IssuerConfig {
+    /// RS256 only, with `DEFAULT_LEEWAY_SECS` and `exp`, `iss`, `aud` and `sub` required.
+    pub fn new(issuer: &str, audiences: Vec<String>) -> Self {
+        Self {
+            issuer: issuer.to_string(),
+            audiences,
+            algorithms: vec![Algorithm::RS256],
+            leeway_secs: DEFAULT_LEEWAY_SECS.into(),
+            required_claims: ["exp", "iss", "aud", "sub"].map(String::from).to_vec()
+        }
+    }
+
+    pub fn with_algorithms(mut self, algorithms: Vec<Algorithm>) -> Self {
+        self.algorithms = algorithms;
+        self
+    }
+
+    pub fn with_leeway_secs(mut self, leeway_secs: u64) -> Self {
+        self.leeway_secs = leeway_secs;
+        self
+    }
+
+    pub fn with_required_claims(mut self, required_claims: Vec<String>) -> Self {
+        self.required_claims = required_claims;
+        self
+    }
+
+    fn validation(&self) -> Validation {
+        let mut validation = Validation::new(self.algorithms.first().copied().unwrap_or(Algorithm::RS256));
+        validation.algorithms = self.algorithms.clone();
+        validation.leeway = self.leeway_secs;
+        validation.set_issuer(&[&self.issuer]);
+        validation.set_audience(&self.audiences);
+        validation.set_required_spec_claims(&self.required_claims);
+
+        validation
+    }
+}
+
+/// The state of `auth_mw` and `optional_auth_mw`, the issuer config and the keys of the issuer. Cheap to clone.
+#[derive(Debug, Clone)]
+pub struct AuthLayer {
+    jwks: Arc<JwksCache>,
+    validation: Arc<Validation>
+}
+
+impl AuthLayer {
+    pub fn new(config: &IssuerConfig, jwks: Arc<JwksCache>) -> Self {
+        Self { jwks, validation: Arc::new(config.validation()) }
+    }
+
+    /// Checks the bearer token in `headers`, the signature, issuer, audience, algorithm and expiry.
+    pub async fn authenticate(&self, headers: &HeaderMap) -> Result<AuthUser, AuthError> {
+        let auth_header = headers
+            .get("Authorization")
+            .ok_or(AuthError::MissingHeader)?
+            .to_str()
+            .map_err(|_| AuthError::InvalidHeader)?;
+
+        let token = auth_header.strip_prefix("Bearer ").ok_or(AuthError::InvalidHeader)?;
+        let header = decode_header(token).map_err(|_| AuthError::InvalidToken)?;
+        let kid = header.kid.ok_or(AuthError::InvalidToken)?;
+
+        let decoding_key = self.jwks.get(&kid).await
+            .map_err(|e| {
+                warn!("no jwks to check a token against: {}", e);
+                AuthError::FetchError
+            })?
+            .ok_or(AuthError::KeyMismatch)?;
+
+        let token_data = decode::<Map<String, Value>>(token, &decoding_key, &self.validation)
+            .map_err(|e| {
+                warn!("rejected a token: {}", e);
+                AuthError::InvalidToken
+            })?;
+
+        AuthUser::from_claims(token_data.claims)
+    }
+}
+
+/// Who made the request, from a validated token.
+#[derive(Debug, Clone)]
+pub struct AuthUser {
+    /// `sub`, the user id everything is stored under.
+    pub subject: String,
+    /// From the space separated `scope` claim.
+    pub scopes: HashSet<String>,
+    /// From the `permissions` claim, which auth0 adds for the roles of the user when RBAC is on.
+    pub permissions: HashSet<String>,
+    /// Everything in the token, for claims that don't have a field here.
+    pub claims: Map<String, Value>
+}
+
+impl AuthUser {
+    fn from_claims(claims: Map<String, Value>) -> Result<Self, AuthError> {
+        let subject = claims.get("sub")
+            .and_then(Value::as_str)
+            .ok_or(AuthError::InvalidToken)?
+            .to_string();
+
+        let scopes = claims.get("scope")
+            .and_then(Value::as_str)
+            .map(|scope| scope.split_whitespace().map(String::from).collect())
+            .unwrap_or_default();
+
+        let permissions = claims.get("permissions")
+            .and_then(Value::as_array)
+            .map(|permissions| permissions.iter().filter_map(Value::as_str).map(String::from).collect())
+            .unwrap_or_default();
+
+        Ok(Self { subject, scopes, permissions, claims })
+    }
+
+    pub fn has_scope(&self, scope: &str) -> bool {
+        self.scopes.contains(scope)
+    }
+
+    /// Granted either way, as a scope of the token or as a permission of the user's roles.
+    pub fn has_permission(&self, permission: &str) -> bool {
+        self.has_scope(permission) || self.permissions.contains(permission)
+    }
+}
+
+/// Put in the request extensions by the middlewares, a route without one of them has no `AuthUser`.
+#[async_trait]
+impl<S> FromRequestParts<S> for AuthUser
+where
+    S: Send + Sync,
+{
+    type Rejection = AuthError;
+
+    async fn from_request_parts(parts: &mut axum::http::request::Parts, _state: &S) -> Result<Self, Self::Rejection> {
+        parts
+            .extensions
+            .get::<AuthUser>()
+            .cloned()
+            .ok_or(AuthError::MissingToken)
+    }
+}
+
+pub async fn auth_mw(
+    State(auth): State<AuthLayer>,
+    headers: HeaderMap,
+    mut req: Request,
+    next: Next,
+) -> Result<Response, AuthError> {
+    let auth_user = auth.authenticate(&headers).await?;
+
+    req.extensions_mut().insert(auth_user);
+
+    Ok(next.run(req).await)
+}
+
+/// Like `auth_mw`, but lets requests without an `Authorization` header through, without an `AuthUser`.
+///
+/// Handlers can then take an `Option<AuthUser>` to behave differently for logged in users. A header with a bad token is still rejected.
+pub async fn optional_auth_mw(
+    State(auth): State<AuthLayer>,
+    headers: HeaderMap,
+    mut req: Request,
+    next: Next,
+) -> Result<Response, AuthError> {
+    if headers.contains_key("Authorization") {
+        let auth_user = auth.authenticate(&headers).await?;
+        req.extensions_mut().insert(auth_user);
+    }
+
+    Ok(next.run(req).await)
+}
+
+/// Only lets users with the `permission` through, has to run after `auth_mw`:
+/// `.layer(from_fn_with_state("manage:weapons", auth::permission_mw)).layer(from_fn_with_state(auth, auth::auth_mw))`
+pub async fn permission_mw(
+    State(permission): State<&'static str>,
+    auth_user: AuthUser,
+    req: Request,
+    next: Next,
+) -> Result<Response, AuthError> {
+    if !auth_user.has_permission(permission) {
+        return Err(AuthError::MissingPermission { permission });
+    }
+
+    Ok(next.run(req).await)
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn only_bad_tokens_are_unauthorized() {
+        let unavailable = AuthError::FetchError.problem();
+        assert_eq!((unavailable.status, unavailable.code), (503, "upstream_unavailable"));
+        assert_eq!(unavailable.retry_after, Some(30));
+
+        for e in [AuthError::MissingHeader, AuthError::InvalidToken, AuthError::KeyMismatch] {
+            assert_eq!(e.problem().status, 401);
+        }
+        assert_eq!(AuthError::MissingPermission { permission: "moderate:quotes" }.problem().status, 403);
+    }
+}
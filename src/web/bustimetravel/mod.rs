@@ -1,5 +1,6 @@
 use axum::response::IntoResponse;
-use axum::{Extension, Json, Router};
+use axum::{Extension, Router};
+use crate::web::extract::Json;
 use axum::routing::get;
 use axum::http::StatusCode;
 use serde::{Deserialize, Serialize};
@@ -8,8 +9,9 @@ use std::{collections::HashMap, sync::Arc};
 use tokio::sync::Mutex;
 use chrono::Utc;
 use std::sync::LazyLock;
+use utoipa::{OpenApi, ToSchema};
 
-use super::ClientWithKeys;
+use super::{error::{problem_response, ApiError, Problem}, ClientWithKeys};
 
 
 
@@ -48,6 +50,14 @@ pub fn routes(client: ClientWithKeys) -> Router {
         .layer(Extension(history))
 }
 
+#[derive(OpenApi)]
+#[openapi(
+    paths(get_current, get_history),
+    components(schemas(Record, Location, RouteInfo)),
+    tags((name = "bustimetravel", description = "Live and recent locations of tracked buses"))
+)]
+pub struct BusTimeTravelApi;
+
 type LocationHistory = Arc<Mutex<Vec<Record>>>;
 
 pub static ROUTES: LazyLock<HashMap<String, RouteInfo>> = LazyLock::new(|| {
@@ -92,10 +102,29 @@ pub static ROUTES: LazyLock<HashMap<String, RouteInfo>> = LazyLock::new(|| {
         .collect()
 });
 
+#[utoipa::path(
+    get,
+    path = "/current",
+    responses(
+        (status = 200, description = "Where the tracked buses are right now", body = Record),
+        (status = 502, description = "The NTA api returned an invalid response", body = Problem, content_type = "application/problem+json"),
+        (status = 503, description = "The NTA api is unavailable or rate limiting us", body = Problem, content_type = "application/problem+json"),
+        (status = 504, description = "The NTA api timed out", body = Problem, content_type = "application/problem+json")
+    ),
+    tag = "bustimetravel"
+)]
 async fn get_current(Extension(client): Extension<ClientWithKeys>) -> Result<impl IntoResponse, Error> {
     get_location(&client).await.map(Json)
 }
 
+#[utoipa::path(
+    get,
+    path = "/history",
+    responses(
+        (status = 200, description = "The locations of the tracked buses over the last 6 hours, every 30 seconds", body = [Record])
+    ),
+    tag = "bustimetravel"
+)]
 async fn get_history(Extension(history): Extension<LocationHistory>) -> Result<impl IntoResponse, Error> {
     let list = history.lock().await.clone();
     Ok(Json(list))
@@ -155,6 +184,8 @@ async fn get_location(client: &ClientWithKeys) -> Result<Record, Error> {
 
 
 
+const UPSTREAM: &str = "The NTA api";
+
 #[derive(Debug, thiserror::Error)]
 pub enum Error {
     #[error("Some error")]
@@ -167,32 +198,35 @@ pub enum Error {
     RateLimited(String),
 }
 
+impl ApiError for Error {
+    fn problem(&self) -> Problem {
+        match self {
+            Self::SomeError => Problem::internal(),
+            Self::ReqwestError(e) => Problem::upstream(UPSTREAM, e),
+            Self::SerdeJsonError(_) => Problem::bad_gateway(UPSTREAM),
+            Self::RateLimited(_) => Problem::new(StatusCode::SERVICE_UNAVAILABLE, "upstream_unavailable", format!("{} is rate limiting us", UPSTREAM))
+                .with_retry_after(60),
+        }
+    }
+}
+
 impl IntoResponse for Error {
     fn into_response(self) -> axum::response::Response {
-        println!("->> {}", self);
-
-        let body = Json(serde_json::json!({
-            "error": format!("{}", &self)
-        }));
-
-        let status_code = match &self {
-            _ => StatusCode::INTERNAL_SERVER_ERROR
-        };
-        
-        (status_code, body).into_response()
+        problem_response(&self)
     }
 }
 
 
 
-#[derive(Serialize, Debug, Clone)]
+#[derive(Serialize, Debug, Clone, ToSchema)]
 struct Record {
+    /// Unix timestamp in seconds, of when the locations were fetched.
     ts: String,
     locations: Vec<Location>
 }
 
 
-#[derive(Serialize, Debug, Clone)] 
+#[derive(Serialize, Debug, Clone, ToSchema)] 
 struct Location {
     lat: f64,
     lon: f64,
@@ -238,7 +272,7 @@ struct Position {
     longitude: f64
 }
 
-#[derive(Serialize, Debug, Clone)]
+#[derive(Serialize, Debug, Clone, ToSchema)]
 pub struct RouteInfo {
     route_id: String,
     short_name: String,
@@ -1,86 +1,262 @@
-use std::time::Instant;
-use axum::{extract::{Path, State}, response::IntoResponse, Extension, Json};
-use poise::serenity_prelude::futures::TryStreamExt;
-use mongodb::{bson::doc, Collection};
+use axum::{extract::State, http::header, response::{IntoResponse, Redirect, Response}, Extension};
+use crate::web::extract::{Json, Path, Query};
+use serde::{Deserialize, Serialize};
 use tracing::info;
-use crate::web::{cats::model::{Cat, CatUnprocessed}, ClientWithKeys};
-use super::{model::CatForCreate, names::{get_random_full_name, get_random_name_from_country}, rarities::get_random_rarity};
+use utoipa::{IntoParams, ToSchema};
+use crate::web::auth::AuthUser;
+use super::{ids::{BreedId, CatId}, images, limits::UnboxBudget, model::{CatForCreate, CatForUpdate, CatListParams, ImageParams, ImageSize}, prefetch::PoolMetrics, rarities::RarityOdds, service, CatsState};
 
+#[derive(Deserialize, IntoParams)]
+#[into_params(parameter_in = Query)]
+pub struct RarityParams {
+    /// A breed id, for the odds of that breed.
+    breed: Option<String>
+}
 
-pub async fn get_all(State(cats): State<Collection<Cat>>) -> Result<impl IntoResponse, super::Error> {
-    let cursor = cats
-        .find(doc! {})
-        .sort(doc! { "createdAt": -1 }).await?;
+#[derive(Serialize, ToSchema)]
+pub struct RaritiesResponse {
+    breed: Option<String>,
+    rarities: Vec<RarityOdds>
+}
 
-    let cats: Vec<Cat> = cursor.try_collect().await?;
+#[derive(Serialize, ToSchema)]
+pub struct MetricsResponse {
+    pool: PoolMetrics
+}
 
-    Ok(Json(cats))
+/// The unboxed cat, with the budget left after unboxing it.
+#[derive(Serialize, ToSchema)]
+pub struct UnboxResponse {
+    #[serde(flatten)]
+    cat: CatForCreate,
+    budget: Option<UnboxBudget>
 }
 
-pub async fn get_one(Path(id): Path<String>, State(cats): State<Collection<Cat>>) -> Result<impl IntoResponse, super::Error> {
-    let cat = cats
-        .find_one(doc! { "_id": &id }).await?
-        .ok_or(super::Error::NotFound { id })?;
+
+#[utoipa::path(
+    get,
+    path = "/",
+    params(CatListParams),
+    responses(
+        (status = 200, description = "A page of cats, filtered and sorted", body = CatPage),
+        (status = 400, description = "Invalid cursor", body = Problem, content_type = "application/problem+json")
+    ),
+    tag = "cats"
+)]
+pub async fn get_all(State(state): State<CatsState>, Query(q): Query<CatListParams>) -> Result<impl IntoResponse, super::Error> {
+    let page = service::list_cats(&state, &q).await?;
+
+    Ok(Json(page))
+}
+
+#[utoipa::path(
+    get,
+    path = "/{id}",
+    params(("id" = String, Path, description = "Cat id, like `0XYvRd7oD`")),
+    responses(
+        (status = 200, description = "A single cat", body = Cat),
+        (status = 400, description = "Invalid cat id", body = Problem, content_type = "application/problem+json"),
+        (status = 404, description = "Cat not found", body = Problem, content_type = "application/problem+json")
+    ),
+    tag = "cats"
+)]
+pub async fn get_one(id: CatId, State(state): State<CatsState>) -> Result<impl IntoResponse, super::Error> {
+    let cat = service::get_cat(&state, id.as_str()).await?;
 
     Ok(Json(cat))
 }
 
-pub async fn get_random(State(cats): State<Collection<Cat>>, Extension(client): Extension<ClientWithKeys>) -> Result<impl IntoResponse, super::Error> {
-    info!("=== /cats/random start ===");
+/// Renames and/or favourites a cat, for users who own it. Renames only change the user's copies, not the cat everyone else sees.
+#[utoipa::path(
+    patch,
+    path = "/{id}",
+    request_body = CatForUpdate,
+    params(("id" = String, Path, description = "Cat id, like `0XYvRd7oD`")),
+    responses(
+        (status = 200, description = "The updated cat, as the user sees it", body = Cat),
+        (status = 400, description = "Invalid cat id, validation error or nothing to update", body = Problem, content_type = "application/problem+json"),
+        (status = 401, description = "Missing or invalid token", body = Problem, content_type = "application/problem+json"),
+        (status = 403, description = "The cat is not in the user's inventory", body = Problem, content_type = "application/problem+json"),
+        (status = 404, description = "Cat not found", body = Problem, content_type = "application/problem+json")
+    ),
+    security(("bearer_auth" = [])),
+    tag = "cats"
+)]
+pub async fn update_one(id: CatId, State(state): State<CatsState>, auth_user: AuthUser, Json(update): Json<CatForUpdate>) -> Result<impl IntoResponse, super::Error> {
+    let cat = service::update_cat(&state, id.as_str(), &auth_user.subject, &update).await?;
+
+    Ok(Json(cat))
+}
+
+#[utoipa::path(
+    post,
+    path = "/{id}/reroll",
+    params(("id" = String, Path, description = "Cat id, like `0XYvRd7oD`")),
+    responses(
+        (status = 200, description = "The re-rolled cat and what's left in the wallet", body = Reroll),
+        (status = 400, description = "Invalid cat id", body = Problem, content_type = "application/problem+json"),
+        (status = 401, description = "Missing or invalid token", body = Problem, content_type = "application/problem+json"),
+        (status = 402, description = "Not enough coins in the wallet", body = Problem, content_type = "application/problem+json"),
+        (status = 403, description = "The cat is not in the user's inventory", body = Problem, content_type = "application/problem+json"),
+        (status = 404, description = "Cat not found", body = Problem, content_type = "application/problem+json")
+    ),
+    security(("bearer_auth" = [])),
+    tag = "cats"
+)]
+pub async fn reroll(id: CatId, State(state): State<CatsState>, auth_user: AuthUser) -> Result<impl IntoResponse, super::Error> {
+    let reroll = service::reroll_cat(&state, id.as_str(), &auth_user.subject).await?;
+
+    Ok(Json(reroll))
+}
+
+#[utoipa::path(
+    get,
+    path = "/wallet",
+    responses(
+        (status = 200, description = "The user's wallet", body = Wallet),
+        (status = 401, description = "Missing or invalid token", body = Problem, content_type = "application/problem+json")
+    ),
+    security(("bearer_auth" = [])),
+    tag = "cats"
+)]
+pub async fn get_wallet(State(state): State<CatsState>, auth_user: AuthUser) -> Result<impl IntoResponse, super::Error> {
+    let wallet = service::get_wallet(&state, &auth_user.subject).await?;
+
+    Ok(Json(wallet))
+}
+
+/// Serves the mirrored image (or thumbnail) of a cat. Cats that weren't mirrored yet get mirrored now,
+/// and get redirected to the original image in the meantime.
+#[utoipa::path(
+    get,
+    path = "/{id}/image",
+    params(("id" = String, Path, description = "Cat id, like `0XYvRd7oD`"), ImageParams),
+    responses(
+        (status = 200, description = "The mirrored image, or a png thumbnail of it"),
+        (status = 307, description = "The image is not mirrored yet, redirects to the original"),
+        (status = 400, description = "Invalid cat id", body = Problem, content_type = "application/problem+json"),
+        (status = 404, description = "Cat not found", body = Problem, content_type = "application/problem+json")
+    ),
+    tag = "cats"
+)]
+pub async fn get_image(id: CatId, State(state): State<CatsState>, Query(q): Query<ImageParams>) -> Result<Response, super::Error> {
+    let cat = service::get_cat(&state, id.as_str()).await?;
 
-    let start_time = Instant::now();
-    let response = client.client
-        .get(format!("https://api.thecatapi.com/v1/images/search?api_key={}&has_breeds=1", client.cat_api_key.clone()))
-        .send()
-        .await?;
-
-    let response_text = response.text().await?;
-    info!("Response text: {}", response_text);
-
-    let cat: Vec<CatUnprocessed> = serde_json::from_str(&response_text)?;
-    let cat = cat.into_iter()
-        .next()  // because this req returns a vec, not a single cat, its always 1 cat anyway, or should be
-        .ok_or(super::Error::NoCatsFromRandomCatApi)?;
-
-    // let cat = client.client
-    //     .get(format!("https://api.thecatapi.com/v1/images/search?api_key={}&has_breeds=1", client.cat_api_key.clone()))
-    //     .send()
-    //     .await?
-    //     .json::<Vec<CatUnprocessed>>()
-    //     .await?
-    //     .into_iter()
-    //     .next()  // because this req returns a vec, not a single cat, its always 1 cat anywa, or should be
-    //     .ok_or(super::Error::NoCatsFromRandomCatApi)?;
-    info!("fetching catapi: {:?}", start_time.elapsed());
-    // look it up in db
-    
-    let start_time = Instant::now();
-    if let Some(found) = cats.find_one(doc! { "_id": &cat.id }).await? {
-        info!("Cat that was previously discovered! {:?}\n{} - {}", found, found.full_name, found.rarity);
-        return Ok(Json(found.into()))  // here cat turns into a cat_for_create, because of how this lib's sillyness
+    let mirrored = cat.images.as_ref().and_then(|images| {
+        images.mirrored.get(&q.size.to_string())
+            .or(images.mirrored.get(&ImageSize::Original.to_string()))
+    });
+
+    if let Some(image) = mirrored {
+        if let Some(blob) = state.blobs.get(&image.key).await? {
+            let headers = [
+                (header::CONTENT_TYPE, blob.content_type),
+                (header::CACHE_CONTROL, "public, max-age=604800, immutable".to_string())
+            ];
+            return Ok((headers, blob.bytes).into_response());
+        }
     }
-    info!("checking in mongo: {:?}", start_time.elapsed());
-    // if one doesnt exist in it yet, create a new cat
 
-    let cat_wip = cat.start_processing()?;
+    info!("image of cat {} is not mirrored yet", cat._id);
+    images::spawn_mirror(state.clone(), cat._id.clone(), cat.img_url.clone());
 
-    let rarity = get_random_rarity();
-    let start_time = Instant::now();
-    let pet_name = get_random_name_from_country(&cat_wip.breed.country_code, client).await?;
-    info!("constructing pet_name: {:?}", start_time.elapsed());
-    let full_name = get_random_full_name(&cat_wip.breed, &pet_name);
+    Ok(Redirect::temporary(&cat.img_url).into_response())
+}
 
-    let cats_for_create: Collection<CatForCreate> = cats.clone_with_type();
+/// Anyone can unbox, but only logged in users get the cat added to their inventory.
+#[utoipa::path(
+    post,
+    path = "/random",
+    responses(
+        (status = 200, description = "The unboxed cat, with the unbox budget left", body = UnboxResponse),
+        (status = 401, description = "Invalid token, requests without one are fine", body = Problem, content_type = "application/problem+json"),
+        (status = 429, description = "No unboxes left for today", body = Problem, content_type = "application/problem+json"),
+        (status = 502, description = "The cat api returned an invalid response", body = Problem, content_type = "application/problem+json"),
+        (status = 503, description = "The cat api is unavailable", body = Problem, content_type = "application/problem+json"),
+        (status = 504, description = "The cat api timed out", body = Problem, content_type = "application/problem+json")
+    ),
+    tag = "cats"
+)]
+pub async fn get_random(State(state): State<CatsState>, auth_user: Option<AuthUser>, budget: Option<Extension<UnboxBudget>>) -> Result<impl IntoResponse, super::Error> {
+    info!("=== /cats/random start ===");
 
-    // NOTE: 
-    // becuase the mongodb crate is stupid theres no createdAt field, so i just have to generate a time here, which will be somewhat inaccurate, but whatever
-    let new_cat = cat_wip.finalize_processing(rarity.into(), pet_name.into(), full_name.into());
+    let user_id = auth_user.map(|user| user.subject);
+    let cat = service::unbox_random_cat(&state, user_id.as_deref()).await?;
+
+    Ok(Json(UnboxResponse { cat, budget: budget.map(|Extension(budget)| budget) }))
+}
 
-    // for some reason this lib only returns the inserted_id
-    let start_time = Instant::now();
-    let _insert_res = cats_for_create.insert_one(&new_cat).await?;
-    info!("inserting into mongo: {:?}", start_time.elapsed());
-    // so i might just return the cat_for_create
+#[utoipa::path(
+    get,
+    path = "/users/{user}/inventory",
+    params(("user" = String, Path, description = "User id")),
+    responses(
+        (status = 200, description = "All the cats a user unboxed", body = Inventory)
+    ),
+    tag = "cats"
+)]
+pub async fn get_inventory(Path(user): Path<String>, State(state): State<CatsState>) -> Result<impl IntoResponse, super::Error> {
+    let inventory = service::get_inventory(&state, &user).await?;
 
-    Ok(Json(new_cat))
-}
\ No newline at end of file
+    Ok(Json(inventory))
+}
+
+/// The effective drop odds, optionally for a specific breed id.
+#[utoipa::path(
+    get,
+    path = "/rarities",
+    params(RarityParams),
+    responses(
+        (status = 200, description = "The drop odds of every rarity", body = RaritiesResponse)
+    ),
+    tag = "cats"
+)]
+pub async fn get_rarities(State(state): State<CatsState>, Query(q): Query<RarityParams>) -> Result<impl IntoResponse, super::Error> {
+    let rarities = state.rarities.odds(q.breed.as_deref());
+
+    Ok(Json(RaritiesResponse { breed: q.breed, rarities }))
+}
+
+/// How full the prefetched cat pool is and how often unboxes were served from it.
+#[utoipa::path(
+    get,
+    path = "/metrics",
+    responses(
+        (status = 200, description = "Metrics of the prefetched cat pool", body = MetricsResponse)
+    ),
+    tag = "cats"
+)]
+pub async fn get_metrics(State(state): State<CatsState>) -> impl IntoResponse {
+    Json(MetricsResponse { pool: state.pool.metrics() })
+}
+
+#[utoipa::path(
+    get,
+    path = "/breeds",
+    responses(
+        (status = 200, description = "Every discovered breed, with stats", body = BreedCatalogue)
+    ),
+    tag = "cats"
+)]
+pub async fn get_breeds(State(state): State<CatsState>) -> Result<impl IntoResponse, super::Error> {
+    let catalogue = service::get_breed_catalogue(&state).await?;
+
+    Ok(Json(catalogue))
+}
+
+#[utoipa::path(
+    get,
+    path = "/breeds/{id}",
+    params(("id" = String, Path, description = "Breed id, like `abys`")),
+    responses(
+        (status = 200, description = "Stats of a single discovered breed", body = BreedStats),
+        (status = 400, description = "Invalid breed id", body = Problem, content_type = "application/problem+json"),
+        (status = 404, description = "Breed not discovered yet", body = Problem, content_type = "application/problem+json")
+    ),
+    tag = "cats"
+)]
+pub async fn get_breed(id: BreedId, State(state): State<CatsState>) -> Result<impl IntoResponse, super::Error> {
+    let breed = service::get_breed(&state, id.as_str()).await?;
+
+    Ok(Json(breed))
+}
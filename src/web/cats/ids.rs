@@ -0,0 +1,95 @@
+use std::fmt;
+use async_trait::async_trait;
+use axum::{extract::{FromRequestParts, Path}, http::request::Parts};
+use serde::{Deserialize, Serialize};
+
+// NOTE:
+// these only check the format, so that obviously broken ids are a 400 instead of going to mongo and being a 404
+
+/// An image id from TheCatAPI, like `0XYvRd7oD` (or `fx-abys-1` for the fixtures).
+#[derive(Debug, Clone, PartialEq, Eq, Hash, Serialize, Deserialize)]
+#[serde(transparent)]
+pub struct CatId(String);
+
+/// A breed id from TheCatAPI, like `abys`.
+#[derive(Debug, Clone, PartialEq, Eq, Hash, Serialize, Deserialize)]
+#[serde(transparent)]
+pub struct BreedId(String);
+
+impl CatId {
+    pub fn parse(id: &str) -> Result<Self, super::Error> {
+        let valid = (1..=32).contains(&id.len())
+            && id.chars().all(|c| c.is_ascii_alphanumeric() || c == '_' || c == '-');
+
+        if !valid {
+            return Err(super::Error::InvalidCatId { id: id.to_string() });
+        }
+
+        Ok(Self(id.to_string()))
+    }
+
+    pub fn as_str(&self) -> &str {
+        &self.0
+    }
+}
+
+impl BreedId {
+    pub fn parse(id: &str) -> Result<Self, super::Error> {
+        let valid = (1..=16).contains(&id.len()) && id.chars().all(|c| c.is_ascii_alphanumeric());
+
+        if !valid {
+            return Err(super::Error::InvalidBreedId { id: id.to_string() });
+        }
+
+        Ok(Self(id.to_string()))
+    }
+
+    pub fn as_str(&self) -> &str {
+        &self.0
+    }
+}
+
+impl fmt::Display for CatId {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(&self.0)
+    }
+}
+
+impl fmt::Display for BreedId {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(&self.0)
+    }
+}
+
+/// Extracts the `:id` of the path, for routes like `/cats/:id`.
+#[async_trait]
+impl<S> FromRequestParts<S> for CatId
+where
+    S: Send + Sync,
+{
+    type Rejection = super::Error;
+
+    async fn from_request_parts(parts: &mut Parts, state: &S) -> Result<Self, Self::Rejection> {
+        Self::parse(&path_id(parts, state).await)
+    }
+}
+
+/// Extracts the `:id` of the path, for routes like `/cats/breeds/:id`.
+#[async_trait]
+impl<S> FromRequestParts<S> for BreedId
+where
+    S: Send + Sync,
+{
+    type Rejection = super::Error;
+
+    async fn from_request_parts(parts: &mut Parts, state: &S) -> Result<Self, Self::Rejection> {
+        Self::parse(&path_id(parts, state).await)
+    }
+}
+
+/// An empty id if there is no `:id`, which then fails to parse.
+async fn path_id<S: Send + Sync>(parts: &mut Parts, state: &S) -> String {
+    Path::<String>::from_request_parts(parts, state).await
+        .map(|Path(id)| id)
+        .unwrap_or_default()
+}
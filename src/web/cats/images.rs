@@ -0,0 +1,112 @@
+use std::{collections::{BTreeMap, HashSet}, sync::{Arc, Mutex}};
+use mongodb::bson::{self, doc, DateTime};
+use tracing::{error, info};
+use crate::web::{storage::Blob, thumbnails::{sniff_content_type, thumbnail}};
+use super::{model::{CatImages, ImageSize, MirroredImage}, CatsState};
+
+/// Mirrors the image in the background, so that unboxing doesn't wait for it.
+/// Does nothing if the image of that cat is already being mirrored.
+pub fn spawn_mirror(state: CatsState, cat_id: String, img_url: String) {
+    let Some(pending) = Pending::start(&state.mirroring, &cat_id) else {
+        return;
+    };
+
+    tokio::spawn(async move {
+        let _pending = pending;
+        if let Err(e) = mirror_cat_image(&state, &cat_id, &img_url).await {
+            error!("->> could not mirror the image of cat {}: {}", cat_id, e);
+        }
+    });
+}
+
+/// A cat in the set of the ones being mirrored, taken out again when this is dropped (even if the mirror panics).
+struct Pending {
+    set: Arc<Mutex<HashSet<String>>>,
+    cat_id: String
+}
+
+impl Pending {
+    fn start(set: &Arc<Mutex<HashSet<String>>>, cat_id: &str) -> Option<Self> {
+        let inserted = set.lock().expect("mirroring lock poisoned").insert(cat_id.to_string());
+
+        inserted.then(|| Self { set: set.clone(), cat_id: cat_id.to_string() })
+    }
+}
+
+impl Drop for Pending {
+    fn drop(&mut self) {
+        self.set.lock().expect("mirroring lock poisoned").remove(&self.cat_id);
+    }
+}
+
+/// Downloads a cat's image once, stores it and its thumbnails in the blob store and records them on the cat.
+pub async fn mirror_cat_image(state: &CatsState, cat_id: &str, img_url: &str) -> Result<CatImages, super::Error> {
+    let bytes = state.source.fetch_image(img_url).await?;
+    let content_type = sniff_content_type(&bytes)
+        .ok_or(super::Error::ImageNotAvailable { url: img_url.to_string() })?;
+
+    // decoding and resizing is cpu work, so it's kept off the async threads
+    let thumbnails = {
+        let bytes = bytes.clone();
+        tokio::task::spawn_blocking(move || {
+            ImageSize::THUMBNAILS.map(|size| (size, size.pixels().and_then(|pixels| thumbnail(&bytes, pixels))))
+        }).await.expect("thumbnail generation panicked")
+    };
+
+    let mut mirrored = BTreeMap::new();
+    mirrored.insert(ImageSize::Original.to_string(), store(state, cat_id, ImageSize::Original, bytes, content_type).await?);
+
+    for (size, thumbnail) in thumbnails {
+        match thumbnail {
+            Some(thumbnail) => {
+                mirrored.insert(size.to_string(), store(state, cat_id, size, thumbnail, "image/png").await?);
+            },
+            None => info!("can't make a {} thumbnail of cat {} ({}), the original will be served", size, cat_id, content_type)
+        }
+    }
+
+    let images = CatImages {
+        original_url: img_url.to_string(),
+        mirrored,
+        mirrored_at: DateTime::now()
+    };
+
+    state.cats
+        .update_one(doc! { "_id": cat_id }, doc! { "$set": { "images": bson::to_bson(&images)?, "updatedAt": DateTime::now() } })
+        .await?;
+    info!("mirrored the image of cat {} ({} sizes)", cat_id, images.mirrored.len());
+
+    Ok(images)
+}
+
+async fn store(state: &CatsState, cat_id: &str, size: ImageSize, bytes: Vec<u8>, content_type: &str) -> Result<MirroredImage, super::Error> {
+    let key = format!("cats/{}/{}", cat_id, size);
+    state.blobs.put(&key, Blob { bytes, content_type: content_type.to_string() }).await?;
+
+    Ok(MirroredImage {
+        url: format!("/cats/{}/image?size={}", cat_id, size),
+        key,
+        content_type: content_type.to_string()
+    })
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn a_cat_is_only_mirrored_once_at_a_time() {
+        let set = Arc::new(Mutex::new(HashSet::new()));
+
+        let first = Pending::start(&set, "cat").unwrap();
+        assert!(Pending::start(&set, "cat").is_none());
+
+        let other = Pending::start(&set, "other cat").unwrap();
+        drop(first);
+        assert!(Pending::start(&set, "cat").is_some());
+
+        drop(other);
+        assert!(set.lock().unwrap().is_empty());
+    }
+}
@@ -0,0 +1,286 @@
+use std::{collections::HashMap, net::{IpAddr, SocketAddr}, sync::{Arc, Mutex}, time::{Duration, Instant}};
+use axum::{extract::{ConnectInfo, Request, State}, http::{HeaderMap, HeaderValue}, middleware::Next, response::Response};
+use chrono::{DateTime, Utc};
+use serde::Serialize;
+use tracing::info;
+use utoipa::ToSchema;
+use crate::web::auth::AuthUser;
+use super::CatsState;
+
+pub const DEFAULT_UNBOXES_PER_USER: u32 = 20;
+pub const DEFAULT_UNBOXES_PER_IP: u32 = 50;
+/// How many proxies in front of the service append to `X-Forwarded-For`, shuttle has one.
+pub const DEFAULT_TRUSTED_PROXY_HOPS: usize = 1;
+/// How long an empty budget takes to fill up again, it refills gradually, one unbox at a time.
+const REFILL_PERIOD: Duration = Duration::from_secs(24 * 60 * 60);
+/// How often the full buckets are dropped, they're the same as missing ones.
+const PRUNE_INTERVAL: Duration = Duration::from_secs(10 * 60);
+
+/// What's left of an unbox budget, sent in the `X-RateLimit-*` headers and in the body of `POST /cats/random`.
+#[derive(Debug, Clone, Serialize, ToSchema)]
+#[serde(rename_all = "camelCase")]
+pub struct UnboxBudget {
+    pub limit: u32,
+    pub remaining: u32,
+    /// When the budget is full again.
+    #[schema(value_type = String, format = DateTime)]
+    pub reset_at: DateTime<Utc>,
+    /// When the next unbox is available, only set if there are none left.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    #[schema(value_type = Option<String>, format = DateTime)]
+    pub retry_at: Option<DateTime<Utc>>
+}
+
+impl UnboxBudget {
+    pub fn retry_after_secs(&self) -> i64 {
+        self.retry_at
+            .map(|at| (at - Utc::now()).num_seconds().max(1))
+            .unwrap_or(0)
+    }
+
+    pub fn write_headers(&self, headers: &mut HeaderMap) {
+        headers.insert("X-RateLimit-Limit", HeaderValue::from(self.limit));
+        headers.insert("X-RateLimit-Remaining", HeaderValue::from(self.remaining));
+        headers.insert("X-RateLimit-Reset", HeaderValue::from(self.reset_at.timestamp()));
+    }
+}
+
+#[derive(Debug, Clone, Copy)]
+struct Bucket {
+    tokens: f64,
+    updated: Instant
+}
+
+/// Token buckets of unboxes, one per user and one per ip. Every unbox takes a token from both,
+/// and each bucket gets `limit` tokens back over `REFILL_PERIOD`.
+///
+/// Kept in memory, so restarting the service resets everyone's budgets.
+#[derive(Debug)]
+pub struct UnboxLimiter {
+    per_user: u32,
+    per_ip: u32,
+    /// See `client_ip`.
+    trusted_proxy_hops: usize,
+    buckets: Mutex<HashMap<String, Bucket>>
+}
+
+impl UnboxLimiter {
+    pub fn new(per_user: u32, per_ip: u32, trusted_proxy_hops: usize) -> Self {
+        Self { per_user, per_ip, trusted_proxy_hops, buckets: Mutex::new(HashMap::new()) }
+    }
+
+    /// Drops the buckets that have filled up again, returns how many.
+    fn prune(&self) -> usize {
+        let now = Instant::now();
+        let mut buckets = self.buckets.lock().expect("unbox limiter lock poisoned");
+        let before = buckets.len();
+
+        buckets.retain(|key, bucket| {
+            let limit = if key.starts_with("user:") { self.per_user } else { self.per_ip };
+            refilled(*bucket, limit, now).tokens < limit as f64
+        });
+
+        before - buckets.len()
+    }
+
+    /// Takes an unbox from all the given budgets if all of them have one left, otherwise from none.
+    /// Returns the most depleted budget either way, `Err` if it had nothing left.
+    fn take(&self, keys: &[(String, u32)]) -> Result<UnboxBudget, UnboxBudget> {
+        let now = Instant::now();
+        let mut buckets = self.buckets.lock().expect("unbox limiter lock poisoned");
+
+        let current = keys.iter()
+            .map(|(key, limit)| {
+                let bucket = buckets.get(key)
+                    .map(|bucket| refilled(*bucket, *limit, now))
+                    .unwrap_or(Bucket { tokens: *limit as f64, updated: now });
+                (key, *limit, bucket)
+            })
+            .collect::<Vec<_>>();
+
+        let allowed = current.iter().all(|(_, _, bucket)| bucket.tokens >= 1.0);
+
+        let mut budgets = Vec::with_capacity(current.len());
+        for (key, limit, mut bucket) in current {
+            if allowed {
+                bucket.tokens -= 1.0;
+            }
+            buckets.insert(key.clone(), bucket);
+            budgets.push(budget(bucket, limit));
+        }
+
+        let tightest = budgets.into_iter()
+            .min_by_key(|budget| (budget.remaining, std::cmp::Reverse(budget.reset_at)))
+            .expect("there is always an ip budget");
+
+        if allowed { Ok(tightest) } else { Err(tightest) }
+    }
+
+    /// Gives back an unbox that failed, so that errors don't eat into the budget.
+    fn refund(&self, keys: &[(String, u32)]) {
+        let mut buckets = self.buckets.lock().expect("unbox limiter lock poisoned");
+
+        for (key, limit) in keys {
+            if let Some(bucket) = buckets.get_mut(key) {
+                bucket.tokens = (bucket.tokens + 1.0).min(*limit as f64);
+            }
+        }
+    }
+}
+
+/// Drops the full buckets every `PRUNE_INTERVAL`, so that the map doesn't grow with every ip that ever unboxed.
+pub fn spawn_pruner(limiter: Arc<UnboxLimiter>) {
+    tokio::spawn(async move {
+        loop {
+            tokio::time::sleep(PRUNE_INTERVAL).await;
+            match limiter.prune() {
+                0 => {},
+                pruned => info!("pruned {} full unbox budgets", pruned)
+            }
+        }
+    });
+}
+
+fn refilled(bucket: Bucket, limit: u32, now: Instant) -> Bucket {
+    let per_sec = limit as f64 / REFILL_PERIOD.as_secs_f64();
+    let elapsed = now.duration_since(bucket.updated).as_secs_f64();
+
+    Bucket {
+        tokens: (bucket.tokens + elapsed * per_sec).min(limit as f64),
+        updated: now
+    }
+}
+
+fn budget(bucket: Bucket, limit: u32) -> UnboxBudget {
+    let secs_per_token = REFILL_PERIOD.as_secs_f64() / limit as f64;
+    let in_secs = |tokens: f64| chrono::Duration::milliseconds((tokens.max(0.0) * secs_per_token * 1000.0) as i64);
+    let now = Utc::now();
+
+    UnboxBudget {
+        limit,
+        remaining: bucket.tokens.floor() as u32,
+        reset_at: now + in_secs(limit as f64 - bucket.tokens),
+        retry_at: (bucket.tokens < 1.0).then(|| now + in_secs(1.0 - bucket.tokens))
+    }
+}
+
+
+/// Enforces the unbox budgets, has to run after `optional_auth_mw` so that logged in users get their own budget.
+///
+/// The budget is put in the request extensions for the handler to report, and failed unboxes are refunded.
+pub async fn unbox_limit_mw(
+    State(state): State<CatsState>,
+    mut req: Request,
+    next: Next,
+) -> Result<Response, super::Error> {
+    let peer = req.extensions().get::<ConnectInfo<SocketAddr>>().map(|info| info.0.ip());
+    let ip = client_ip(req.headers(), peer, state.limiter.trusted_proxy_hops);
+
+    let mut keys = vec![(format!("ip:{}", ip), state.limiter.per_ip)];
+    if let Some(user) = req.extensions().get::<AuthUser>() {
+        keys.push((format!("user:{}", user.subject), state.limiter.per_user));
+    }
+
+    let budget = state.limiter.take(&keys).map_err(|budget| {
+        info!("unbox limit reached for {:?}", keys);
+        super::Error::UnboxLimitReached { budget }
+    })?;
+
+    req.extensions_mut().insert(budget.clone());
+    let mut res = next.run(req).await;
+
+    let budget = if res.status().is_success() {
+        budget
+    } else {
+        state.limiter.refund(&keys);
+        UnboxBudget { remaining: (budget.remaining + 1).min(budget.limit), ..budget }
+    };
+    budget.write_headers(res.headers_mut());
+
+    Ok(res)
+}
+
+/// The address of the client, as seen by the outermost of the `trusted_proxy_hops` proxies in front of the service.
+///
+/// Each proxy appends the address it got the request from to `X-Forwarded-For`, so only the last `trusted_proxy_hops` entries
+/// can be trusted, anything before them is whatever the client sent. Without a proxy (`0`), or if the header has fewer entries
+/// than there are proxies, it's the peer address.
+fn client_ip(headers: &HeaderMap, peer: Option<IpAddr>, trusted_proxy_hops: usize) -> String {
+    let forwarded = headers.get_all("X-Forwarded-For")
+        .iter()
+        .filter_map(|v| v.to_str().ok())
+        .flat_map(|v| v.split(','))
+        .map(str::trim)
+        .collect::<Vec<_>>();
+
+    let from_proxy = match trusted_proxy_hops {
+        0 => None,
+        hops => forwarded.len().checked_sub(hops).and_then(|i| forwarded[i].parse::<IpAddr>().ok())
+    };
+
+    from_proxy
+        .or(peer)
+        .map(|ip| ip.to_string())
+        .unwrap_or("unknown".into())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn headers(forwarded: &[&str]) -> HeaderMap {
+        let mut headers = HeaderMap::new();
+        for value in forwarded {
+            headers.append("X-Forwarded-For", HeaderValue::from_str(value).unwrap());
+        }
+        headers
+    }
+
+    const PEER: Option<IpAddr> = Some(IpAddr::V4(std::net::Ipv4Addr::new(10, 0, 0, 1)));
+
+    #[test]
+    fn client_ip_is_the_entry_the_trusted_proxy_appended() {
+        assert_eq!(client_ip(&headers(&["203.0.113.7"]), PEER, 1), "203.0.113.7");
+        // whatever the client put in the header before the proxy appended to it is ignored
+        assert_eq!(client_ip(&headers(&["1.2.3.4, 203.0.113.7"]), PEER, 1), "203.0.113.7");
+        assert_eq!(client_ip(&headers(&["1.2.3.4", "203.0.113.7"]), PEER, 1), "203.0.113.7");
+        assert_eq!(client_ip(&headers(&["1.2.3.4, 203.0.113.7, 198.51.100.2"]), PEER, 2), "203.0.113.7");
+    }
+
+    #[test]
+    fn client_ip_falls_back_to_the_peer() {
+        // not behind a proxy, the header is the client's own
+        assert_eq!(client_ip(&headers(&["1.2.3.4"]), PEER, 0), "10.0.0.1");
+        // fewer entries than proxies, it didn't come through all of them
+        assert_eq!(client_ip(&headers(&["203.0.113.7"]), PEER, 2), "10.0.0.1");
+        assert_eq!(client_ip(&headers(&[]), PEER, 1), "10.0.0.1");
+        assert_eq!(client_ip(&headers(&["not an ip"]), PEER, 1), "10.0.0.1");
+        assert_eq!(client_ip(&headers(&[]), None, 1), "unknown");
+    }
+
+    #[test]
+    fn spoofed_headers_share_one_budget() {
+        let limiter = UnboxLimiter::new(20, 2, 1);
+        let take = |spoofed: &str| {
+            let ip = client_ip(&headers(&[&format!("{}, 203.0.113.7", spoofed)]), PEER, 1);
+            limiter.take(&[(format!("ip:{}", ip), limiter.per_ip)])
+        };
+
+        assert!(take("1.1.1.1").is_ok());
+        assert!(take("2.2.2.2").is_ok());
+        assert!(take("3.3.3.3").is_err());
+    }
+
+    #[test]
+    fn prune_only_drops_full_buckets() {
+        let limiter = UnboxLimiter::new(20, 50, 1);
+        limiter.take(&[("ip:a".into(), 50)]).unwrap();
+        limiter.take(&[("ip:b".into(), 50)]).unwrap();
+        limiter.refund(&[("ip:b".into(), 50)]);
+
+        assert_eq!(limiter.prune(), 1);
+        let buckets = limiter.buckets.lock().unwrap();
+        assert!(buckets.contains_key("ip:a"));
+        assert!(!buckets.contains_key("ip:b"));
+    }
+}
@@ -1,27 +1,158 @@
+use std::collections::HashSet;
+use std::sync::{Arc, Mutex};
+use axum::extract::FromRef;
+use axum::middleware::from_fn_with_state;
 use axum::response::IntoResponse;
-use axum::{Json, Router};
-use axum::routing::{get, post};
+use axum::Router;
+use axum::routing::{get, patch, post};
 use axum::http::StatusCode;
-use tracing::error;
+use mongodb::{bson::doc, Collection, IndexModel};
+use tower_http::services::ServeDir;
+use tracing::info;
+use utoipa::OpenApi;
 
-use self::model::Cat;
+use self::limits::{UnboxBudget, UnboxLimiter};
+use self::model::{Cat, CatSortBy, Unbox, Wallet};
+use self::names::PetNameGenerator;
+use self::prefetch::CatPool;
+use self::rarities::RarityTable;
+use self::source::{CatSource, FIXTURE_IMAGES_ROUTE};
+use self::trades::{Trade, TradeEvent};
+use super::{error::{problem_response, ApiError, Problem}, auth::{self, AuthLayer}, storage::{BlobStore, StorageError}, ClientWithKeys};
 
-mod model;
+pub mod ids;
+pub mod images;
+pub mod limits;
+pub mod model;
+pub mod names;
+pub mod prefetch;
+pub mod rarities;
+pub mod service;
+pub mod source;
+pub mod trades;
 mod controller;
-mod rarities;
-mod names;
 
 
-pub fn routes(db: mongodb::Database) -> Router {
-    let cats = db.collection::<Cat>("cats");
+/// Shared by the `/cats` routes and the discord bot.
+#[derive(Debug, Clone, FromRef)]
+pub struct CatsState {
+    pub cats: Collection<Cat>,
+    pub unboxes: Collection<Unbox>,
+    pub trades: Collection<Trade>,
+    pub trade_events: Collection<TradeEvent>,
+    pub wallets: Collection<Wallet>,
+    /// For the transactions of trades and re-rolls.
+    pub mongo: mongodb::Client,
+    pub rarities: Arc<RarityTable>,
+    pub names: Arc<PetNameGenerator>,
+    pub source: Arc<dyn CatSource>,
+    pub limiter: Arc<UnboxLimiter>,
+    pub pool: Arc<CatPool>,
+    /// Where the mirrored cat images are kept.
+    pub blobs: Arc<dyn BlobStore>,
+    /// The ids of the cats whose images are being mirrored right now, so that they're only downloaded once.
+    pub mirroring: Arc<Mutex<HashSet<String>>>,
+    pub client: ClientWithKeys,
+}
+
+impl CatsState {
+    #[allow(clippy::too_many_arguments)]
+    pub fn new(db: &mongodb::Database, rarities: RarityTable, names: PetNameGenerator, source: Arc<dyn CatSource>, limiter: UnboxLimiter, pool: CatPool, blobs: Arc<dyn BlobStore>, client: ClientWithKeys) -> Self {
+        Self {
+            cats: db.collection::<Cat>("cats"),
+            unboxes: db.collection::<Unbox>("unboxes"),
+            trades: db.collection::<Trade>("trades"),
+            trade_events: db.collection::<TradeEvent>("trade_events"),
+            wallets: db.collection::<Wallet>("wallets"),
+            mongo: db.client().clone(),
+            rarities: Arc::new(rarities),
+            names: Arc::new(names),
+            source,
+            limiter: Arc::new(limiter),
+            pool: Arc::new(pool),
+            blobs,
+            mirroring: Arc::new(Mutex::new(HashSet::new())),
+            client
+        }
+    }
+}
+
+#[derive(OpenApi)]
+#[openapi(
+    paths(
+        controller::get_all, controller::get_one, controller::update_one, controller::reroll, controller::get_image,
+        controller::get_random, controller::get_rarities, controller::get_metrics,
+        controller::get_breeds, controller::get_breed, controller::get_inventory, controller::get_wallet
+    ),
+    components(schemas(
+        model::Cat, model::CatForCreate, model::Breed, model::CatImages, model::MirroredImage, model::ImageSize,
+        model::CatPage, model::CatSortBy, model::Sort, model::CatForUpdate, model::Reroll, model::Wallet,
+        model::BreedCatalogue, model::BreedStats, model::TemperamentCount, model::Inventory, model::InventoryEntry,
+        controller::UnboxResponse, controller::RaritiesResponse, controller::MetricsResponse,
+        limits::UnboxBudget, rarities::RarityOdds, prefetch::PoolMetrics
+    )),
+    tags((name = "cats", description = "Unboxing, browsing and collecting cats"))
+)]
+pub struct CatsApi;
+
+pub async fn routes(state: CatsState, auth: AuthLayer) -> Result<Router, mongodb::error::Error> {
+    create_indexes(&state).await?;
+    self::trades::spawn_expiry_sweeper(state.clone());
+    self::limits::spawn_pruner(state.limiter.clone());
+
+    let mut router = Router::new();
 
-    Router::new()
+    if let Some(image_dir) = state.source.image_dir() {
+        router = router.nest_service(FIXTURE_IMAGES_ROUTE, ServeDir::new(image_dir));
+    }
+
+    let router = router
         .route("/", get(self::controller::get_all))
-        .route("/:id", get(self::controller::get_one))
-        .route("/random", post(self::controller::get_random))
-        .with_state(cats)
+        .route("/:id", get(self::controller::get_one)
+            .merge(patch(self::controller::update_one).layer(from_fn_with_state(auth.clone(), auth::auth_mw))))
+        .route("/:id/reroll", post(self::controller::reroll).layer(from_fn_with_state(auth.clone(), auth::auth_mw)))
+        .route("/:id/image", get(self::controller::get_image))
+        .route("/random", post(self::controller::get_random)
+            .layer(from_fn_with_state(state.clone(), limits::unbox_limit_mw))
+            .layer(from_fn_with_state(auth.clone(), auth::optional_auth_mw)))
+        .route("/rarities", get(self::controller::get_rarities))
+        .route("/metrics", get(self::controller::get_metrics))
+        .route("/breeds", get(self::controller::get_breeds))
+        .route("/breeds/:id", get(self::controller::get_breed))
+        .route("/users/:user/inventory", get(self::controller::get_inventory))
+        .route("/wallet", get(self::controller::get_wallet).layer(from_fn_with_state(auth.clone(), auth::auth_mw)))
+        .nest("/trades", self::trades::routes(auth))
+        .with_state(state);
+
+    Ok(router)
 }
 
+/// Indexes for the filters and sorts of `GET /cats` and for the inventory lookups, creating existing ones again is a no-op.
+async fn create_indexes(state: &CatsState) -> Result<(), mongodb::error::Error> {
+    let sort_indexes = CatSortBy::ALL.iter()
+        .map(|sort_by| IndexModel::builder().keys(doc! { sort_by.field(): 1, "_id": 1 }).build());
+
+    let filter_indexes = ["rarity", "breed.id", "breed.country_code", "breed.temperament"].into_iter()
+        .map(|field| IndexModel::builder().keys(doc! { field: 1 }).build());
+
+    state.cats.create_indexes(sort_indexes.chain(filter_indexes)).await?;
+    state.unboxes.create_index(IndexModel::builder().keys(doc! { "userId": 1, "catId": 1 }).build()).await?;
+
+    state.trades.create_indexes([
+        IndexModel::builder().keys(doc! { "fromUser": 1, "createdAt": -1 }).build(),
+        IndexModel::builder().keys(doc! { "toUser": 1, "createdAt": -1 }).build(),
+        IndexModel::builder().keys(doc! { "status": 1, "expiresAt": 1 }).build(),
+    ]).await?;
+    state.trade_events.create_index(IndexModel::builder().keys(doc! { "tradeId": 1, "at": 1 }).build()).await?;
+
+    info!("created cat indexes");
+
+    Ok(())
+}
+
+
+/// What the upstream errors are blamed on in responses, the cat source is the only api an unbox can't do without.
+const UPSTREAM: &str = "The cat api";
 
 #[derive(Debug, thiserror::Error)]
 pub enum Error {
@@ -37,28 +168,94 @@ pub enum Error {
     #[error("No random user was delievered from RandomUserAPI")]
     NoPeopleFromRandomUserApi,
 
-    #[error("NOOOOO: {0} also {0:?}")]
+    #[error("Could not parse a RandomCatAPI response: {0}")]
     JsonParseError(#[from] serde_json::Error),
+    #[error("Bson deserialization error: {0}")]
+    BsonDeError(#[from] mongodb::bson::de::Error),
+    #[error("Bson serialization error: {0}")]
+    BsonSerError(#[from] mongodb::bson::ser::Error),
+    #[error("Cat image storage error: {0}")]
+    ImageStorageError(#[from] StorageError),
+    #[error("The image at {url} is not available or not an image")]
+    ImageNotAvailable { url: String },
+    #[error("The image at {url} is over {limit} bytes")]
+    ImageTooLarge { url: String, limit: usize },
 
     // 400s
     #[error("Cat with id {id} not found")]
     NotFound { id: String },
+    #[error("Invalid cat id `{id}`")]
+    InvalidCatId { id: String },
+    #[error("Invalid breed id `{id}`")]
+    InvalidBreedId { id: String },
+    #[error("No cats of breed {id} were discovered yet")]
+    BreedNotFound { id: String },
+    #[error("Invalid cursor")]
+    InvalidCursor,
+    #[error("Trade with id {id} not found")]
+    TradeNotFound { id: String },
+    #[error("Invalid trade id `{id}`")]
+    InvalidTradeId { id: String },
+    #[error("Invalid trade: {reason}")]
+    InvalidTrade { reason: String },
+    #[error("Validation error: {0}")]
+    ValidationError(#[from] validator::ValidationErrors),
+    #[error("No unboxes left, try again in {}s", budget.retry_after_secs())]
+    UnboxLimitReached { budget: UnboxBudget },
+    #[error("Nothing to update, give a `petName` and/or `favourite`")]
+    NothingToUpdate,
+    #[error("You don't own cat {id}")]
+    NotCatOwner { id: String },
+    #[error("Not enough coins, this costs {cost} but you have {balance}")]
+    InsufficientFunds { balance: i64, cost: i64 },
+    #[error("Only the other side of the trade can do that")]
+    NotTradeParticipant,
+    #[error("The trade is already {status}")]
+    TradeNotPending { status: String },
+    #[error("Some of the cats in the trade changed owners since it was offered")]
+    CatsNoLongerOwned,
+}
+
+impl ApiError for Error {
+    fn problem(&self) -> Problem {
+        match self {
+            // the internal error text only goes to the logs
+            Self::CatDbError(_) | Self::BsonDeError(_) | Self::BsonSerError(_) | Self::ImageStorageError(_) => Problem::internal(),
+            Self::CatReqwestError(e) => Problem::upstream(UPSTREAM, e),
+            Self::NoCatsFromRandomCatApi | Self::NoBreedsFromRandomCatApi | Self::NoPeopleFromRandomUserApi
+                | Self::JsonParseError(_) | Self::ImageNotAvailable { url: _ } | Self::ImageTooLarge { url: _, limit: _ } => Problem::bad_gateway(UPSTREAM),
+
+            Self::NotFound { id: _ } => Problem::new(StatusCode::NOT_FOUND, "cat_not_found", self),
+            Self::BreedNotFound { id: _ } => Problem::new(StatusCode::NOT_FOUND, "breed_not_found", self),
+            Self::TradeNotFound { id: _ } => Problem::new(StatusCode::NOT_FOUND, "trade_not_found", self),
+            Self::InvalidCatId { id: _ } => Problem::new(StatusCode::BAD_REQUEST, "invalid_cat_id", self),
+            Self::InvalidBreedId { id: _ } => Problem::new(StatusCode::BAD_REQUEST, "invalid_breed_id", self),
+            Self::InvalidCursor => Problem::new(StatusCode::BAD_REQUEST, "invalid_cursor", self),
+            Self::InvalidTradeId { id: _ } => Problem::new(StatusCode::BAD_REQUEST, "invalid_trade_id", self),
+            Self::InvalidTrade { reason: _ } => Problem::new(StatusCode::BAD_REQUEST, "invalid_trade", self),
+            Self::ValidationError(errors) => Problem::new(StatusCode::BAD_REQUEST, "validation_error", "Validation error")
+                .with_errors(errors),
+            Self::NothingToUpdate => Problem::new(StatusCode::BAD_REQUEST, "nothing_to_update", self),
+            Self::NotCatOwner { id: _ } => Problem::new(StatusCode::FORBIDDEN, "not_cat_owner", self),
+            Self::NotTradeParticipant => Problem::new(StatusCode::FORBIDDEN, "not_trade_participant", self),
+            Self::InsufficientFunds { balance: _, cost: _ } => Problem::new(StatusCode::PAYMENT_REQUIRED, "insufficient_funds", self),
+            Self::TradeNotPending { status: _ } => Problem::new(StatusCode::CONFLICT, "trade_not_pending", self),
+            Self::CatsNoLongerOwned => Problem::new(StatusCode::CONFLICT, "cats_no_longer_owned", self),
+            Self::UnboxLimitReached { budget } => Problem::new(StatusCode::TOO_MANY_REQUESTS, "unbox_limit_reached", self)
+                .with_retry_after(budget.retry_after_secs() as u64)
+                .with_errors(budget),
+        }
+    }
 }
 
 impl IntoResponse for Error {
     fn into_response(self) -> axum::response::Response {
-        error!("->> {}", self);
-
-        let body = Json(serde_json::json!({
-            "error": format!("{}", &self)
-        }));
-
-        let status_code = match &self {
-            Self::NotFound { id: _ } => StatusCode::NOT_FOUND,
-            _ => StatusCode::INTERNAL_SERVER_ERROR
-        };
-        
-        (status_code, body).into_response()
+        let mut res = problem_response(&self);
+
+        if let Self::UnboxLimitReached { budget } = &self {
+            budget.write_headers(res.headers_mut());
+        }
+
+        res
     }
 }
-
@@ -1,9 +1,12 @@
+use std::collections::BTreeMap;
 use serde::{Deserialize, Serialize};
-use mongodb::bson::DateTime;
+use mongodb::bson::{oid::ObjectId, DateTime, Document};
+use utoipa::{IntoParams, ToSchema};
+use validator::{Validate, ValidationError};
 use crate::helpers::split_and_collect;
 
 
-#[derive(Debug, Clone, Serialize, Deserialize)]
+#[derive(Debug, Clone, Serialize, Deserialize, ToSchema)]
 pub struct Breed {
     pub id: String,
     pub name: String,
@@ -15,7 +18,7 @@ pub struct Breed {
     pub wikipedia_url: String
 }
 
-#[derive(Debug, Clone, Serialize, Deserialize)]
+#[derive(Debug, Clone, Serialize, Deserialize, ToSchema)]
 #[serde(rename_all = "camelCase")]
 pub struct Cat {
     #[serde(rename = "_id")]
@@ -25,7 +28,12 @@ pub struct Cat {
     pub rarity: String,
     pub pet_name: String,
     pub full_name: String,
+    /// Copies of the image at `img_url`, set once it was mirrored.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub images: Option<CatImages>,
+    #[schema(value_type = Object)]
     pub created_at: DateTime,
+    #[schema(value_type = Object)]
     pub updated_at: DateTime
 }
 
@@ -38,13 +46,14 @@ impl From<Cat> for CatForCreate {
             rarity: cat.rarity,
             pet_name: cat.pet_name,
             full_name: cat.full_name,
+            images: cat.images,
             created_at: cat.created_at,
             updated_at: cat.updated_at
         }
     }
 }
 
-#[derive(Debug, Clone, Serialize, Deserialize)]
+#[derive(Debug, Clone, Serialize, Deserialize, ToSchema)]
 #[serde(rename_all = "camelCase")]
 pub struct CatForCreate {
     #[serde(rename = "_id")]
@@ -54,10 +63,399 @@ pub struct CatForCreate {
     pub rarity: String,
     pub pet_name: String,
     pub full_name: String,
+    /// Copies of the image at `img_url`, set once it was mirrored.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub images: Option<CatImages>,
+    #[schema(value_type = Object)]
     pub created_at: DateTime,
+    #[schema(value_type = Object)]
     pub updated_at: DateTime
 }
 
+/// The sizes of `GET /cats/:id/image?size=`, thumbnails fit in a square of their size.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize, strum_macros::Display, ToSchema)]
+#[serde(rename_all = "lowercase")]
+#[strum(serialize_all = "lowercase")]
+pub enum ImageSize {
+    #[default]
+    Original,
+    Small,
+    Medium,
+    Large
+}
+
+impl ImageSize {
+    pub const THUMBNAILS: [ImageSize; 3] = [Self::Small, Self::Medium, Self::Large];
+
+    pub fn pixels(&self) -> Option<u32> {
+        match self {
+            Self::Original => None,
+            Self::Small => Some(128),
+            Self::Medium => Some(256),
+            Self::Large => Some(512)
+        }
+    }
+}
+
+/// A copy of a cat image in the blob store.
+#[derive(Debug, Clone, Serialize, Deserialize, ToSchema)]
+#[serde(rename_all = "camelCase")]
+pub struct MirroredImage {
+    /// Where it's served from, `/cats/:id/image?size=`.
+    pub url: String,
+    pub key: String,
+    pub content_type: String
+}
+
+/// The mirrored copies of a cat's image, by size name. Sizes without thumbnails (images that couldn't be decoded) are missing.
+#[derive(Debug, Clone, Serialize, Deserialize, ToSchema)]
+#[serde(rename_all = "camelCase")]
+pub struct CatImages {
+    pub original_url: String,
+    pub mirrored: BTreeMap<String, MirroredImage>,
+    #[schema(value_type = Object)]
+    pub mirrored_at: DateTime
+}
+
+#[derive(Debug, Clone, Default, Deserialize, IntoParams)]
+#[into_params(parameter_in = Query)]
+pub struct ImageParams {
+    #[serde(default)]
+    pub size: ImageSize
+}
+
+/// Query params of `GET /cats`.
+#[derive(Debug, Clone, Default, Deserialize, IntoParams)]
+#[serde(rename_all = "camelCase")]
+#[into_params(parameter_in = Query)]
+pub struct CatListParams {
+    pub rarity: Option<String>,
+    /// `breed.id`
+    pub breed: Option<String>,
+    /// `breed.country_code`
+    pub country: Option<String>,
+    pub temperament: Option<String>,
+    #[param(value_type = Option<String>, format = DateTime)]
+    pub created_after: Option<chrono::DateTime<chrono::Utc>>,
+    #[param(value_type = Option<String>, format = DateTime)]
+    pub created_before: Option<chrono::DateTime<chrono::Utc>>,
+    #[serde(default)]
+    pub sort_by: CatSortBy,
+    #[serde(default)]
+    pub sort: Sort,
+    pub limit: Option<i64>,
+    /// The `nextCursor` of the previous page, with the same `sortBy` and `sort`.
+    pub cursor: Option<String>
+}
+
+impl CatListParams {
+    pub fn filter(&self) -> Document {
+        let mut filter = Document::new();
+
+        if let Some(rarity) = &self.rarity {
+            filter.insert("rarity", rarity);
+        }
+        if let Some(breed) = &self.breed {
+            filter.insert("breed.id", breed);
+        }
+        if let Some(country) = &self.country {
+            filter.insert("breed.country_code", country.to_uppercase());
+        }
+        if let Some(temperament) = &self.temperament {
+            // matches if the array contains it
+            filter.insert("breed.temperament", temperament);
+        }
+
+        let mut created_at = Document::new();
+        if let Some(after) = self.created_after {
+            created_at.insert("$gte", DateTime::from_millis(after.timestamp_millis()));
+        }
+        if let Some(before) = self.created_before {
+            created_at.insert("$lt", DateTime::from_millis(before.timestamp_millis()));
+        }
+        if !created_at.is_empty() {
+            filter.insert("createdAt", created_at);
+        }
+
+        filter
+    }
+}
+
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize, ToSchema)]
+#[serde(rename_all = "camelCase")]
+pub enum CatSortBy {
+    #[default]
+    CreatedAt,
+    UpdatedAt,
+    FullName,
+    Rarity,
+    Breed
+}
+
+impl CatSortBy {
+    pub const ALL: &'static [CatSortBy] = &[Self::CreatedAt, Self::UpdatedAt, Self::FullName, Self::Rarity, Self::Breed];
+
+    pub fn field(&self) -> &'static str {
+        match self {
+            Self::CreatedAt => "createdAt",
+            Self::UpdatedAt => "updatedAt",
+            Self::FullName => "fullName",
+            Self::Rarity => "rarity",
+            Self::Breed => "breed.name",
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize, ToSchema)]
+#[serde(rename_all = "lowercase")]
+pub enum Sort {
+    #[default]
+    Desc,
+    Asc
+}
+
+impl Sort {
+    pub fn direction(&self) -> i32 {
+        match self {
+            Self::Desc => -1,
+            Self::Asc => 1,
+        }
+    }
+}
+
+/// A page of results, `next_cursor` is `None` on the last page.
+#[derive(Debug, Clone, Serialize, ToSchema)]
+#[serde(rename_all = "camelCase")]
+#[aliases(CatPage = Page<Cat>)]
+pub struct Page<T> {
+    pub items: Vec<T>,
+    pub total: u64,
+    pub next_cursor: Option<String>
+}
+
+/// What `$group`ing the cats by breed returns, before the known rarities are filled in.
+#[derive(Debug, Clone, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct BreedAggregate {
+    #[serde(rename = "_id")]
+    pub id: String,
+    pub name: String,
+    pub origins: Vec<String>,
+    pub country_codes: Vec<String>,
+    pub discovered: i64,
+    /// Only the rarities someone got.
+    pub rarities: Vec<RarityCount>,
+    /// How many of the discovered cats have each temperament, counted separately.
+    #[serde(default)]
+    pub temperaments: Vec<TemperamentCount>,
+    pub first_discovered_at: DateTime
+}
+
+#[derive(Debug, Clone, Deserialize)]
+pub struct RarityCount {
+    pub rarity: String,
+    pub count: i64
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize, ToSchema)]
+#[serde(rename_all = "camelCase")]
+pub struct TemperamentCount {
+    pub temperament: String,
+    pub count: i64
+}
+
+#[derive(Debug, Clone, Serialize, ToSchema)]
+#[serde(rename_all = "camelCase")]
+pub struct BreedStats {
+    pub id: String,
+    pub name: String,
+    pub origins: Vec<String>,
+    pub country_codes: Vec<String>,
+    /// How many different cats of this breed were unboxed.
+    pub discovered: i64,
+    pub rarity_distribution: BTreeMap<String, i64>,
+    pub top_temperaments: Vec<TemperamentCount>,
+    #[schema(value_type = Object)]
+    pub first_discovered_at: DateTime
+}
+
+impl BreedStats {
+    pub const TOP_TEMPERAMENTS: usize = 5;
+
+    /// `rarities` are all the known rarity names, so that the ones nobody got yet show up with a 0.
+    pub fn from_aggregate(aggregate: BreedAggregate, rarities: &[String]) -> Self {
+        let mut rarity_distribution = rarities.iter()
+            .map(|rarity| (rarity.clone(), 0))
+            .collect::<BTreeMap<_, _>>();
+        for RarityCount { rarity, count } in aggregate.rarities {
+            *rarity_distribution.entry(rarity).or_insert(0) += count;
+        }
+
+        // most common first, ties by name so the order is stable
+        let mut top_temperaments = aggregate.temperaments;
+        top_temperaments.sort_by(|a, b| b.count.cmp(&a.count).then_with(|| a.temperament.cmp(&b.temperament)));
+        top_temperaments.truncate(Self::TOP_TEMPERAMENTS);
+
+        Self {
+            id: aggregate.id,
+            name: aggregate.name,
+            origins: aggregate.origins,
+            country_codes: aggregate.country_codes,
+            discovered: aggregate.discovered,
+            rarity_distribution,
+            top_temperaments,
+            first_discovered_at: aggregate.first_discovered_at
+        }
+    }
+}
+
+/// The "pokedex" of `GET /cats/breeds`.
+#[derive(Debug, Clone, Serialize, ToSchema)]
+#[serde(rename_all = "camelCase")]
+pub struct BreedCatalogue {
+    pub breeds_discovered: usize,
+    pub cats_discovered: i64,
+    pub breeds: Vec<BreedStats>
+}
+
+/// A single unbox of a cat by a user. The cat itself is stored once in `cats`, this only references it by its `_id`.
+///
+/// Renames and re-rolls only change the owner's copies, so they're kept here and not on the shared cat.
+/// All of a user's unboxes of a cat are changed together, the unset ones fall back to the cat's own.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct Unbox {
+    #[serde(rename = "_id")]
+    pub _id: ObjectId,
+    pub user_id: String,
+    pub cat_id: String,
+    #[serde(default)]
+    pub favourite: bool,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub pet_name: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub full_name: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub rarity: Option<String>,
+    pub unboxed_at: DateTime
+}
+
+impl Unbox {
+    pub fn new(user_id: &str, cat_id: &str) -> Self {
+        Self {
+            _id: ObjectId::new(),
+            user_id: user_id.to_string(),
+            cat_id: cat_id.to_string(),
+            favourite: false,
+            pet_name: None,
+            full_name: None,
+            rarity: None,
+            unboxed_at: DateTime::now()
+        }
+    }
+
+    /// The cat as the owner of this unbox sees it.
+    pub fn apply_to(&self, mut cat: Cat) -> Cat {
+        if let Some(pet_name) = &self.pet_name {
+            cat.pet_name = pet_name.clone();
+        }
+        if let Some(full_name) = &self.full_name {
+            cat.full_name = full_name.clone();
+        }
+        if let Some(rarity) = &self.rarity {
+            cat.rarity = rarity.clone();
+        }
+        cat
+    }
+}
+
+/// Body of `PATCH /cats/:id`, only the given fields are changed.
+#[derive(Debug, Clone, Deserialize, Validate, ToSchema)]
+#[serde(rename_all = "camelCase")]
+pub struct CatForUpdate {
+    /// The new `pet_name`, the `full_name` is generated again from it. Only the user's copies are renamed.
+    #[validate(length(min = 1, max = 32), custom(function = "validate_pet_name"))]
+    pub pet_name: Option<String>,
+    /// Favourites are per user, this marks all of the user's unboxes of the cat.
+    pub favourite: Option<bool>
+}
+
+fn validate_pet_name(name: &str) -> Result<(), ValidationError> {
+    if name.trim() != name || name.chars().any(char::is_control) {
+        return Err(ValidationError::new("pet_name")
+            .with_message("a pet name can't have leading or trailing whitespace or control characters".into()));
+    }
+
+    Ok(())
+}
+
+/// A user's currency, earned by unboxing and spent on re-rolls. The `_id` is the user id.
+#[derive(Debug, Clone, Serialize, Deserialize, ToSchema)]
+#[serde(rename_all = "camelCase")]
+pub struct Wallet {
+    #[serde(rename = "_id")]
+    pub user_id: String,
+    pub balance: i64,
+    #[schema(value_type = Object)]
+    pub updated_at: DateTime
+}
+
+impl Wallet {
+    pub fn empty(user_id: &str) -> Self {
+        Self { user_id: user_id.to_string(), balance: 0, updated_at: DateTime::now() }
+    }
+}
+
+/// The re-rolled cat, with what's left in the wallet.
+#[derive(Debug, Clone, Serialize, ToSchema)]
+#[serde(rename_all = "camelCase")]
+pub struct Reroll {
+    pub cat: Cat,
+    pub previous_rarity: String,
+    pub cost: i64,
+    pub balance: i64
+}
+
+/// All the unboxes of a single cat by a single user, the cat with the user's names and rarity on it.
+#[derive(Debug, Clone, Serialize, Deserialize, ToSchema)]
+#[serde(rename_all = "camelCase")]
+pub struct InventoryEntry {
+    pub cat: Cat,
+    pub count: i64,
+    pub favourite: bool,
+    #[schema(value_type = Object)]
+    pub first_unboxed_at: DateTime,
+    #[schema(value_type = Vec<Object>)]
+    pub unbox_ids: Vec<ObjectId>
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize, ToSchema)]
+#[serde(rename_all = "camelCase")]
+pub struct Inventory {
+    pub user_id: String,
+    pub total_unboxes: i64,
+    pub unique_cats: usize,
+    pub rarity_totals: BTreeMap<String, i64>,
+    pub cats: Vec<InventoryEntry>
+}
+
+impl Inventory {
+    pub fn new(user_id: &str, cats: Vec<InventoryEntry>) -> Self {
+        let mut rarity_totals = BTreeMap::new();
+        for entry in &cats {
+            *rarity_totals.entry(entry.cat.rarity.clone()).or_insert(0) += entry.count;
+        }
+
+        Self {
+            user_id: user_id.to_string(),
+            total_unboxes: cats.iter().map(|entry| entry.count).sum(),
+            unique_cats: cats.len(),
+            rarity_totals,
+            cats
+        }
+    }
+}
+
 #[derive(Debug, Clone)]
 pub struct CatHalfProcessed {
     pub _id: String,
@@ -74,6 +472,7 @@ impl CatHalfProcessed {
             rarity,
             pet_name,
             full_name,
+            images: None,
             created_at: DateTime::now(),
             updated_at: DateTime::now()
         }
@@ -144,3 +543,46 @@ impl BreedUnprocessed {
     }
 }
 
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn temperament(temperament: &str, count: i64) -> TemperamentCount {
+        TemperamentCount { temperament: temperament.to_string(), count }
+    }
+
+    #[test]
+    fn breed_stats_keep_the_most_common_temperaments() {
+        let aggregate = BreedAggregate {
+            id: "abys".to_string(),
+            name: "Abyssinian".to_string(),
+            origins: vec!["Egypt".to_string()],
+            country_codes: vec!["EG".to_string()],
+            discovered: 4,
+            rarities: vec![RarityCount { rarity: "Rare".to_string(), count: 4 }],
+            temperaments: vec![
+                temperament("Active", 1),
+                temperament("Energetic", 4),
+                temperament("Independent", 2),
+                temperament("Intelligent", 2),
+                temperament("Gentle", 3),
+                temperament("Curious", 2),
+            ],
+            first_discovered_at: DateTime::now()
+        };
+        let rarities = ["Common".to_string(), "Rare".to_string()];
+
+        let stats = BreedStats::from_aggregate(aggregate, &rarities);
+
+        assert_eq!(stats.top_temperaments, vec![
+            temperament("Energetic", 4),
+            temperament("Gentle", 3),
+            temperament("Curious", 2),
+            temperament("Independent", 2),
+            temperament("Intelligent", 2),
+        ]);
+        assert_eq!(stats.rarity_distribution, BTreeMap::from([("Common".to_string(), 0), ("Rare".to_string(), 4)]));
+    }
+}
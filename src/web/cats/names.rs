@@ -1,33 +1,153 @@
-
+use std::collections::BTreeMap;
+use rand::{distributions::WeightedIndex, prelude::Distribution, seq::IteratorRandom, Rng};
 use serde::Deserialize;
+use tracing::{info, warn};
 use crate::{helpers::random_choice, web::ClientWithKeys};
 use super::model::Breed;
 
+/// Weighted first names per country, see `PetNameGenerator`.
+pub const PET_NAMES_FILE: &str = "assets/pet_names.csv";
 
-const COUNTRY_CODES: &[&'static str] = &[
+/// The countries randomuser.me has people from, only these can be used for the remote fallback.
+const COUNTRY_CODES: &[&str] = &[
     "AU", "BR", "CA", "CH", "DE", "DK", "ES", "FI", "FR", "GB", "IE", "IN", "IR", "MX", "NL", "NO", "NZ", "RS", "TR", "UA", "US"
 ];
 
-pub async fn get_random_name_from_country(country_code: &str, client: ClientWithKeys) -> Result<String, super::Error> {
-    let country_code = country_code.to_uppercase();
+#[derive(Debug, thiserror::Error)]
+pub enum NamesError {
+    #[error("Could not read the pet names file: {0}")]
+    FileError(#[from] std::io::Error),
+    #[error("Could not parse the pet names file: {0}")]
+    CsvError(#[from] csv::Error),
+    #[error("The pet names file has no names")]
+    NoNames,
+    #[error("Name `{name}` ({country_code}) has a weight of 0")]
+    ZeroWeight { country_code: String, name: String },
+}
+
+#[derive(Debug, Clone, Deserialize)]
+struct NameRecord {
+    country_code: String,
+    name: String,
+    gender: String,
+    weight: u32
+}
+
+/// Names of a single gender, with how common they are.
+#[derive(Debug, Clone)]
+struct WeightedNames {
+    names: Vec<String>,
+    distribution: WeightedIndex<u32>
+}
+
+/// Generates pet names from the bundled corpus, without any network requests.
+///
+/// The corpus is a csv of `country_code,name,gender,weight`. Picking is gender-neutral: first a gender is picked uniformly,
+/// then a name within it by its weight, so a country with more names of one gender doesn't skew the results.
+#[derive(Debug, Clone)]
+pub struct PetNameGenerator {
+    corpus: BTreeMap<String, Vec<WeightedNames>>,
+    /// Whether to ask randomuser.me for a name if the country is not in the corpus.
+    remote_fallback: bool
+}
+
+impl PetNameGenerator {
+    pub fn from_file(path: &str, remote_fallback: bool) -> Result<Self, NamesError> {
+        Self::from_csv(&std::fs::read_to_string(path)?, remote_fallback)
+    }
+
+    pub fn from_csv(contents: &str, remote_fallback: bool) -> Result<Self, NamesError> {
+        let mut rdr = csv::ReaderBuilder::new()
+            .has_headers(true)
+            .from_reader(contents.as_bytes());
+
+        // country -> gender -> names
+        let mut grouped: BTreeMap<String, BTreeMap<String, Vec<(String, u32)>>> = BTreeMap::new();
+        for record in rdr.deserialize::<NameRecord>() {
+            let record = record?;
+            if record.weight == 0 {
+                return Err(NamesError::ZeroWeight { country_code: record.country_code, name: record.name });
+            }
+
+            grouped.entry(record.country_code.to_uppercase())
+                .or_default()
+                .entry(record.gender.to_lowercase())
+                .or_default()
+                .push((record.name, record.weight));
+        }
+
+        if grouped.is_empty() {
+            return Err(NamesError::NoNames);
+        }
+
+        let corpus = grouped.into_iter()
+            .map(|(country_code, genders)| {
+                let genders = genders.into_values()
+                    .map(|names| {
+                        let (names, weights): (Vec<_>, Vec<_>) = names.into_iter().unzip();
+                        // weights are all > 0 and the group is never empty, so this can't fail
+                        let distribution = WeightedIndex::new(weights).expect("weights were checked above");
+                        WeightedNames { names, distribution }
+                    })
+                    .collect();
+
+                (country_code, genders)
+            })
+            .collect::<BTreeMap<_, _>>();
+
+        info!("loaded pet names for {} countries", corpus.len());
+
+        Ok(Self { corpus, remote_fallback })
+    }
+
+    /// Picks a name from the corpus, `None` if there are no names for that country.
+    pub fn pick_local<R: Rng + ?Sized>(&self, country_code: &str, rng: &mut R) -> Option<String> {
+        let genders = self.corpus.get(&country_code.to_uppercase())?;
+        let names = genders.iter().choose(rng)?;
+
+        Some(names.names[names.distribution.sample(rng)].clone())
+    }
+
+    /// Never fails: countries without names get one from the remote fallback if it's enabled, otherwise (or if that fails too)
+    /// one from a random country in the corpus.
+    pub async fn get_random_name_from_country(&self, country_code: &str, client: &ClientWithKeys) -> String {
+        if let Some(name) = self.pick_local(country_code, &mut rand::thread_rng()) {
+            return name;
+        }
+
+        if self.remote_fallback && COUNTRY_CODES.contains(&country_code.to_uppercase().as_str()) {
+            match get_random_name_from_randomuser(country_code, client).await {
+                Ok(name) => return name,
+                Err(e) => warn!("randomuser fallback failed for {}: {}", country_code, e)
+            }
+        }
+
+        self.pick_any(&mut rand::thread_rng())
+    }
 
-    let country_code = if COUNTRY_CODES.contains(&country_code.as_str()) {
-        country_code
-    }  else {
-        random_choice(COUNTRY_CODES).unwrap().to_string()
-    };
+    /// A name from a random country in the corpus.
+    pub fn pick_any<R: Rng + ?Sized>(&self, rng: &mut R) -> String {
+        let random_country = self.corpus.keys()
+            .choose(rng)
+            .expect("the corpus is never empty");
 
+        self.pick_local(random_country, rng)
+            .expect("every country in the corpus has names")
+    }
+}
+
+async fn get_random_name_from_randomuser(country_code: &str, client: &ClientWithKeys) -> Result<String, super::Error> {
     let person = client.client
-        .get(format!("https://randomuser.me/api/?nat={}", country_code))
+        .get(format!("https://randomuser.me/api/?nat={}", country_code.to_uppercase()))
         .send()
         .await?
-        .json::<RandomPersonResponse>() 
+        .json::<RandomPersonResponse>()
         .await?
         .results
         .into_iter()
         .next()
         .ok_or(super::Error::NoPeopleFromRandomUserApi)?;
-    
+
     Ok(person.name.first)
 }
 
@@ -58,4 +178,103 @@ struct RandomPersonName {
     title: String,
     first: String,
     last: String
-}
\ No newline at end of file
+}
+
+
+#[cfg(test)]
+mod tests {
+    use std::collections::HashMap;
+    use rand::{rngs::StdRng, SeedableRng};
+
+    use super::*;
+
+    const CORPUS: &str = "\
+country_code,name,gender,weight
+ie,Aoife,f,1
+IE,Sean,M,3
+IE,Conor,m,1
+IE,Rowan,n,1
+FI,Aino,f,1
+";
+
+    fn generator() -> PetNameGenerator {
+        PetNameGenerator::from_csv(CORPUS, false).unwrap()
+    }
+
+    fn counts(mut pick: impl FnMut() -> String, rolls: usize) -> HashMap<String, usize> {
+        let mut counts = HashMap::new();
+        for _ in 0..rolls {
+            *counts.entry(pick()).or_insert(0) += 1;
+        }
+        counts
+    }
+
+    #[test]
+    fn names_are_picked_by_country() {
+        let generator = generator();
+        let mut rng = StdRng::seed_from_u64(5);
+
+        for _ in 0..100 {
+            assert_eq!(generator.pick_local("fi", &mut rng).as_deref(), Some("Aino"));
+            assert_ne!(generator.pick_local("IE", &mut rng).as_deref(), Some("Aino"));
+        }
+    }
+
+    #[test]
+    fn every_gender_is_as_likely_and_names_follow_their_weight() {
+        let generator = generator();
+        let mut rng = StdRng::seed_from_u64(5);
+
+        let rolls = 30_000;
+        let counts = counts(|| generator.pick_local("IE", &mut rng).unwrap(), rolls);
+        let share = |name: &str| counts.get(name).copied().unwrap_or_default() as f64 / rolls as f64;
+
+        // f, m and n are a third each, whatever the number of names in them
+        assert!((share("Aoife") - 1.0 / 3.0).abs() < 0.02, "{:?}", counts);
+        assert!((share("Rowan") - 1.0 / 3.0).abs() < 0.02, "{:?}", counts);
+        // within m, Sean is 3 times as common as Conor
+        assert!((share("Sean") - 0.25).abs() < 0.02, "{:?}", counts);
+        assert!((share("Conor") - 1.0 / 12.0).abs() < 0.02, "{:?}", counts);
+    }
+
+    #[test]
+    fn seeded_picks_are_deterministic() {
+        let generator = generator();
+        let picks = |seed| {
+            let mut rng = StdRng::seed_from_u64(seed);
+            (0..20).map(|_| generator.pick_local("IE", &mut rng).unwrap()).collect::<Vec<_>>()
+        };
+
+        assert_eq!(picks(7), picks(7));
+    }
+
+    #[test]
+    fn countries_without_names_fall_back_to_any_country() {
+        let generator = generator();
+        let mut rng = StdRng::seed_from_u64(5);
+
+        assert_eq!(generator.pick_local("XX", &mut rng), None);
+
+        let counts = counts(|| generator.pick_any(&mut rng), 1000);
+        assert!(counts.contains_key("Aino"), "{:?}", counts);
+        assert!(counts.contains_key("Sean"), "{:?}", counts);
+        assert_eq!(counts.keys().filter(|name| !["Aoife", "Sean", "Conor", "Rowan", "Aino"].contains(&name.as_str())).count(), 0);
+    }
+
+    #[test]
+    fn invalid_corpora_are_rejected() {
+        assert!(matches!(PetNameGenerator::from_csv("country_code,name,gender,weight\n", false), Err(NamesError::NoNames)));
+        assert!(matches!(
+            PetNameGenerator::from_csv("country_code,name,gender,weight\nIE,Sean,m,0\n", false),
+            Err(NamesError::ZeroWeight { name, .. }) if name == "Sean"
+        ));
+        assert!(matches!(PetNameGenerator::from_csv("country_code,name,gender,weight\nIE,Sean,m,lots\n", false), Err(NamesError::CsvError(_))));
+    }
+
+    #[test]
+    fn the_bundled_corpus_loads() {
+        let generator = PetNameGenerator::from_file(PET_NAMES_FILE, false).unwrap();
+
+        assert!(generator.pick_local("IE", &mut StdRng::seed_from_u64(5)).is_some());
+    }
+}
@@ -0,0 +1,123 @@
+use std::{collections::VecDeque, sync::{atomic::{AtomicU64, Ordering}, Mutex}, time::Duration};
+use serde::Serialize;
+use tokio::sync::Notify;
+use tracing::{error, info};
+use utoipa::ToSchema;
+use super::{model::CatHalfProcessed, CatsState};
+
+pub const DEFAULT_PREFETCH_POOL_SIZE: usize = 10;
+/// How long the refill task waits after the cat source fails, so that it doesn't hammer a broken api.
+const REFILL_BACKOFF: Duration = Duration::from_secs(30);
+
+/// A cat from the cat source that went through everything that needs the network, only the rarity roll and the mongo insert are left.
+#[derive(Debug, Clone)]
+pub struct PrefetchedCat {
+    pub cat: CatHalfProcessed,
+    pub pet_name: String
+}
+
+/// Ready to serve cats for `POST /cats/random`, kept full by a background task so that unboxing doesn't wait on
+/// TheCatAPI and randomuser.me. Whoever pops a cat wakes the task up to replace it.
+#[derive(Debug)]
+pub struct CatPool {
+    cats: Mutex<VecDeque<PrefetchedCat>>,
+    capacity: usize,
+    refill: Notify,
+    hits: AtomicU64,
+    misses: AtomicU64
+}
+
+#[derive(Debug, Clone, Serialize, ToSchema)]
+#[serde(rename_all = "camelCase")]
+pub struct PoolMetrics {
+    pub size: usize,
+    pub capacity: usize,
+    pub hits: u64,
+    pub misses: u64,
+    /// Hits over all pops, `null` before the first one.
+    pub hit_rate: Option<f64>
+}
+
+impl CatPool {
+    /// A capacity of 0 turns prefetching off, every unbox then goes through the live path.
+    pub fn new(capacity: usize) -> Self {
+        Self {
+            cats: Mutex::new(VecDeque::with_capacity(capacity)),
+            capacity,
+            refill: Notify::new(),
+            hits: AtomicU64::new(0),
+            misses: AtomicU64::new(0)
+        }
+    }
+
+    /// Takes the oldest prefetched cat, `None` (counted as a miss) if the pool is empty.
+    pub fn pop(&self) -> Option<PrefetchedCat> {
+        let cat = self.cats.lock().expect("cat pool lock poisoned").pop_front();
+
+        match cat {
+            Some(_) => self.hits.fetch_add(1, Ordering::Relaxed),
+            None => self.misses.fetch_add(1, Ordering::Relaxed)
+        };
+        self.refill.notify_one();
+
+        cat
+    }
+
+    pub fn len(&self) -> usize {
+        self.cats.lock().expect("cat pool lock poisoned").len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    pub fn metrics(&self) -> PoolMetrics {
+        let hits = self.hits.load(Ordering::Relaxed);
+        let misses = self.misses.load(Ordering::Relaxed);
+        let total = hits + misses;
+
+        PoolMetrics {
+            size: self.len(),
+            capacity: self.capacity,
+            hits,
+            misses,
+            hit_rate: (total > 0).then(|| hits as f64 / total as f64)
+        }
+    }
+
+    fn push(&self, cat: PrefetchedCat) {
+        self.cats.lock().expect("cat pool lock poisoned").push_back(cat);
+    }
+}
+
+/// Starts the task that keeps `state.pool` full, does nothing if prefetching is off.
+pub fn spawn_refill(state: CatsState) {
+    if state.pool.capacity == 0 {
+        info!("cat prefetching is off");
+        return;
+    }
+
+    tokio::spawn(async move {
+        loop {
+            while state.pool.len() < state.pool.capacity {
+                match prefetch_cat(&state).await {
+                    Ok(cat) => state.pool.push(cat),
+                    Err(e) => {
+                        error!("->> could not prefetch a cat: {}", e);
+                        tokio::time::sleep(REFILL_BACKOFF).await;
+                    }
+                }
+            }
+
+            info!("cat pool is full ({})", state.pool.capacity);
+            state.pool.refill.notified().await;
+        }
+    });
+}
+
+async fn prefetch_cat(state: &CatsState) -> Result<PrefetchedCat, super::Error> {
+    let cat = state.source.random_cat().await?.start_processing()?;
+    let pet_name = state.names.get_random_name_from_country(&cat.breed.country_code, &state.client).await;
+
+    Ok(PrefetchedCat { cat, pet_name })
+}
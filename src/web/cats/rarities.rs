@@ -1,32 +1,289 @@
+use std::collections::HashMap;
+use mongodb::bson::doc;
+use poise::serenity_prelude::futures::TryStreamExt;
 use rand::Rng;
+use serde::{Deserialize, Deserializer, Serialize, Serializer};
 use tracing::info;
+use utoipa::ToSchema;
 
-const RARITIES: &[&'static str] = &[
-    "COMMON",
-    "RARE",
-    "LEGENDARY",
-    "MYTHIC"
-];
+/// Where the rarity table is read from if the `rarities` mongo collection is empty.
+pub const RARITIES_FILE: &str = "assets/rarities.json";
 
-const RARITY_FACTOR: f64 = 0.3;
+/// A single rarity tier. The chance of getting it is its weight divided by the sum of all weights.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct RarityTier {
+    pub name: String,
+    pub weight: f64,
+    #[serde(serialize_with = "serialize_colour", deserialize_with = "deserialize_colour")]
+    pub colour: u32,
+    /// Multipliers for the weight of this tier, by breed id. For example `{ "sphy": 2.0 }` makes a sphynx twice as likely to be this rarity.
+    #[serde(default)]
+    pub breed_modifiers: HashMap<String, f64>
+}
+
+impl RarityTier {
+    fn weight_for(&self, breed_id: Option<&str>) -> f64 {
+        let modifier = breed_id
+            .and_then(|id| self.breed_modifiers.get(id))
+            .copied()
+            .unwrap_or(1.0);
+
+        self.weight * modifier
+    }
+}
+
+/// The effective odds of a tier, as published by `GET /cats/rarities`.
+#[derive(Debug, Clone, Serialize, ToSchema)]
+#[serde(rename_all = "camelCase")]
+pub struct RarityOdds {
+    pub name: String,
+    #[serde(serialize_with = "serialize_colour")]
+    #[schema(value_type = String, example = "#4B69FF")]
+    pub colour: u32,
+    pub weight: f64,
+    pub probability: f64
+}
+
+#[derive(Debug, thiserror::Error)]
+pub enum RarityTableError {
+    #[error("The rarity table has no tiers")]
+    NoTiers,
+    #[error("Rarity `{name}` has an invalid weight or breed modifier, they have to be finite and not negative")]
+    InvalidWeight { name: String },
+    #[error("Rarity `{name}` is defined more than once")]
+    DuplicateTier { name: String },
+    #[error("All rarity weights are 0")]
+    AllWeightsZero,
+    #[error("Could not read the rarities file: {0}")]
+    FileError(#[from] std::io::Error),
+    #[error("Could not parse the rarities file: {0}")]
+    ParseError(#[from] serde_json::Error),
+    #[error("Could not read the rarities collection: {0}")]
+    DbError(#[from] mongodb::error::Error),
+}
+
+/// The rarity tiers, sorted from the most to the least common one.
+#[derive(Debug, Clone)]
+pub struct RarityTable {
+    tiers: Vec<RarityTier>
+}
+
+impl RarityTable {
+    pub fn new(mut tiers: Vec<RarityTier>) -> Result<Self, RarityTableError> {
+        if tiers.is_empty() {
+            return Err(RarityTableError::NoTiers);
+        }
+
+        for (i, tier) in tiers.iter().enumerate() {
+            let valid = |w: f64| w.is_finite() && w >= 0.0;
+            if !valid(tier.weight) || !tier.breed_modifiers.values().all(|m| valid(*m)) {
+                return Err(RarityTableError::InvalidWeight { name: tier.name.clone() });
+            }
+            if tiers[..i].iter().any(|other| other.name == tier.name) {
+                return Err(RarityTableError::DuplicateTier { name: tier.name.clone() });
+            }
+        }
+
+        if tiers.iter().all(|tier| tier.weight == 0.0) {
+            return Err(RarityTableError::AllWeightsZero);
+        }
+
+        tiers.sort_by(|a, b| b.weight.total_cmp(&a.weight));
+
+        Ok(Self { tiers })
+    }
+
+    pub fn from_file(path: &str) -> Result<Self, RarityTableError> {
+        let contents = std::fs::read_to_string(path)?;
+        let tiers = serde_json::from_str::<Vec<RarityTier>>(&contents)?;
+
+        Self::new(tiers)
+    }
+
+    /// Reads the tiers from the `rarities` collection, or from `RARITIES_FILE` if the collection is empty.
+    pub async fn load(db: &mongodb::Database) -> Result<Self, RarityTableError> {
+        let tiers: Vec<RarityTier> = db.collection::<RarityTier>("rarities")
+            .find(doc! {}).await?
+            .try_collect().await?;
+
+        if !tiers.is_empty() {
+            info!("loaded {} rarities from mongo", tiers.len());
+            return Self::new(tiers);
+        }
+
+        info!("no rarities in mongo, reading {}", RARITIES_FILE);
+        Self::from_file(RARITIES_FILE)
+    }
 
-/// Gets the rarity based on a random number.
-fn get_rarity_for_random_num(random_num: f64) -> &'static str {
-    let mut index = 0;
-    let mut factor = RARITY_FACTOR;
+    /// The odds of each tier, with the breed modifiers applied if a breed is given.
+    pub fn odds(&self, breed_id: Option<&str>) -> Vec<RarityOdds> {
+        let weights = self.weights(breed_id);
+        let total: f64 = weights.iter().sum();
 
-    while random_num < factor && index < RARITIES.len() - 1 {
-        index += 1;
-        factor *= RARITY_FACTOR;
+        self.tiers.iter()
+            .zip(weights)
+            .map(|(tier, weight)| RarityOdds {
+                name: tier.name.clone(),
+                colour: tier.colour,
+                weight,
+                probability: if total > 0.0 { weight / total } else { 0.0 }
+            })
+            .collect()
     }
 
-    RARITIES[index]
+    /// Gets the rarity for a random number from `[0, 1)`, by walking the cumulative probabilities of the tiers.
+    pub fn get_rarity_for_random_num(&self, random_num: f64, breed_id: Option<&str>) -> &RarityTier {
+        let odds = self.odds(breed_id);
+        let mut cumulative = 0.0;
+
+        for (tier, odds) in self.tiers.iter().zip(odds) {
+            cumulative += odds.probability;
+            if random_num < cumulative {
+                return tier;
+            }
+        }
+
+        // only reachable through float rounding, or if a breed's modifiers zeroed out every tier
+        &self.tiers[0]
+    }
+
+    /// Gets the colour of a rarity, unknown rarities get the colour of the most common one.
+    pub fn get_rarity_colour(&self, rarity: &str) -> u32 {
+        self.tiers.iter()
+            .find(|tier| tier.name == rarity)
+            .unwrap_or(&self.tiers[0])
+            .colour
+    }
+
+    fn weights(&self, breed_id: Option<&str>) -> Vec<f64> {
+        self.tiers.iter()
+            .map(|tier| tier.weight_for(breed_id))
+            .collect()
+    }
 }
 
-/// Generates a random rarity name based on the predefined rarities and their probabilities.
-pub fn get_random_rarity() -> &'static str {
-    let rand: f64 = rand::thread_rng().gen();
-    let rarity = get_rarity_for_random_num(rand);
+/// Generates a random rarity name for a breed based on the table. The rng is passed in so that the odds can be checked with a seeded one.
+pub fn get_random_rarity<R: Rng + ?Sized>(table: &RarityTable, breed_id: &str, rng: &mut R) -> String {
+    let rand: f64 = rng.gen();
+    let rarity = &table.get_rarity_for_random_num(rand, Some(breed_id)).name;
     info!("{} -> {}", rand, rarity);
-    rarity
-}
\ No newline at end of file
+    rarity.clone()
+}
+
+
+fn serialize_colour<S: Serializer>(colour: &u32, serializer: S) -> Result<S::Ok, S::Error> {
+    serializer.serialize_str(&format!("#{:06X}", colour))
+}
+
+fn deserialize_colour<'de, D: Deserializer<'de>>(deserializer: D) -> Result<u32, D::Error> {
+    let colour = String::deserialize(deserializer)?;
+
+    u32::from_str_radix(colour.trim_start_matches('#'), 16)
+        .map_err(|_| serde::de::Error::custom(format!("invalid colour `{}`, expected something like `#9E9E9E`", colour)))
+}
+
+#[cfg(test)]
+mod tests {
+    use rand::{rngs::StdRng, SeedableRng};
+
+    use super::*;
+
+    fn tier(name: &str, weight: f64, breed_modifiers: &[(&str, f64)]) -> RarityTier {
+        RarityTier {
+            name: name.to_string(),
+            weight,
+            colour: 0,
+            breed_modifiers: breed_modifiers.iter().map(|(id, m)| (id.to_string(), *m)).collect()
+        }
+    }
+
+    /// The same tiers as `assets/rarities.json`, the weights add up to 1000.
+    fn table() -> RarityTable {
+        RarityTable::new(vec![
+            tier("MYTHIC", 27.0, &[]),
+            tier("COMMON", 700.0, &[]),
+            tier("LEGENDARY", 63.0, &[("sphy", 1.5)]),
+            tier("RARE", 210.0, &[]),
+        ]).unwrap()
+    }
+
+    fn rarity(table: &RarityTable, random_num: f64, breed_id: Option<&str>) -> String {
+        table.get_rarity_for_random_num(random_num, breed_id).name.clone()
+    }
+
+    #[test]
+    fn tier_boundaries() {
+        let table = table();
+
+        assert_eq!(rarity(&table, 0.0, None), "COMMON");
+        assert_eq!(rarity(&table, 0.6999, None), "COMMON");
+        assert_eq!(rarity(&table, 0.7, None), "RARE");
+        assert_eq!(rarity(&table, 0.9, None), "RARE");
+        assert_eq!(rarity(&table, 0.911, None), "LEGENDARY");
+        assert_eq!(rarity(&table, 0.972, None), "LEGENDARY");
+        assert_eq!(rarity(&table, 0.9731, None), "MYTHIC");
+        assert_eq!(rarity(&table, 0.9999, None), "MYTHIC");
+    }
+
+    #[test]
+    fn breed_modifiers_shift_the_boundaries() {
+        let table = table();
+        // legendary is 94.5 for a sphynx, out of 1031.5
+        let common_end = 700.0 / 1031.5;
+        let legendary_start = 910.0 / 1031.5;
+        let legendary_end = 1004.5 / 1031.5;
+
+        assert_eq!(rarity(&table, common_end - 1e-6, Some("sphy")), "COMMON");
+        assert_eq!(rarity(&table, common_end + 1e-6, Some("sphy")), "RARE");
+        assert_eq!(rarity(&table, legendary_start + 1e-6, Some("sphy")), "LEGENDARY");
+        assert_eq!(rarity(&table, legendary_end - 1e-6, Some("sphy")), "LEGENDARY");
+        assert_eq!(rarity(&table, legendary_end + 1e-6, Some("sphy")), "MYTHIC");
+
+        // other breeds keep the plain odds
+        assert_eq!(rarity(&table, 0.69, Some("abys")), "COMMON");
+        assert_eq!(rarity(&table, 0.69, Some("sphy")), "RARE");
+
+        let odds = table.odds(Some("sphy"));
+        let legendary = odds.iter().find(|o| o.name == "LEGENDARY").unwrap();
+        assert_eq!(legendary.weight, 94.5);
+        assert!((odds.iter().map(|o| o.probability).sum::<f64>() - 1.0).abs() < 1e-9);
+    }
+
+    #[test]
+    fn a_zeroed_tier_is_never_rolled() {
+        let table = RarityTable::new(vec![
+            tier("COMMON", 1.0, &[]),
+            tier("RARE", 1.0, &[("sphy", 0.0)]),
+        ]).unwrap();
+        let mut rng = StdRng::seed_from_u64(7);
+
+        assert!((0..1000).all(|_| get_random_rarity(&table, "sphy", &mut rng) == "COMMON"));
+    }
+
+    #[test]
+    fn seeded_rolls_are_deterministic_and_follow_the_odds() {
+        let table = table();
+        let roll = |seed| {
+            let mut rng = StdRng::seed_from_u64(seed);
+            (0..20_000).map(|_| get_random_rarity(&table, "sphy", &mut rng)).collect::<Vec<_>>()
+        };
+
+        let rolls = roll(42);
+        assert_eq!(rolls, roll(42));
+
+        for odds in table.odds(Some("sphy")) {
+            let share = rolls.iter().filter(|r| **r == odds.name).count() as f64 / rolls.len() as f64;
+            assert!((share - odds.probability).abs() < 0.01, "{} rolled {} of the time, expected {}", odds.name, share, odds.probability);
+        }
+    }
+
+    #[test]
+    fn invalid_tables_are_rejected() {
+        assert!(matches!(RarityTable::new(vec![]), Err(RarityTableError::NoTiers)));
+        assert!(matches!(RarityTable::new(vec![tier("A", 0.0, &[])]), Err(RarityTableError::AllWeightsZero)));
+        assert!(matches!(RarityTable::new(vec![tier("A", -1.0, &[])]), Err(RarityTableError::InvalidWeight { .. })));
+        assert!(matches!(RarityTable::new(vec![tier("A", 1.0, &[("sphy", f64::NAN)])]), Err(RarityTableError::InvalidWeight { .. })));
+        assert!(matches!(RarityTable::new(vec![tier("A", 1.0, &[]), tier("A", 2.0, &[])]), Err(RarityTableError::DuplicateTier { .. })));
+    }
+}
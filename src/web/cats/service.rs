@@ -0,0 +1,524 @@
+use std::{collections::HashMap, time::Instant};
+use base64::{engine::general_purpose::URL_SAFE_NO_PAD, Engine};
+use mongodb::{bson::{self, doc, Bson, DateTime, Document}, error::{ErrorKind, WriteFailure}, options::ReturnDocument, Collection};
+use poise::serenity_prelude::futures::TryStreamExt;
+use serde::{Deserialize, Serialize};
+use tracing::{error, info};
+use validator::Validate;
+use super::{images, model::{BreedAggregate, BreedCatalogue, BreedStats, Cat, CatForCreate, CatForUpdate, CatHalfProcessed, CatListParams, CatSortBy, Inventory, InventoryEntry, Page, Reroll, Sort, TemperamentCount, Unbox, Wallet}, names::get_random_full_name, rarities::get_random_rarity, CatsState};
+
+// NOTE:
+// the unboxing flow lives here instead of in the controller, so that both the axum handlers and the discord bot can use it
+
+
+pub async fn get_cat(state: &CatsState, id: &str) -> Result<Cat, super::Error> {
+    state.cats
+        .find_one(doc! { "_id": id }).await?
+        .ok_or(super::Error::NotFound { id: id.to_string() })
+}
+
+/// What mongo answers with when inserting an `_id` that already exists.
+const DUPLICATE_KEY_CODE: i32 = 11000;
+
+/// Coins credited to a user for every unbox.
+pub const UNBOX_REWARD: i64 = 10;
+/// Coins a rarity re-roll costs.
+pub const REROLL_COST: i64 = 50;
+
+pub const DEFAULT_PAGE_SIZE: i64 = 20;
+pub const MAX_PAGE_SIZE: i64 = 100;
+
+/// A page of cats, filtered and sorted by the params.
+/// 
+/// Paging is keyset based, the cursor holds the sort field's value and the `_id` of the last cat on the previous page,
+/// with `_id` breaking ties between cats with the same value. It only works with the ordering it was made for.
+pub async fn list_cats(state: &CatsState, params: &CatListParams) -> Result<Page<Cat>, super::Error> {
+    let limit = params.limit.unwrap_or(DEFAULT_PAGE_SIZE).clamp(1, MAX_PAGE_SIZE);
+    let field = params.sort_by.field();
+    let direction = params.sort.direction();
+
+    let filter = params.filter();
+    let total = state.cats.count_documents(filter.clone()).await?;
+
+    let filter = match &params.cursor {
+        Some(cursor) => {
+            let (value, id) = decode_cursor(cursor, params.sort_by, params.sort)?;
+            let op = match params.sort {
+                Sort::Desc => "$lt",
+                Sort::Asc => "$gt",
+            };
+
+            doc! { "$and": [
+                filter,
+                { "$or": [
+                    { field: { op: value.clone() } },
+                    { field: value, "_id": { op: id } }
+                ] }
+            ] }
+        },
+        None => filter
+    };
+
+    // 1 extra to know if there's a next page
+    let mut items: Vec<Cat> = state.cats
+        .find(filter)
+        .sort(doc! { field: direction, "_id": direction })
+        .limit(limit + 1).await?
+        .try_collect().await?;
+
+    let next_cursor = if items.len() as i64 > limit {
+        items.truncate(limit as usize);
+        items.last()
+            .map(|last| encode_cursor(last, params.sort_by, params.sort))
+            .transpose()?
+    } else {
+        None
+    };
+
+    Ok(Page { items, total, next_cursor })
+}
+
+/// Every breed that has been discovered so far, most discovered first.
+pub async fn get_breed_catalogue(state: &CatsState) -> Result<BreedCatalogue, super::Error> {
+    let breeds = aggregate_breeds(state, doc! {}).await?;
+
+    Ok(BreedCatalogue {
+        breeds_discovered: breeds.len(),
+        cats_discovered: breeds.iter().map(|breed| breed.discovered).sum(),
+        breeds
+    })
+}
+
+pub async fn get_breed(state: &CatsState, breed_id: &str) -> Result<BreedStats, super::Error> {
+    aggregate_breeds(state, doc! { "breed.id": breed_id }).await?
+        .into_iter()
+        .next()
+        .ok_or(super::Error::BreedNotFound { id: breed_id.to_string() })
+}
+
+async fn aggregate_breeds(state: &CatsState, filter: Document) -> Result<Vec<BreedStats>, super::Error> {
+    // grouped by breed and rarity first, so the rarities are counted in mongo and the groups stay small however many cats there are
+    let pipeline = vec![
+        doc! { "$match": filter.clone() },
+        doc! { "$group": {
+            "_id": { "breed": "$breed.id", "rarity": "$rarity" },
+            "name": { "$first": "$breed.name" },
+            "origins": { "$addToSet": "$breed.origin" },
+            "countryCodes": { "$addToSet": "$breed.country_code" },
+            "count": { "$sum": 1 },
+            "firstDiscoveredAt": { "$min": "$createdAt" }
+        } },
+        doc! { "$group": {
+            "_id": "$_id.breed",
+            "name": { "$first": "$name" },
+            "origins": { "$push": "$origins" },
+            "countryCodes": { "$push": "$countryCodes" },
+            "discovered": { "$sum": "$count" },
+            "rarities": { "$push": { "rarity": "$_id.rarity", "count": "$count" } },
+            "firstDiscoveredAt": { "$min": "$firstDiscoveredAt" }
+        } },
+        doc! { "$set": {
+            "origins": { "$reduce": { "input": "$origins", "initialValue": [], "in": { "$setUnion": ["$$value", "$$this"] } } },
+            "countryCodes": { "$reduce": { "input": "$countryCodes", "initialValue": [], "in": { "$setUnion": ["$$value", "$$this"] } } }
+        } },
+        doc! { "$sort": { "discovered": -1, "_id": 1 } },
+    ];
+
+    let (breeds, mut temperaments) = tokio::try_join!(
+        async { Ok::<_, super::Error>(state.cats.aggregate(pipeline).await?.try_collect::<Vec<_>>().await?) },
+        count_temperaments(state, filter)
+    )?;
+
+    let rarities = state.rarities.odds(None).into_iter()
+        .map(|odds| odds.name)
+        .collect::<Vec<_>>();
+
+    let breeds = breeds.into_iter()
+        .map(|breed| {
+            let mut breed = bson::from_document::<BreedAggregate>(breed)?;
+            breed.temperaments = temperaments.remove(&breed.id).unwrap_or_default();
+            Ok(BreedStats::from_aggregate(breed, &rarities))
+        })
+        .collect::<Result<Vec<_>, bson::de::Error>>()?;
+
+    Ok(breeds)
+}
+
+/// How many of the cats of each breed have each temperament, by breed id.
+async fn count_temperaments(state: &CatsState, filter: Document) -> Result<HashMap<String, Vec<TemperamentCount>>, super::Error> {
+    #[derive(Deserialize)]
+    struct BreedTemperaments {
+        #[serde(rename = "_id")]
+        breed: String,
+        temperaments: Vec<TemperamentCount>
+    }
+
+    let pipeline = vec![
+        doc! { "$match": filter },
+        doc! { "$unwind": "$breed.temperament" },
+        doc! { "$group": {
+            "_id": { "breed": "$breed.id", "temperament": "$breed.temperament" },
+            "count": { "$sum": 1 }
+        } },
+        doc! { "$group": {
+            "_id": "$_id.breed",
+            "temperaments": { "$push": { "temperament": "$_id.temperament", "count": "$count" } }
+        } },
+    ];
+
+    let counts = state.cats
+        .aggregate(pipeline).await?
+        .try_collect::<Vec<_>>().await?
+        .into_iter()
+        .map(|breed| bson::from_document::<BreedTemperaments>(breed).map(|breed| (breed.breed, breed.temperaments)))
+        .collect::<Result<HashMap<_, _>, _>>()?;
+
+    Ok(counts)
+}
+
+/// What a `list_cats` cursor holds. The ordering is in it too, the value of another ordering (maybe of another type)
+/// doesn't point anywhere in this one.
+#[derive(Debug, Serialize, Deserialize)]
+struct CatCursor {
+    sort_by: CatSortBy,
+    sort: Sort,
+    value: serde_json::Value,
+    id: String
+}
+
+fn encode_cursor(cat: &Cat, sort_by: CatSortBy, sort: Sort) -> Result<String, super::Error> {
+    let document = bson::to_document(cat)?;
+    let value = get_path(&document, sort_by.field()).cloned().unwrap_or(Bson::Null);
+
+    let json = serde_json::to_vec(&CatCursor { sort_by, sort, value: value.into_relaxed_extjson(), id: cat._id.clone() })?;
+    Ok(URL_SAFE_NO_PAD.encode(json))
+}
+
+fn decode_cursor(cursor: &str, sort_by: CatSortBy, sort: Sort) -> Result<(Bson, String), super::Error> {
+    let cursor = URL_SAFE_NO_PAD.decode(cursor).ok()
+        .and_then(|json| serde_json::from_slice::<CatCursor>(&json).ok())
+        .filter(|cursor| cursor.sort_by == sort_by && cursor.sort == sort)
+        .ok_or(super::Error::InvalidCursor)?;
+    let value = Bson::try_from(cursor.value)
+        .map_err(|_| super::Error::InvalidCursor)?;
+
+    Ok((value, cursor.id))
+}
+
+/// Gets a value by a dotted path like `breed.name`.
+fn get_path<'a>(document: &'a Document, path: &str) -> Option<&'a Bson> {
+    let mut parts = path.split('.');
+    let mut value = document.get(parts.next()?)?;
+
+    for part in parts {
+        value = value.as_document()?.get(part)?;
+    }
+
+    Some(value)
+}
+
+/// Unboxes a random cat and, if there's a user doing the unboxing, records it in their inventory.
+/// 
+/// User ids are either a JWT subject or a discord user id prefixed with `discord:`.
+pub async fn unbox_random_cat(state: &CatsState, user_id: Option<&str>) -> Result<CatForCreate, super::Error> {
+    let cat = find_or_create_random_cat(state).await?;
+
+    if let Some(user_id) = user_id {
+        let unbox = Unbox::new(user_id, &cat._id);
+        state.unboxes.insert_one(&unbox).await?;
+        info!("recorded unbox of {} for {}", cat._id, user_id);
+
+        state.wallets
+            .update_one(
+                doc! { "_id": user_id },
+                doc! { "$inc": { "balance": UNBOX_REWARD }, "$set": { "updatedAt": DateTime::now() } }
+            )
+            .upsert(true).await?;
+    }
+
+    Ok(cat)
+}
+
+/// All the cats a user has unboxed, grouped by cat, oldest first.
+pub async fn get_inventory(state: &CatsState, user_id: &str) -> Result<Inventory, super::Error> {
+    let pipeline = vec![
+        doc! { "$match": { "userId": user_id } },
+        doc! { "$group": {
+            "_id": "$catId",
+            "count": { "$sum": 1 },
+            "favourite": { "$max": { "$ifNull": ["$favourite", false] } },
+            "firstUnboxedAt": { "$min": "$unboxedAt" },
+            "unboxIds": { "$push": "$_id" },
+            // the user's own names and rarity, set on all of their unboxes of the cat or missing
+            "petName": { "$max": "$petName" },
+            "fullName": { "$max": "$fullName" },
+            "rarity": { "$max": "$rarity" }
+        } },
+        doc! { "$lookup": {
+            "from": state.cats.name(),
+            "localField": "_id",
+            "foreignField": "_id",
+            "as": "cat"
+        } },
+        // an unbox always references an existing cat, but just in case one got deleted
+        doc! { "$unwind": "$cat" },
+        doc! { "$set": {
+            "cat.petName": { "$ifNull": ["$petName", "$cat.petName"] },
+            "cat.fullName": { "$ifNull": ["$fullName", "$cat.fullName"] },
+            "cat.rarity": { "$ifNull": ["$rarity", "$cat.rarity"] }
+        } },
+        doc! { "$sort": { "firstUnboxedAt": 1 } },
+    ];
+
+    let entries = state.unboxes
+        .aggregate(pipeline).await?
+        .try_collect::<Vec<_>>().await?
+        .into_iter()
+        .map(bson::from_document::<InventoryEntry>)
+        .collect::<Result<Vec<_>, _>>()?;
+
+    Ok(Inventory::new(user_id, entries))
+}
+
+pub async fn get_wallet(state: &CatsState, user_id: &str) -> Result<Wallet, super::Error> {
+    let wallet = state.wallets.find_one(doc! { "_id": user_id }).await?;
+
+    Ok(wallet.unwrap_or(Wallet::empty(user_id)))
+}
+
+/// Renames and/or (un)favourites a cat, only for users who own it. The rename only applies to the user's copies.
+pub async fn update_cat(state: &CatsState, id: &str, user_id: &str, update: &CatForUpdate) -> Result<Cat, super::Error> {
+    update.validate()?;
+    if update.pet_name.is_none() && update.favourite.is_none() {
+        return Err(super::Error::NothingToUpdate);
+    }
+
+    let mut cat = get_owned_cat(state, id, user_id).await?;
+    let previous_name = cat.full_name.clone();
+
+    let mut set = doc! {};
+    if let Some(favourite) = update.favourite {
+        set.insert("favourite", favourite);
+    }
+    if let Some(pet_name) = &update.pet_name {
+        cat.full_name = get_random_full_name(&cat.breed, pet_name);
+        cat.pet_name = pet_name.clone();
+        set.insert("petName", &cat.pet_name);
+        set.insert("fullName", &cat.full_name);
+    }
+
+    let res = state.unboxes
+        .update_many(doc! { "catId": &cat._id, "userId": user_id }, doc! { "$set": set })
+        .await?;
+    // traded away in the meantime
+    if res.matched_count == 0 {
+        return Err(super::Error::NotCatOwner { id: cat._id });
+    }
+
+    if update.pet_name.is_some() {
+        info!("{} renamed {} to {}", user_id, previous_name, cat.full_name);
+    }
+
+    Ok(cat)
+}
+
+/// Spends `REROLL_COST` coins to roll a new rarity for a cat the user owns, only for the user's copies.
+/// The payment and the new rarity are written in a single transaction, so coins can't be lost on a failed re-roll.
+pub async fn reroll_cat(state: &CatsState, id: &str, user_id: &str) -> Result<Reroll, super::Error> {
+    let cat = get_owned_cat(state, id, user_id).await?;
+
+    let rarity = get_random_rarity(&state.rarities, &cat.breed.id, &mut rand::thread_rng());
+
+    let mut session = state.mongo.start_session().await?;
+    session.start_transaction().await?;
+
+    let res = async {
+        let wallet = state.wallets
+            .find_one_and_update(
+                doc! { "_id": user_id, "balance": { "$gte": REROLL_COST } },
+                doc! { "$inc": { "balance": -REROLL_COST }, "$set": { "updatedAt": DateTime::now() } }
+            )
+            .return_document(ReturnDocument::After)
+            .session(&mut session).await?;
+
+        let Some(wallet) = wallet else {
+            // read in the session too, so the balance is the one the debit saw
+            let balance = state.wallets
+                .find_one(doc! { "_id": user_id })
+                .session(&mut session).await?
+                .unwrap_or(Wallet::empty(user_id))
+                .balance;
+            return Err(super::Error::InsufficientFunds { balance, cost: REROLL_COST });
+        };
+
+        let res = state.unboxes
+            .update_many(doc! { "catId": &cat._id, "userId": user_id }, doc! { "$set": { "rarity": &rarity } })
+            .session(&mut session).await?;
+        if res.matched_count == 0 {
+            return Err(super::Error::NotCatOwner { id: cat._id.clone() });
+        }
+
+        Ok(wallet)
+    }.await;
+
+    match res {
+        Ok(wallet) => {
+            session.commit_transaction().await?;
+            info!("{} re-rolled {} from {} to {}", user_id, cat._id, cat.rarity, rarity);
+
+            let previous_rarity = cat.rarity.clone();
+            Ok(Reroll { cat: Cat { rarity, ..cat }, previous_rarity, cost: REROLL_COST, balance: wallet.balance })
+        },
+        Err(e) => {
+            if let Err(abort_err) = session.abort_transaction().await {
+                error!("->> could not abort re-roll transaction: {}", abort_err);
+            }
+            Err(e)
+        }
+    }
+}
+
+/// The cat with the user's names and rarity on it, if the user has unboxed it.
+async fn get_owned_cat(state: &CatsState, id: &str, user_id: &str) -> Result<Cat, super::Error> {
+    let cat = get_cat(state, id).await?;
+
+    let unboxes = state.unboxes
+        .find(doc! { "catId": &cat._id, "userId": user_id }).await?
+        .try_collect::<Vec<_>>().await?;
+    if unboxes.is_empty() {
+        return Err(super::Error::NotCatOwner { id: cat._id });
+    }
+
+    // unboxes that came in after a rename (new ones, or traded in) have nothing set, the set ones are all the same
+    Ok(unboxes.iter().fold(cat, |cat, unbox| unbox.apply_to(cat)))
+}
+
+/// If the random cat was discovered before the stored one is returned, otherwise a new one is named and inserted.
+async fn find_or_create_random_cat(state: &CatsState) -> Result<CatForCreate, super::Error> {
+    if let Some(prefetched) = state.pool.pop() {
+        info!("serving a prefetched cat, {} left in the pool", state.pool.len());
+        return insert_or_get_existing(state, prefetched.cat, prefetched.pet_name).await;
+    }
+    info!("cat pool is empty, unboxing live");
+
+    let start_time = Instant::now();
+    let cat = state.source.random_cat().await?;
+    info!("fetching a random cat: {:?}", start_time.elapsed());
+    let cat_wip = cat.start_processing()?;
+
+    // looking it up in db and constructing a pet_name don't depend on each other, so they're done at the same time
+    let start_time = Instant::now();
+    let (found, pet_name) = tokio::join!(
+        async { state.cats.find_one(doc! { "_id": &cat.id }).await },
+        state.names.get_random_name_from_country(&cat_wip.breed.country_code, &state.client)
+    );
+    info!("checking in mongo and constructing pet_name: {:?}", start_time.elapsed());
+
+    if let Some(found) = found? {
+        info!("Cat that was previously discovered! {:?}\n{} - {}", found, found.full_name, found.rarity);
+        return Ok(found.into())  // here cat turns into a cat_for_create, because of how this lib's sillyness
+    }
+
+    insert_or_get_existing(state, cat_wip, pet_name).await
+}
+
+/// Rolls the rarity and inserts the new cat. If it was discovered in the meantime (or, for prefetched cats, before)
+/// the insert hits the duplicate `_id` and the existing cat is returned instead.
+async fn insert_or_get_existing(state: &CatsState, cat_wip: CatHalfProcessed, pet_name: String) -> Result<CatForCreate, super::Error> {
+    let rarity = get_random_rarity(&state.rarities, &cat_wip.breed.id, &mut rand::thread_rng());
+    let full_name = get_random_full_name(&cat_wip.breed, &pet_name);
+
+    let cats_for_create: Collection<CatForCreate> = state.cats.clone_with_type();
+
+    // NOTE:
+    // becuase the mongodb crate is stupid theres no createdAt field, so i just have to generate a time here, which will be somewhat inaccurate, but whatever
+    let new_cat = cat_wip.finalize_processing(rarity, pet_name, full_name);
+
+    // for some reason this lib only returns the inserted_id
+    let start_time = Instant::now();
+    match cats_for_create.insert_one(&new_cat).await {
+        Ok(_) => {},
+        Err(e) if is_duplicate_key(&e) => {
+            info!("Cat that was previously discovered! {}", new_cat._id);
+            return Ok(get_cat(state, &new_cat._id).await?.into());
+        },
+        Err(e) => return Err(e.into())
+    }
+    info!("inserting into mongo: {:?}", start_time.elapsed());
+    // so i might just return the cat_for_create
+
+    images::spawn_mirror(state.clone(), new_cat._id.clone(), new_cat.img_url.clone());
+
+    Ok(new_cat)
+}
+
+fn is_duplicate_key(e: &mongodb::error::Error) -> bool {
+    matches!(
+        e.kind.as_ref(),
+        ErrorKind::Write(WriteFailure::WriteError(write_error)) if write_error.code == DUPLICATE_KEY_CODE
+    )
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use super::super::{model::Breed, Error};
+
+    fn cat() -> Cat {
+        Cat {
+            _id: "0XYvRd7oD".to_string(),
+            img_url: "https://cdn2.thecatapi.com/images/0XYvRd7oD.jpg".to_string(),
+            breed: Breed {
+                id: "abys".to_string(),
+                name: "Abyssinian".to_string(),
+                temperament: vec!["Active".to_string()],
+                alt_names: vec![],
+                origin: "Egypt".to_string(),
+                country_code: "EG".to_string(),
+                description: String::new(),
+                wikipedia_url: String::new()
+            },
+            rarity: "Rare".to_string(),
+            pet_name: "Omar".to_string(),
+            full_name: "Omar, the Active Abyssinian".to_string(),
+            images: None,
+            created_at: DateTime::from_millis(1_700_000_000_000),
+            updated_at: DateTime::from_millis(1_700_000_500_000)
+        }
+    }
+
+    #[test]
+    fn cursors_round_trip() {
+        let cat = cat();
+
+        for (sort_by, expected) in [
+            (CatSortBy::CreatedAt, Bson::DateTime(cat.created_at)),
+            (CatSortBy::UpdatedAt, Bson::DateTime(cat.updated_at)),
+            (CatSortBy::FullName, Bson::String(cat.full_name.clone())),
+            (CatSortBy::Rarity, Bson::String(cat.rarity.clone())),
+            (CatSortBy::Breed, Bson::String(cat.breed.name.clone())),
+        ] {
+            let cursor = encode_cursor(&cat, sort_by, Sort::Asc).unwrap();
+            assert_eq!(decode_cursor(&cursor, sort_by, Sort::Asc).unwrap(), (expected, cat._id.clone()), "{:?}", sort_by);
+        }
+    }
+
+    #[test]
+    fn cursors_of_another_order_are_rejected() {
+        let cursor = encode_cursor(&cat(), CatSortBy::CreatedAt, Sort::Desc).unwrap();
+
+        assert!(matches!(decode_cursor(&cursor, CatSortBy::FullName, Sort::Desc), Err(Error::InvalidCursor)));
+        assert!(matches!(decode_cursor(&cursor, CatSortBy::CreatedAt, Sort::Asc), Err(Error::InvalidCursor)));
+    }
+
+    #[test]
+    fn garbage_cursors_are_rejected() {
+        // a cursor from before the ordering was part of it
+        let without_order = URL_SAFE_NO_PAD.encode(r#"[{"$date":"2023-11-14T22:13:20Z"},"0XYvRd7oD"]"#);
+
+        for garbage in ["", "not base64!", &URL_SAFE_NO_PAD.encode("not json"), &without_order] {
+            assert!(matches!(decode_cursor(garbage, CatSortBy::CreatedAt, Sort::Desc), Err(Error::InvalidCursor)), "{}", garbage);
+        }
+    }
+}
@@ -0,0 +1,276 @@
+use std::{path::{Path, PathBuf}, sync::Arc};
+use async_trait::async_trait;
+use tracing::info;
+use crate::{helpers::random_choice, web::ClientWithKeys};
+use super::model::CatUnprocessed;
+
+/// The biggest image a cat source may send, TheCatAPI's are a few hundred KiB at most.
+pub const MAX_IMAGE_BYTES: usize = 10 * 1024 * 1024;
+
+/// Where the fixture images are served from, relative to the `/cats` routes.
+pub const FIXTURE_IMAGES_ROUTE: &str = "/fixtures/images";
+
+/// Somewhere random cats come from, in the shape TheCatAPI returns them.
+#[async_trait]
+pub trait CatSource: Send + Sync + std::fmt::Debug {
+    async fn random_cat(&self) -> Result<CatUnprocessed, super::Error>;
+
+    /// Downloads the image behind the `url` of one of this source's cats.
+    async fn fetch_image(&self, url: &str) -> Result<Vec<u8>, super::Error>;
+
+    /// A local directory of images that has to be served under `FIXTURE_IMAGES_ROUTE`, if the source needs one.
+    fn image_dir(&self) -> Option<&Path> {
+        None
+    }
+}
+
+#[derive(Debug, thiserror::Error)]
+pub enum SourceError {
+    #[error("Unknown cat source `{got}`, expected `thecatapi` or `fixture`")]
+    UnknownSource { got: String },
+    #[error("Could not read the cat fixtures: {0}")]
+    FileError(#[from] std::io::Error),
+    #[error("Could not parse the cat fixtures catalogue: {0}")]
+    ParseError(#[from] serde_json::Error),
+    #[error("The cat fixtures catalogue has no cats with breeds")]
+    EmptyCatalogue,
+}
+
+/// Picks the source by name, `thecatapi` or `fixture`.
+///
+/// `public_base_url` is where this server can be reached from outside, the fixture image urls end up in discord embeds so they have to be absolute.
+pub async fn cat_source_from_config(source: &str, fixtures_dir: &str, public_base_url: &str, client: ClientWithKeys) -> Result<Arc<dyn CatSource>, SourceError> {
+    match source {
+        "thecatapi" => Ok(Arc::new(TheCatApiSource::new(client))),
+        "fixture" => Ok(Arc::new(FixtureCatSource::from_dir(fixtures_dir, public_base_url).await?)),
+        other => Err(SourceError::UnknownSource { got: other.to_string() })
+    }
+}
+
+
+#[derive(Debug, Clone)]
+pub struct TheCatApiSource {
+    client: ClientWithKeys
+}
+
+impl TheCatApiSource {
+    pub fn new(client: ClientWithKeys) -> Self {
+        Self { client }
+    }
+}
+
+#[async_trait]
+impl CatSource for TheCatApiSource {
+    async fn random_cat(&self) -> Result<CatUnprocessed, super::Error> {
+        let response = self.client.client
+            .get(format!("https://api.thecatapi.com/v1/images/search?api_key={}&has_breeds=1", self.client.cat_api_key.clone()))
+            .send()
+            .await?;
+
+        let response_text = response.text().await?;
+        info!("Response text: {}", response_text);
+
+        let cat: Vec<CatUnprocessed> = serde_json::from_str(&response_text)?;
+        cat.into_iter()
+            .next()  // because this req returns a vec, not a single cat, its always 1 cat anyway, or should be
+            .ok_or(super::Error::NoCatsFromRandomCatApi)
+    }
+
+    async fn fetch_image(&self, url: &str) -> Result<Vec<u8>, super::Error> {
+        let response = self.client.client
+            .get(url)
+            .send()
+            .await?
+            .error_for_status()?;
+
+        read_capped(response, url, MAX_IMAGE_BYTES).await
+    }
+}
+
+/// Reads the body chunk by chunk and gives up as soon as it's over `limit`,
+/// the content length is only a hint (and can be missing), so it's checked up front and while streaming.
+async fn read_capped(mut response: reqwest::Response, url: &str, limit: usize) -> Result<Vec<u8>, super::Error> {
+    let too_large = || super::Error::ImageTooLarge { url: url.to_string(), limit };
+
+    if response.content_length().is_some_and(|len| len > limit as u64) {
+        return Err(too_large());
+    }
+
+    let mut bytes = Vec::new();
+    while let Some(chunk) = response.chunk().await? {
+        if bytes.len() + chunk.len() > limit {
+            return Err(too_large());
+        }
+        bytes.extend_from_slice(&chunk);
+    }
+
+    Ok(bytes)
+}
+
+
+/// Serves cats from a local catalogue, so that the `/cats` flow works without an api key or network.
+///
+/// The directory has a `catalogue.json` in the same format as TheCatAPI's `/v1/images/search` response,
+/// except that each `url` is the name of a file in the `images` subdirectory. They're turned into absolute urls of `FIXTURE_IMAGES_ROUTE`.
+#[derive(Debug, Clone)]
+pub struct FixtureCatSource {
+    cats: Vec<CatUnprocessed>,
+    image_dir: PathBuf
+}
+
+impl FixtureCatSource {
+    pub async fn from_dir(dir: &str, public_base_url: &str) -> Result<Self, SourceError> {
+        let dir = Path::new(dir);
+        let public_base_url = public_base_url.trim_end_matches('/');
+        let contents = tokio::fs::read_to_string(dir.join("catalogue.json")).await?;
+
+        let cats = serde_json::from_str::<Vec<CatUnprocessed>>(&contents)?
+            .into_iter()
+            .filter(|cat| !cat.breeds.is_empty())
+            .map(|cat| CatUnprocessed {
+                url: format!("{}/cats{}/{}", public_base_url, FIXTURE_IMAGES_ROUTE, cat.url),
+                ..cat
+            })
+            .collect::<Vec<_>>();
+
+        if cats.is_empty() {
+            return Err(SourceError::EmptyCatalogue);
+        }
+
+        info!("loaded {} fixture cats from {:?}", cats.len(), dir);
+
+        Ok(Self { cats, image_dir: dir.join("images") })
+    }
+}
+
+#[async_trait]
+impl CatSource for FixtureCatSource {
+    async fn random_cat(&self) -> Result<CatUnprocessed, super::Error> {
+        random_choice(&self.cats)
+            .cloned()
+            .ok_or(super::Error::NoCatsFromRandomCatApi)
+    }
+
+    async fn fetch_image(&self, url: &str) -> Result<Vec<u8>, super::Error> {
+        // the urls are `<public base url>/cats/fixtures/images/<file>`, only the file name matters
+        let file_name = url.rsplit('/').next().unwrap_or_default();
+
+        tokio::fs::read(self.image_dir.join(file_name)).await
+            .map_err(|_| super::Error::ImageNotAvailable { url: url.to_string() })
+    }
+
+    fn image_dir(&self) -> Option<&Path> {
+        Some(&self.image_dir)
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const BUNDLED_FIXTURES: &str = "assets/cats";
+
+    /// A fixtures directory with just a catalogue, removed again by the caller.
+    async fn fixtures_with(name: &str, catalogue: &str) -> PathBuf {
+        let dir = std::env::temp_dir().join(format!("service-nexus-fixtures-{}-{}", std::process::id(), name));
+        tokio::fs::create_dir_all(dir.join("images")).await.unwrap();
+        tokio::fs::write(dir.join("catalogue.json"), catalogue).await.unwrap();
+        dir
+    }
+
+    #[tokio::test]
+    async fn the_bundled_catalogue_loads() {
+        let source = FixtureCatSource::from_dir(BUNDLED_FIXTURES, "http://localhost:8000").await.unwrap();
+
+        assert!(!source.cats.is_empty());
+        assert!(source.cats.iter().all(|cat| !cat.breeds.is_empty()));
+        assert_eq!(source.image_dir(), Some(Path::new("assets/cats/images")));
+    }
+
+    #[tokio::test]
+    async fn image_urls_are_made_absolute() {
+        let source = FixtureCatSource::from_dir(BUNDLED_FIXTURES, "https://cats.example.com/").await.unwrap();
+        let cat = source.cats.iter().find(|cat| cat.id == "fx-abys-1").unwrap();
+
+        assert_eq!(cat.url, "https://cats.example.com/cats/fixtures/images/fx-abys-1.png");
+    }
+
+    #[tokio::test]
+    async fn images_are_read_by_the_file_name_of_their_url() {
+        let source = FixtureCatSource::from_dir(BUNDLED_FIXTURES, "http://localhost:8000").await.unwrap();
+        let cat = source.random_cat().await.unwrap();
+
+        let bytes = source.fetch_image(&cat.url).await.unwrap();
+        assert_eq!(bytes, tokio::fs::read(source.image_dir.join(cat.url.rsplit('/').next().unwrap())).await.unwrap());
+    }
+
+    #[tokio::test]
+    async fn a_missing_image_file_is_not_available() {
+        let source = FixtureCatSource::from_dir(BUNDLED_FIXTURES, "http://localhost:8000").await.unwrap();
+
+        let res = source.fetch_image("http://localhost:8000/cats/fixtures/images/not-a-cat.png").await;
+        assert!(matches!(res, Err(super::super::Error::ImageNotAvailable { url }) if url.ends_with("not-a-cat.png")));
+    }
+
+    #[tokio::test]
+    async fn a_catalogue_without_cats_with_breeds_is_empty() {
+        for (name, catalogue) in [("empty", "[]"), ("no-breeds", r#"[{ "id": "x", "url": "x.png", "breeds": [] }]"#)] {
+            let dir = fixtures_with(name, catalogue).await;
+
+            let res = FixtureCatSource::from_dir(dir.to_str().unwrap(), "http://localhost:8000").await;
+            tokio::fs::remove_dir_all(&dir).await.unwrap();
+
+            assert!(matches!(res, Err(SourceError::EmptyCatalogue)), "{}: {:?}", name, res);
+        }
+    }
+
+    #[tokio::test]
+    async fn a_missing_or_broken_catalogue_is_an_error() {
+        let res = FixtureCatSource::from_dir("assets/not-cats", "http://localhost:8000").await;
+        assert!(matches!(res, Err(SourceError::FileError(_))), "{:?}", res);
+
+        let dir = fixtures_with("broken", "{ not json").await;
+        let res = FixtureCatSource::from_dir(dir.to_str().unwrap(), "http://localhost:8000").await;
+        tokio::fs::remove_dir_all(&dir).await.unwrap();
+        assert!(matches!(res, Err(SourceError::ParseError(_))), "{:?}", res);
+    }
+
+    /// Serves `body` on a random local port and returns its url.
+    async fn serve(body: fn() -> axum::body::Body) -> String {
+        let app = axum::Router::new().route("/cat.png", axum::routing::get(move || async move { body() }));
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let url = format!("http://{}/cat.png", listener.local_addr().unwrap());
+        tokio::spawn(async move { axum::serve(listener, app).await.unwrap() });
+        url
+    }
+
+    async fn read_image(url: &str, limit: usize) -> Result<Vec<u8>, super::super::Error> {
+        let response = reqwest::get(url).await.unwrap();
+        read_capped(response, url, limit).await
+    }
+
+    /// A body without a content length, so only the streaming check can catch it.
+    fn chunked() -> axum::body::Body {
+        let chunks = (0..4).map(|_| Ok::<_, std::io::Error>(vec![0u8; 256]));
+        axum::body::Body::from_stream(poise::serenity_prelude::futures::stream::iter(chunks))
+    }
+
+    #[tokio::test]
+    async fn images_within_the_limit_are_read_whole() {
+        let url = serve(|| vec![7u8; 1024].into()).await;
+        assert_eq!(read_image(&url, 1024).await.unwrap(), vec![7u8; 1024]);
+
+        let url = serve(chunked).await;
+        assert_eq!(read_image(&url, 1024).await.unwrap().len(), 1024);
+    }
+
+    #[tokio::test]
+    async fn images_over_the_limit_are_rejected() {
+        let url = serve(|| vec![7u8; 1025].into()).await;
+        assert!(matches!(read_image(&url, 1024).await, Err(super::super::Error::ImageTooLarge { limit: 1024, .. })));
+
+        let url = serve(chunked).await;
+        assert!(matches!(read_image(&url, 1000).await, Err(super::super::Error::ImageTooLarge { limit: 1000, .. })));
+    }
+}
@@ -0,0 +1,497 @@
+use axum::{extract::State, middleware::from_fn_with_state, response::IntoResponse, routing::{get, post}, Router};
+use crate::web::extract::{Json, Path, Query};
+use mongodb::{bson::{doc, oid::ObjectId, DateTime, Document}, options::ReturnDocument, ClientSession};
+use poise::serenity_prelude::futures::TryStreamExt;
+use serde::{Deserialize, Serialize};
+use tracing::{error, info};
+use utoipa::{IntoParams, OpenApi, ToSchema};
+use validator::Validate;
+
+use crate::web::auth::{self, AuthLayer, AuthUser};
+use super::CatsState;
+
+/// Who the expiry sweeper shows up as in the trade history.
+const SYSTEM_USER: &str = "system";
+const DEFAULT_EXPIRY_HOURS: i64 = 24;
+const EXPIRY_SWEEP_INTERVAL_SECS: u64 = 60;
+
+
+/// Everything here needs a logged in user, the user ids are the same ones the unbox inventories use.
+pub fn routes(auth: AuthLayer) -> Router<CatsState> {
+    Router::new()
+        .route("/", get(get_my_trades).post(create_trade))
+        .route("/:id", get(get_trade))
+        .route("/:id/accept", post(accept_trade))
+        .route("/:id/decline", post(decline_trade))
+        .route("/:id/cancel", post(cancel_trade))
+        .route("/:id/history", get(get_trade_history))
+        .layer(from_fn_with_state(auth, auth::auth_mw))
+}
+
+#[derive(OpenApi)]
+#[openapi(
+    paths(get_my_trades, create_trade, get_trade, get_trade_history, accept_trade, decline_trade, cancel_trade),
+    components(schemas(Trade, TradeEvent, TradeStatus, TradeForCreate)),
+    tags((name = "trades", description = "Trading unboxed cats between users"))
+)]
+pub struct TradesApi;
+
+/// Marks pending trades past their `expiresAt` as expired, every minute.
+pub fn spawn_expiry_sweeper(state: CatsState) {
+    tokio::spawn(async move {
+        loop {
+            match expire_trades(&state).await {
+                Ok(0) => {},
+                Ok(expired) => info!("expired {} trades", expired),
+                Err(e) => error!("->> trade expiry sweep failed: {}", e)
+            }
+            tokio::time::sleep(tokio::time::Duration::from_secs(EXPIRY_SWEEP_INTERVAL_SECS)).await;
+        }
+    });
+}
+
+
+#[derive(Debug, Clone, Copy, Serialize, Deserialize, PartialEq, Eq, strum_macros::Display, ToSchema)]
+#[serde(rename_all = "lowercase")]
+#[strum(serialize_all = "lowercase")]
+pub enum TradeStatus {
+    Pending,
+    Accepted,
+    Declined,
+    Cancelled,
+    Expired
+}
+
+/// An offer of some of `from_user`'s unboxes for some of `to_user`'s. The ids are `_id`s of documents in `unboxes`.
+#[derive(Debug, Clone, Serialize, Deserialize, ToSchema)]
+#[serde(rename_all = "camelCase")]
+pub struct Trade {
+    #[serde(rename = "_id")]
+    #[schema(value_type = Object)]
+    pub _id: ObjectId,
+    pub from_user: String,
+    pub to_user: String,
+    #[schema(value_type = Vec<Object>)]
+    pub offered: Vec<ObjectId>,
+    #[schema(value_type = Vec<Object>)]
+    pub requested: Vec<ObjectId>,
+    pub status: TradeStatus,
+    #[schema(value_type = Object)]
+    pub created_at: DateTime,
+    #[schema(value_type = Object)]
+    pub updated_at: DateTime,
+    #[schema(value_type = Object)]
+    pub expires_at: DateTime
+}
+
+/// The audit log of trades, one for each status a trade went through (`pending` being its creation).
+#[derive(Debug, Clone, Serialize, Deserialize, ToSchema)]
+#[serde(rename_all = "camelCase")]
+pub struct TradeEvent {
+    #[serde(rename = "_id")]
+    #[schema(value_type = Object)]
+    pub _id: ObjectId,
+    #[schema(value_type = Object)]
+    pub trade_id: ObjectId,
+    pub user_id: String,
+    pub status: TradeStatus,
+    #[schema(value_type = Object)]
+    pub at: DateTime
+}
+
+impl TradeEvent {
+    fn new(trade_id: ObjectId, user_id: &str, status: TradeStatus) -> Self {
+        Self {
+            _id: ObjectId::new(),
+            trade_id,
+            user_id: user_id.to_string(),
+            status,
+            at: DateTime::now()
+        }
+    }
+}
+
+#[derive(Debug, Clone, Deserialize, Validate, ToSchema)]
+#[serde(rename_all = "camelCase")]
+pub struct TradeForCreate {
+    #[validate(length(min = 1))]
+    pub to_user: String,
+    #[validate(length(max = 10))]
+    pub offered: Vec<String>,
+    #[validate(length(max = 10))]
+    pub requested: Vec<String>,
+    #[validate(range(min = 1, max = 168))]
+    pub expires_in_hours: Option<i64>
+}
+
+#[derive(Deserialize, IntoParams)]
+#[into_params(parameter_in = Query)]
+pub struct TradeParams {
+    status: Option<TradeStatus>
+}
+
+
+#[utoipa::path(
+    get,
+    path = "/",
+    params(TradeParams),
+    responses(
+        (status = 200, description = "The user's trades, newest first", body = [Trade]),
+        (status = 401, description = "Missing or invalid token", body = Problem, content_type = "application/problem+json")
+    ),
+    security(("bearer_auth" = [])),
+    tag = "trades"
+)]
+async fn get_my_trades(State(state): State<CatsState>, auth_user: AuthUser, Query(q): Query<TradeParams>) -> Result<impl IntoResponse, super::Error> {
+    let mut filter = doc! { "$or": [{ "fromUser": &auth_user.subject }, { "toUser": &auth_user.subject }] };
+    if let Some(status) = q.status {
+        filter.insert("status", status.to_string());
+    }
+
+    let trades: Vec<Trade> = state.trades
+        .find(filter)
+        .sort(doc! { "createdAt": -1 }).await?
+        .try_collect().await?;
+
+    Ok(Json(trades))
+}
+
+#[utoipa::path(
+    get,
+    path = "/{id}",
+    params(("id" = String, Path, description = "Trade id")),
+    responses(
+        (status = 200, description = "A single trade", body = Trade),
+        (status = 400, description = "Invalid trade id", body = Problem, content_type = "application/problem+json"),
+        (status = 401, description = "Missing or invalid token", body = Problem, content_type = "application/problem+json"),
+        (status = 404, description = "Trade not found", body = Problem, content_type = "application/problem+json")
+    ),
+    security(("bearer_auth" = [])),
+    tag = "trades"
+)]
+async fn get_trade(Path(id): Path<String>, State(state): State<CatsState>, auth_user: AuthUser) -> Result<impl IntoResponse, super::Error> {
+    let trade = find_trade_for_participant(&state, &id, &auth_user.subject).await?;
+
+    Ok(Json(trade))
+}
+
+#[utoipa::path(
+    get,
+    path = "/{id}/history",
+    params(("id" = String, Path, description = "Trade id")),
+    responses(
+        (status = 200, description = "Every status the trade went through, oldest first", body = [TradeEvent]),
+        (status = 400, description = "Invalid trade id", body = Problem, content_type = "application/problem+json"),
+        (status = 401, description = "Missing or invalid token", body = Problem, content_type = "application/problem+json"),
+        (status = 404, description = "Trade not found", body = Problem, content_type = "application/problem+json")
+    ),
+    security(("bearer_auth" = [])),
+    tag = "trades"
+)]
+async fn get_trade_history(Path(id): Path<String>, State(state): State<CatsState>, auth_user: AuthUser) -> Result<impl IntoResponse, super::Error> {
+    let trade = find_trade_for_participant(&state, &id, &auth_user.subject).await?;
+
+    let events: Vec<TradeEvent> = state.trade_events
+        .find(doc! { "tradeId": trade._id })
+        .sort(doc! { "at": 1 }).await?
+        .try_collect().await?;
+
+    Ok(Json(events))
+}
+
+#[utoipa::path(
+    post,
+    path = "/",
+    request_body = TradeForCreate,
+    responses(
+        (status = 200, description = "The created trade", body = Trade),
+        (status = 400, description = "Invalid trade, e.g. with cats the users don't own, or validation error", body = Problem, content_type = "application/problem+json"),
+        (status = 401, description = "Missing or invalid token", body = Problem, content_type = "application/problem+json")
+    ),
+    security(("bearer_auth" = [])),
+    tag = "trades"
+)]
+async fn create_trade(State(state): State<CatsState>, auth_user: AuthUser, Json(trade): Json<TradeForCreate>) -> Result<impl IntoResponse, super::Error> {
+    trade.validate()?;
+
+    if trade.to_user == auth_user.subject {
+        return Err(super::Error::InvalidTrade { reason: "you can't trade with yourself".into() });
+    }
+    if trade.offered.is_empty() && trade.requested.is_empty() {
+        return Err(super::Error::InvalidTrade { reason: "a trade has to offer or request at least 1 cat".into() });
+    }
+
+    let offered = parse_unbox_ids(&trade.offered)?;
+    let requested = parse_unbox_ids(&trade.requested)?;
+
+    // checked again when accepting, this is just to not let obviously broken offers in
+    ensure_owned(&state, &offered, &auth_user.subject).await?;
+    ensure_owned(&state, &requested, &trade.to_user).await?;
+
+    let now = DateTime::now();
+    let expires_in_ms = trade.expires_in_hours.unwrap_or(DEFAULT_EXPIRY_HOURS) * 60 * 60 * 1000;
+
+    let new_trade = Trade {
+        _id: ObjectId::new(),
+        from_user: auth_user.subject.clone(),
+        to_user: trade.to_user,
+        offered,
+        requested,
+        status: TradeStatus::Pending,
+        created_at: now,
+        updated_at: now,
+        expires_at: DateTime::from_millis(now.timestamp_millis() + expires_in_ms)
+    };
+
+    // the trade and its first event go in together, so there's never a trade without a history
+    let mut session = state.mongo.start_session().await?;
+    session.start_transaction().await?;
+
+    let res = async {
+        state.trades.insert_one(&new_trade).session(&mut session).await?;
+        state.trade_events
+            .insert_one(TradeEvent::new(new_trade._id, &auth_user.subject, TradeStatus::Pending))
+            .session(&mut session).await
+    }.await;
+
+    match res {
+        Ok(_) => {
+            session.commit_transaction().await?;
+            Ok(Json(new_trade))
+        },
+        Err(e) => {
+            if let Err(abort_err) = session.abort_transaction().await {
+                error!("->> could not abort trade transaction: {}", abort_err);
+            }
+            Err(e.into())
+        }
+    }
+}
+
+/// Swaps the owners of all the unboxes in a single transaction. Every update is also conditional on the current owner,
+/// so if any cat changed hands since the offer was made (e.g. through another trade) nothing is transferred.
+#[utoipa::path(
+    post,
+    path = "/{id}/accept",
+    params(("id" = String, Path, description = "Trade id")),
+    responses(
+        (status = 200, description = "The accepted trade, the cats changed owners", body = Trade),
+        (status = 400, description = "Invalid trade id", body = Problem, content_type = "application/problem+json"),
+        (status = 401, description = "Missing or invalid token", body = Problem, content_type = "application/problem+json"),
+        (status = 403, description = "Not the participant who can do this", body = Problem, content_type = "application/problem+json"),
+        (status = 404, description = "Trade not found", body = Problem, content_type = "application/problem+json"),
+        (status = 409, description = "The trade is not pending anymore, or the cats changed owners since", body = Problem, content_type = "application/problem+json")
+    ),
+    security(("bearer_auth" = [])),
+    tag = "trades"
+)]
+async fn accept_trade(Path(id): Path<String>, State(state): State<CatsState>, auth_user: AuthUser) -> Result<impl IntoResponse, super::Error> {
+    let trade_id = parse_trade_id(&id)?;
+
+    let mut session = state.mongo.start_session().await?;
+    session.start_transaction().await?;
+
+    match transfer_ownership(&state, &mut session, trade_id, &auth_user.subject).await {
+        Ok(trade) => {
+            session.commit_transaction().await?;
+            info!("trade {} accepted", trade._id);
+            Ok(Json(trade))
+        },
+        Err(e) => {
+            if let Err(abort_err) = session.abort_transaction().await {
+                error!("->> could not abort trade transaction: {}", abort_err);
+            }
+            Err(e)
+        }
+    }
+}
+
+#[utoipa::path(
+    post,
+    path = "/{id}/decline",
+    params(("id" = String, Path, description = "Trade id")),
+    responses(
+        (status = 200, description = "The declined trade", body = Trade),
+        (status = 400, description = "Invalid trade id", body = Problem, content_type = "application/problem+json"),
+        (status = 401, description = "Missing or invalid token", body = Problem, content_type = "application/problem+json"),
+        (status = 403, description = "Not the participant who can do this", body = Problem, content_type = "application/problem+json"),
+        (status = 404, description = "Trade not found", body = Problem, content_type = "application/problem+json"),
+        (status = 409, description = "The trade is not pending anymore", body = Problem, content_type = "application/problem+json")
+    ),
+    security(("bearer_auth" = [])),
+    tag = "trades"
+)]
+async fn decline_trade(Path(id): Path<String>, State(state): State<CatsState>, auth_user: AuthUser) -> Result<impl IntoResponse, super::Error> {
+    let trade_id = parse_trade_id(&id)?;
+    let trade = close_trade(&state, trade_id, doc! { "toUser": &auth_user.subject }, &auth_user.subject, TradeStatus::Declined).await?;
+
+    Ok(Json(trade))
+}
+
+#[utoipa::path(
+    post,
+    path = "/{id}/cancel",
+    params(("id" = String, Path, description = "Trade id")),
+    responses(
+        (status = 200, description = "The cancelled trade", body = Trade),
+        (status = 400, description = "Invalid trade id", body = Problem, content_type = "application/problem+json"),
+        (status = 401, description = "Missing or invalid token", body = Problem, content_type = "application/problem+json"),
+        (status = 403, description = "Not the participant who can do this", body = Problem, content_type = "application/problem+json"),
+        (status = 404, description = "Trade not found", body = Problem, content_type = "application/problem+json"),
+        (status = 409, description = "The trade is not pending anymore", body = Problem, content_type = "application/problem+json")
+    ),
+    security(("bearer_auth" = [])),
+    tag = "trades"
+)]
+async fn cancel_trade(Path(id): Path<String>, State(state): State<CatsState>, auth_user: AuthUser) -> Result<impl IntoResponse, super::Error> {
+    let trade_id = parse_trade_id(&id)?;
+    let trade = close_trade(&state, trade_id, doc! { "fromUser": &auth_user.subject }, &auth_user.subject, TradeStatus::Cancelled).await?;
+
+    Ok(Json(trade))
+}
+
+
+async fn transfer_ownership(state: &CatsState, session: &mut ClientSession, trade_id: ObjectId, user_id: &str) -> Result<Trade, super::Error> {
+    let trade = state.trades
+        .find_one_and_update(
+            doc! { "_id": trade_id, "toUser": user_id, "status": TradeStatus::Pending.to_string(), "expiresAt": { "$gt": DateTime::now() } },
+            doc! { "$set": { "status": TradeStatus::Accepted.to_string(), "updatedAt": DateTime::now() } }
+        )
+        .return_document(ReturnDocument::After)
+        .session(&mut *session).await?;
+
+    let trade = match trade {
+        Some(trade) => trade,
+        None => return Err(explain_unchanged_trade(state, trade_id, user_id).await)
+    };
+
+    for (unbox_ids, from, to) in [(&trade.offered, &trade.from_user, &trade.to_user), (&trade.requested, &trade.to_user, &trade.from_user)] {
+        if unbox_ids.is_empty() {
+            continue;
+        }
+
+        let res = state.unboxes
+            .update_many(
+                doc! { "_id": { "$in": unbox_ids }, "userId": from },
+                // names, re-rolled rarities and favourites are the previous owner's, they don't come with the cat
+                doc! { "$set": { "userId": to }, "$unset": { "petName": "", "fullName": "", "rarity": "", "favourite": "" } }
+            )
+            .session(&mut *session).await?;
+
+        if res.modified_count != unbox_ids.len() as u64 {
+            return Err(super::Error::CatsNoLongerOwned);
+        }
+    }
+
+    state.trade_events
+        .insert_one(TradeEvent::new(trade._id, user_id, TradeStatus::Accepted))
+        .session(&mut *session).await?;
+
+    Ok(trade)
+}
+
+/// Moves a pending trade into a final status, `who` says which participant is allowed to do it.
+async fn close_trade(state: &CatsState, trade_id: ObjectId, who: Document, user_id: &str, status: TradeStatus) -> Result<Trade, super::Error> {
+    // expired trades can't be closed, even before the sweeper got to them
+    let mut filter = doc! { "_id": trade_id, "status": TradeStatus::Pending.to_string(), "expiresAt": { "$gt": DateTime::now() } };
+    filter.extend(who);
+
+    let trade = state.trades
+        .find_one_and_update(filter, doc! { "$set": { "status": status.to_string(), "updatedAt": DateTime::now() } })
+        .return_document(ReturnDocument::After).await?;
+
+    let trade = match trade {
+        Some(trade) => trade,
+        None => return Err(explain_unchanged_trade(state, trade_id, user_id).await)
+    };
+
+    state.trade_events.insert_one(TradeEvent::new(trade._id, user_id, status)).await?;
+    info!("trade {} {}", trade._id, status);
+
+    Ok(trade)
+}
+
+/// Works out why a conditional update of a trade didn't match anything.
+async fn explain_unchanged_trade(state: &CatsState, trade_id: ObjectId, user_id: &str) -> super::Error {
+    let trade = match state.trades.find_one(doc! { "_id": trade_id }).await {
+        Ok(Some(trade)) => trade,
+        Ok(None) => return super::Error::TradeNotFound { id: trade_id.to_hex() },
+        Err(e) => return e.into()
+    };
+
+    if trade.from_user != user_id && trade.to_user != user_id {
+        super::Error::TradeNotFound { id: trade_id.to_hex() }
+    } else if trade.status != TradeStatus::Pending {
+        super::Error::TradeNotPending { status: trade.status.to_string() }
+    } else if trade.expires_at <= DateTime::now() {
+        super::Error::TradeNotPending { status: TradeStatus::Expired.to_string() }
+    } else {
+        super::Error::NotTradeParticipant
+    }
+}
+
+/// Returns how many trades were actually expired, candidates that got accepted or declined in the meantime don't count.
+async fn expire_trades(state: &CatsState) -> Result<u64, super::Error> {
+    let filter = doc! { "status": TradeStatus::Pending.to_string(), "expiresAt": { "$lte": DateTime::now() } };
+    let candidates: Vec<Trade> = state.trades.find(filter).await?.try_collect().await?;
+    let mut expired = 0;
+
+    for trade in &candidates {
+        // conditional again, in case it was accepted in the meantime
+        let res = state.trades
+            .update_one(
+                doc! { "_id": trade._id, "status": TradeStatus::Pending.to_string() },
+                doc! { "$set": { "status": TradeStatus::Expired.to_string(), "updatedAt": DateTime::now() } }
+            ).await?;
+
+        if res.modified_count == 1 {
+            state.trade_events.insert_one(TradeEvent::new(trade._id, SYSTEM_USER, TradeStatus::Expired)).await?;
+        }
+        expired += res.modified_count;
+    }
+
+    Ok(expired)
+}
+
+async fn find_trade_for_participant(state: &CatsState, id: &str, user_id: &str) -> Result<Trade, super::Error> {
+    let trade_id = parse_trade_id(id)?;
+
+    // other people's trades are reported as not found, to not leak which ids exist
+    state.trades
+        .find_one(doc! { "_id": trade_id, "$or": [{ "fromUser": user_id }, { "toUser": user_id }] }).await?
+        .ok_or(super::Error::TradeNotFound { id: id.to_string() })
+}
+
+async fn ensure_owned(state: &CatsState, unbox_ids: &[ObjectId], user_id: &str) -> Result<(), super::Error> {
+    if unbox_ids.is_empty() {
+        return Ok(());
+    }
+
+    let count = state.unboxes.count_documents(doc! { "_id": { "$in": unbox_ids }, "userId": user_id }).await?;
+
+    if count != unbox_ids.len() as u64 {
+        return Err(super::Error::InvalidTrade { reason: format!("not all of the cats belong to {}", user_id) });
+    }
+
+    Ok(())
+}
+
+fn parse_trade_id(id: &str) -> Result<ObjectId, super::Error> {
+    ObjectId::parse_str(id).map_err(|_| super::Error::InvalidTradeId { id: id.to_string() })
+}
+
+fn parse_unbox_ids(ids: &[String]) -> Result<Vec<ObjectId>, super::Error> {
+    let mut parsed = Vec::with_capacity(ids.len());
+
+    for id in ids {
+        let id = ObjectId::parse_str(id)
+            .map_err(|_| super::Error::InvalidTrade { reason: format!("`{}` is not a valid unbox id", id) })?;
+
+        if parsed.contains(&id) {
+            return Err(super::Error::InvalidTrade { reason: format!("unbox `{}` is listed more than once", id) });
+        }
+        parsed.push(id);
+    }
+
+    Ok(parsed)
+}
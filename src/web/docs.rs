@@ -0,0 +1,42 @@
+use axum::{routing::get, Json, Router};
+use tower_http::services::{ServeDir, ServeFile};
+use utoipa::{openapi::InfoBuilder, OpenApi};
+
+use super::{jp2, bustimetravel::BusTimeTravelApi, cats::{trades::TradesApi, CatsApi}, error::ErrorApi, tf2sc::Tf2scApi, timetable::TimetableApi, utoipa_ext::{merge_openapis, MergeError, NestedApi}};
+
+const TITLE: &str = "Service Nexus";
+
+
+/// `/openapi.json` with the docs of every service, and a swagger ui for it at `/docs`.
+pub fn routes() -> Result<Router, MergeError> {
+    let openapi = build_openapi()?;
+
+    let router = Router::new()
+        .route("/openapi.json", get(|| async { Json(openapi) }))
+        .route_service("/docs", ServeFile::new("assets/docs.html"))
+        .route_service("/docs/swagger-ui-darkmode.css", ServeFile::new("assets/swagger-ui-darkmode.css"))
+        .nest_service("/docs/favicon_io", ServeDir::new("assets/favicon_io"))
+        .nest_service("/docs/images", ServeDir::new("assets/images"));
+
+    Ok(router)
+}
+
+/// Every service documents its paths relative to where it's nested, new services just need to be added here.
+pub fn build_openapi() -> Result<utoipa::openapi::OpenApi, MergeError> {
+    let info = InfoBuilder::new()
+        .title(TITLE)
+        .version(env!("CARGO_PKG_VERSION"))
+        .description(Some("Every service of the nexus. Errors are `application/problem+json` `Problem`s."))
+        .build();
+
+    let services = [
+        NestedApi::new("/cats", CatsApi::openapi()),
+        NestedApi::new("/cats/trades", TradesApi::openapi()),
+        NestedApi::new("/timetable", TimetableApi::openapi()),
+        NestedApi::new("/tf2sc", Tf2scApi::openapi()),
+        NestedApi::new("/bustimetravel", BusTimeTravelApi::openapi()),
+        NestedApi::new("", ErrorApi::openapi())
+    ];
+
+    merge_openapis(info, services.into_iter().chain(jp2::nested_apis()))
+}
@@ -0,0 +1,151 @@
+use axum::{http::{header, HeaderValue, StatusCode}, response::{IntoResponse, Response}, Json};
+use serde::Serialize;
+use tracing::{error, warn};
+use utoipa::{OpenApi, ToSchema};
+
+use super::request_id;
+
+/// Implemented by the `Error` of every service, so that they all respond with the same `Problem` shape.
+///
+/// Their `IntoResponse` is then just `problem_response(&self)`.
+pub trait ApiError: std::fmt::Display {
+    fn problem(&self) -> Problem;
+}
+
+/// Logs the error with the request id and turns it into a problem+json response.
+///
+/// Only 5xx are logged as errors, the 4xx are the client's doing and would drown them out.
+pub fn problem_response<E: ApiError>(e: &E) -> Response {
+    let problem = e.problem();
+    let request_id = problem.request_id.as_deref().unwrap_or("-");
+    if problem.status >= 500 {
+        error!("->> [{}] {} {}: {}", request_id, problem.status, problem.code, e);
+    } else {
+        warn!("->> [{}] {} {}: {}", request_id, problem.status, problem.code, e);
+    }
+
+    problem.into_response()
+}
+
+/// An RFC 7807 problem details body, sent as `application/problem+json` by every service.
+///
+/// `detail` is always safe to show to users, 5xx responses never carry the internal error text, that only goes to the logs.
+#[derive(Debug, Clone, Serialize, ToSchema)]
+#[serde(rename_all = "camelCase")]
+pub struct Problem {
+    /// Identifies the kind of problem, `urn:service-nexus:<code>`.
+    #[serde(rename = "type")]
+    #[schema(example = "urn:service-nexus:cat_not_found")]
+    pub kind: String,
+    #[schema(example = "Cat not found")]
+    pub title: String,
+    #[schema(example = 404)]
+    pub status: u16,
+    #[schema(example = "Cat with id 0XYvRd7oD not found")]
+    pub detail: String,
+    /// The path of the request that failed.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    #[schema(example = "/cats/0XYvRd7oD")]
+    pub instance: Option<String>,
+    /// A stable, machine readable name of the error.
+    #[schema(example = "cat_not_found")]
+    pub code: &'static str,
+    /// Same as the `X-Request-Id` response header, for finding the request in the logs.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub request_id: Option<String>,
+    /// Seconds until retrying makes sense, also sent as `Retry-After`.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub retry_after: Option<u64>,
+    /// More about the error, e.g. which fields failed validation.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    #[schema(value_type = Option<Object>)]
+    pub errors: Option<serde_json::Value>
+}
+
+impl Problem {
+    pub fn new(status: StatusCode, code: &'static str, detail: impl ToString) -> Self {
+        let request = request_id::current();
+
+        Self {
+            kind: format!("urn:service-nexus:{}", code),
+            title: title_for(code),
+            status: status.as_u16(),
+            detail: detail.to_string(),
+            instance: request.as_ref().map(|r| r.path.clone()),
+            code,
+            request_id: request.map(|r| r.id),
+            retry_after: None,
+            errors: None
+        }
+    }
+
+    /// A 500 that says nothing about what went wrong.
+    pub fn internal() -> Self {
+        Self::new(StatusCode::INTERNAL_SERVER_ERROR, "internal_error", "Something went wrong on our side")
+    }
+
+    /// An api this one depends on failed: 504 if it timed out, 503 if it's down or rate limiting us, 502 otherwise.
+    pub fn upstream(service: &str, e: &reqwest::Error) -> Self {
+        if e.is_timeout() {
+            return Self::new(StatusCode::GATEWAY_TIMEOUT, "upstream_timeout", format!("{} took too long to respond", service))
+                .with_retry_after(5);
+        }
+
+        let unavailable = e.is_connect() || e.status().is_some_and(|status| {
+            status == StatusCode::TOO_MANY_REQUESTS || status == StatusCode::SERVICE_UNAVAILABLE
+        });
+
+        if unavailable {
+            Self::new(StatusCode::SERVICE_UNAVAILABLE, "upstream_unavailable", format!("{} is unavailable right now", service))
+                .with_retry_after(30)
+        } else {
+            Self::bad_gateway(service)
+        }
+    }
+
+    /// An api this one depends on answered with something unusable.
+    pub fn bad_gateway(service: &str) -> Self {
+        Self::new(StatusCode::BAD_GATEWAY, "upstream_error", format!("{} returned an invalid response", service))
+    }
+
+    pub fn with_retry_after(mut self, secs: u64) -> Self {
+        self.retry_after = Some(secs);
+        self
+    }
+
+    pub fn with_errors(mut self, errors: impl Serialize) -> Self {
+        self.errors = serde_json::to_value(errors).ok();
+        self
+    }
+}
+
+impl IntoResponse for Problem {
+    fn into_response(self) -> Response {
+        let status = StatusCode::from_u16(self.status).unwrap_or(StatusCode::INTERNAL_SERVER_ERROR);
+        let retry_after = self.retry_after;
+
+        let mut res = (status, Json(self)).into_response();
+        res.headers_mut().insert(header::CONTENT_TYPE, HeaderValue::from_static("application/problem+json"));
+        if let Some(secs) = retry_after {
+            res.headers_mut().insert(header::RETRY_AFTER, HeaderValue::from(secs));
+        }
+
+        res
+    }
+}
+
+/// `cat_not_found` -> `Cat not found`, so that the title is the same for every problem of a kind.
+fn title_for(code: &str) -> String {
+    let words = code.replace('_', " ");
+    let mut chars = words.chars();
+
+    match chars.next() {
+        Some(first) => first.to_uppercase().chain(chars).collect(),
+        None => words
+    }
+}
+
+/// Documents the shape of the error responses.
+#[derive(OpenApi)]
+#[openapi(components(schemas(Problem)))]
+pub struct ErrorApi;
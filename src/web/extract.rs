@@ -0,0 +1,159 @@
+//! axum's `Json`, `Query` and `Path`, but rejecting with a problem+json body instead of axum's plain text.
+//!
+//! Handlers should import these instead of the axum ones, `Json` also works as a response.
+
+use axum::{extract::{rejection::{JsonRejection, PathRejection, QueryRejection}, FromRequest, FromRequestParts}, http::StatusCode, response::{IntoResponse, Response}};
+use serde::Serialize;
+
+use super::error::{problem_response, ApiError, Problem};
+
+
+#[derive(Debug, Clone, Copy, Default, FromRequest)]
+#[from_request(via(axum::Json), rejection(ExtractError))]
+pub struct Json<T>(pub T);
+
+impl<T: Serialize> IntoResponse for Json<T> {
+    fn into_response(self) -> Response {
+        axum::Json(self.0).into_response()
+    }
+}
+
+#[derive(Debug, Clone, Copy, Default, FromRequestParts)]
+#[from_request(via(axum::extract::Query), rejection(ExtractError))]
+pub struct Query<T>(pub T);
+
+#[derive(Debug, Clone, Copy, Default, FromRequestParts)]
+#[from_request(via(axum::extract::Path), rejection(ExtractError))]
+pub struct Path<T>(pub T);
+
+
+/// Why the body, query or path of a request couldn't be extracted. The detail is axum's own message.
+#[derive(Debug, thiserror::Error)]
+pub enum ExtractError {
+    #[error(transparent)]
+    Json(#[from] JsonRejection),
+    #[error(transparent)]
+    Query(#[from] QueryRejection),
+    #[error(transparent)]
+    Path(#[from] PathRejection),
+}
+
+impl ApiError for ExtractError {
+    fn problem(&self) -> Problem {
+        match self {
+            Self::Json(JsonRejection::MissingJsonContentType(e)) => Problem::new(e.status(), "not_json", e.body_text()),
+            Self::Json(JsonRejection::JsonSyntaxError(e)) => Problem::new(e.status(), "invalid_json", e.body_text()),
+            Self::Json(JsonRejection::JsonDataError(e)) => Problem::new(e.status(), "invalid_body", e.body_text()),
+            Self::Json(e) if e.status() == StatusCode::PAYLOAD_TOO_LARGE => Problem::new(e.status(), "body_too_large", e.body_text()),
+            Self::Json(e) => Problem::new(StatusCode::BAD_REQUEST, "body_unreadable", e.body_text()),
+            Self::Query(e) => Problem::new(e.status(), "invalid_query", e.body_text()),
+            // a route without the params the handler asks for, that's on us
+            Self::Path(e) if e.status().is_server_error() => Problem::internal(),
+            Self::Path(e) => Problem::new(e.status(), "invalid_path", e.body_text()),
+        }
+    }
+}
+
+impl IntoResponse for ExtractError {
+    fn into_response(self) -> Response {
+        problem_response(&self)
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+    use axum::{body::{to_bytes, Body}, extract::Request, http::{header, request::Parts}, routing::get, Router};
+    use serde::Deserialize;
+
+    use super::*;
+
+    #[derive(Debug, Deserialize)]
+    struct Named {
+        #[allow(dead_code)]
+        name: String
+    }
+
+    async fn problem_of(res: Response) -> (StatusCode, serde_json::Value) {
+        assert_eq!(res.headers()[header::CONTENT_TYPE], "application/problem+json");
+        let status = res.status();
+        let body = to_bytes(res.into_body(), usize::MAX).await.unwrap();
+
+        (status, serde_json::from_slice(&body).unwrap())
+    }
+
+    async fn json_rejection(content_type: Option<&str>, body: &'static str) -> (StatusCode, serde_json::Value) {
+        let mut req = Request::builder().method("POST").uri("/");
+        if let Some(content_type) = content_type {
+            req = req.header(header::CONTENT_TYPE, content_type);
+        }
+
+        let rejection = Json::<Named>::from_request(req.body(Body::from(body)).unwrap(), &()).await.unwrap_err();
+        problem_of(rejection.into_response()).await
+    }
+
+    fn parts(uri: &str) -> Parts {
+        Request::builder().uri(uri).body(()).unwrap().into_parts().0
+    }
+
+    #[tokio::test]
+    async fn json_rejections_are_problems() {
+        let (status, problem) = json_rejection(None, r#"{"name": "x"}"#).await;
+        assert_eq!((status, problem["code"].as_str()), (StatusCode::UNSUPPORTED_MEDIA_TYPE, Some("not_json")));
+
+        let (status, problem) = json_rejection(Some("application/json"), r#"{"name": "#).await;
+        assert_eq!((status, problem["code"].as_str()), (StatusCode::BAD_REQUEST, Some("invalid_json")));
+
+        let (status, problem) = json_rejection(Some("application/json"), r#"{"nom": "x"}"#).await;
+        assert_eq!((status, problem["code"].as_str()), (StatusCode::UNPROCESSABLE_ENTITY, Some("invalid_body")));
+        assert!(problem["detail"].as_str().unwrap().contains("missing field `name`"), "{}", problem);
+        assert_eq!(problem["type"], "urn:service-nexus:invalid_body");
+    }
+
+    #[tokio::test]
+    async fn valid_json_is_extracted() {
+        let req = Request::builder().method("POST").uri("/")
+            .header(header::CONTENT_TYPE, "application/json")
+            .body(Body::from(r#"{"name": "x"}"#)).unwrap();
+
+        let Json(body) = Json::<Named>::from_request(req, &()).await.unwrap();
+        assert_eq!(body.name, "x");
+    }
+
+    #[tokio::test]
+    async fn query_rejections_are_problems() {
+        #[derive(Debug, Deserialize)]
+        struct Params {
+            #[allow(dead_code)]
+            limit: i64
+        }
+
+        let rejection = Query::<Params>::from_request_parts(&mut parts("/?limit=lots"), &()).await.unwrap_err();
+        let (status, problem) = problem_of(rejection.into_response()).await;
+        assert_eq!((status, problem["code"].as_str()), (StatusCode::BAD_REQUEST, Some("invalid_query")));
+
+        let Query(params) = Query::<Params>::from_request_parts(&mut parts("/?limit=5"), &()).await.unwrap();
+        assert_eq!(params.limit, 5);
+    }
+
+    #[tokio::test]
+    async fn path_rejections_are_problems() {
+        async fn handler(Path(id): Path<u32>) -> String {
+            id.to_string()
+        }
+
+        // path params only exist once the router matched the route
+        let app = Router::new().route("/:id", get(handler));
+        let call = |uri: &'static str| {
+            let app = app.clone();
+            async move {
+                tower::ServiceExt::oneshot(app, Request::builder().uri(uri).body(Body::empty()).unwrap()).await.unwrap()
+            }
+        };
+
+        let (status, problem) = problem_of(call("/abc").await).await;
+        assert_eq!((status, problem["code"].as_str()), (StatusCode::BAD_REQUEST, Some("invalid_path")));
+
+        assert_eq!(call("/12").await.status(), StatusCode::OK);
+    }
+}
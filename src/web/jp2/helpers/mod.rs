@@ -1,2 +0,0 @@
-pub mod general;
-pub mod utoipa_ext;
\ No newline at end of file
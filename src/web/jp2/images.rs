@@ -1,73 +1,307 @@
 use std::sync::Arc;
-use axum::{response::IntoResponse, routing::get, Extension, Json, Router};
+use axum::{extract::DefaultBodyLimit, http::{header, StatusCode}, middleware::from_fn_with_state, response::IntoResponse, routing::{get, post}, Extension, Router};
+use crate::web::extract::{Json, Path, Query};
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
 use sqlx::{query_as, query_scalar};
-use utoipa::OpenApi;
+use tracing::info;
+use utoipa::{IntoParams, OpenApi, ToSchema};
 
-use crate::web::{Object, SupabaseResources};
+use crate::web::{auth::{self, AuthLayer, AuthUser, BearerAuth}, multipart::FormData, storage::Blob, thumbnails, Jp2Resources};
 
 use super::CountResponse;
 
-pub fn routes() -> Router {
+/// Where the image files are served from, the links point here.
+const FILES_ROUTE: &str = "/jp2/images/files";
+/// Everything but the id.
+const IMAGE_COLUMNS: &str = "name, content_type, width, height, size_bytes, sha256, uploaded_by, tags, created_at";
+
+/// Biggest image that can be uploaded.
+pub const MAX_IMAGE_BYTES: usize = 5 * 1024 * 1024;
+/// Room for the tags and the multipart headers on top of the image.
+const MAX_FORM_OVERHEAD: usize = 64 * 1024;
+const MAX_TAGS: usize = 10;
+const MAX_TAG_LEN: usize = 32;
+
+pub fn routes(auth: AuthLayer) -> Router {
     Router::new()
-        .route("/", get(get_all))
+        .route("/", get(get_all)
+            .merge(post(upload)
+                .layer(DefaultBodyLimit::max(MAX_IMAGE_BYTES + MAX_FORM_OVERHEAD))
+                .layer(from_fn_with_state(auth, auth::auth_mw))))
         .route("/random", get(get_random))
         .route("/count", get(get_count))
+        .route("/files/:name", get(get_file))
 }
 
 #[derive(OpenApi)]
 #[openapi(
-    paths(get_all, get_random),
-    // components(schemas(??? should be the img object but links will do for now)),
+    paths(get_all, upload, get_random, get_count, get_file),
+    components(schemas(Image, UploadForm, UploadedImage, super::CountResponse)),
+    modifiers(&BearerAuth),
     tags(
         (name = "images", description = "Images API endpoints")
     )
 )]
 pub struct ImagesApi;
 
+/// A row of `images`, the file is in the jp2 bucket under the same name.
+#[derive(Debug, sqlx::FromRow, Serialize, ToSchema)]
+pub struct Image {
+    name: String,
+    content_type: String,
+    /// The dimensions, size, hash and uploader are `None` for the images from before uploads.
+    width: Option<i32>,
+    height: Option<i32>,
+    size_bytes: Option<i64>,
+    sha256: Option<String>,
+    uploaded_by: Option<String>,
+    tags: Vec<String>,
+    #[schema(value_type = String, format = DateTime)]
+    created_at: chrono::DateTime<chrono::Utc>
+}
+
+impl Image {
+    pub fn to_link(&self) -> String {
+        format!("{}/{}", FILES_ROUTE, self.name)
+    }
+}
+
+/// The `multipart/form-data` body of an upload, only for the docs.
+#[derive(ToSchema)]
+#[allow(dead_code)]
+pub struct UploadForm {
+    /// A png, jpeg, gif or webp of at most 5 MiB, the type is sniffed from the content.
+    #[schema(value_type = String, format = Binary)]
+    file: Vec<u8>,
+    /// Comma separated, can also be repeated. Lowercase letters, digits, `-` and `_`, at most 10 of them.
+    tags: Option<String>
+}
+
+#[derive(Debug, Serialize, ToSchema)]
+pub struct UploadedImage {
+    link: String,
+    /// `true` if the same file was uploaded before, the image is then the earlier one, with its tags.
+    duplicate: bool,
+    image: Image
+}
+
+#[derive(Debug, Deserialize, IntoParams)]
+#[into_params(parameter_in = Query)]
+pub struct TagParams {
+    /// Comma separated, only images with all of these tags.
+    tags: Option<String>
+}
+
+impl TagParams {
+    fn tags(&self) -> Result<Vec<String>, super::Error> {
+        parse_tags(self.tags.iter().map(String::as_str))
+    }
+}
+
 #[utoipa::path(
     get,
     path = "/",
     tag = "images",
+    params(TagParams),
     responses(
-        (status = 200, description = "Get all images", body = [String])
+        (status = 200, description = "Get all images", body = [String]),
+        (status = 400, description = "Invalid tag", body = Problem, content_type = "application/problem+json")
     )
 )]
-async fn get_all(Extension(supabase): Extension<Arc<SupabaseResources>>) -> Result<impl IntoResponse, super::Error> {
-    let img_links = query_as::<_, Object>("SELECT * FROM storage.objects WHERE bucket_id = $1 AND metadata->>'mimetype' != 'application/octet-stream'")
-        .bind(&supabase.storage.bucket_id)
-        .fetch_all(&supabase.db)
+async fn get_all(Extension(jp2): Extension<Arc<Jp2Resources>>, Query(q): Query<TagParams>) -> Result<impl IntoResponse, super::Error> {
+    let img_links = query_as::<_, Image>(&format!("SELECT {} FROM images WHERE tags @> $1 ORDER BY created_at", IMAGE_COLUMNS))
+        .bind(q.tags()?)
+        .fetch_all(&jp2.db)
         .await?
         .iter()
-        .map(|img| img.to_link(&supabase.storage))
+        .map(Image::to_link)
         .collect::<Vec<_>>();
 
     Ok(Json(img_links))
 }
 
+#[utoipa::path(
+    post,
+    path = "/",
+    tag = "images",
+    request_body(content = UploadForm, content_type = "multipart/form-data"),
+    responses(
+        (status = 201, description = "The uploaded image", body = UploadedImage),
+        (status = 200, description = "The same file was already uploaded, this is the earlier image", body = UploadedImage),
+        (status = 400, description = "No file, a file that's not a valid image, or an invalid tag", body = Problem, content_type = "application/problem+json"),
+        (status = 401, description = "Missing or invalid token", body = Problem, content_type = "application/problem+json"),
+        (status = 413, description = "The image is bigger than 5 MiB", body = Problem, content_type = "application/problem+json"),
+        (status = 415, description = "Not a multipart body, or not a png, jpeg, gif or webp", body = Problem, content_type = "application/problem+json")
+    ),
+    security(("bearer_auth" = []))
+)]
+async fn upload(Extension(jp2): Extension<Arc<Jp2Resources>>, auth_user: AuthUser, form: FormData) -> Result<impl IntoResponse, super::Error> {
+    let file = form.field("file").ok_or(super::Error::MissingImageFile)?;
+    let tags = parse_tags(form.fields("tags").map(|f| f.text().unwrap_or_default()))?;
+
+    if file.bytes.len() > MAX_IMAGE_BYTES {
+        return Err(super::Error::ImageTooLarge { max_bytes: MAX_IMAGE_BYTES });
+    }
+
+    // whatever the client claims the type is doesn't matter
+    let content_type = thumbnails::sniff_content_type(&file.bytes).ok_or(super::Error::UnsupportedImageType)?;
+    let (width, height) = thumbnails::dimensions(&file.bytes).ok_or(super::Error::InvalidImage)?;
+    // the columns are plain integers, a header can claim up to u32::MAX
+    let (Ok(width), Ok(height)) = (i32::try_from(width), i32::try_from(height)) else {
+        return Err(super::Error::InvalidImage);
+    };
+    let sha256 = format!("{:x}", Sha256::digest(&file.bytes));
+
+    if let Some(existing) = find_by_hash(&jp2, &sha256).await? {
+        return Ok((StatusCode::OK, Json(UploadedImage { link: existing.to_link(), duplicate: true, image: existing })));
+    }
+
+    let name = format!("{}.{}", &sha256[..16], extension(content_type));
+
+    // the row is only committed once the file is stored, so a failed insert or store leaves nothing behind
+    // (if the commit itself fails the file stays, but the name comes from the hash, so uploading it again reuses it)
+    let mut tx = jp2.db.begin().await?;
+
+    let sql = format!(
+        "INSERT INTO images (name, content_type, width, height, size_bytes, sha256, uploaded_by, tags) \
+        VALUES ($1, $2, $3, $4, $5, $6, $7, $8) ON CONFLICT (sha256) DO NOTHING RETURNING {}",
+        IMAGE_COLUMNS
+    );
+    let inserted = query_as::<_, Image>(&sql)
+        .bind(&name)
+        .bind(content_type)
+        .bind(width)
+        .bind(height)
+        .bind(file.bytes.len() as i64)
+        .bind(&sha256)
+        .bind(&auth_user.subject)
+        .bind(&tags)
+        .fetch_optional(&mut *tx)
+        .await?;
+
+    if inserted.is_some() {
+        jp2.storage.put(&name, Blob { bytes: file.bytes.to_vec(), content_type: content_type.to_string() }).await?;
+    }
+    tx.commit().await?;
+
+    let (status, image, duplicate) = match inserted {
+        Some(image) => (StatusCode::CREATED, image, false),
+        // someone uploaded the same file in the meantime, their file is the one that was stored
+        None => (StatusCode::OK, find_by_hash(&jp2, &sha256).await?.ok_or(super::Error::ImageNotFound { name })?, true)
+    };
+
+    info!("{} uploaded {} ({}x{}, {} bytes, duplicate: {})", auth_user.subject, image.name, width, height, file.bytes.len(), duplicate);
+
+    Ok((status, Json(UploadedImage { link: image.to_link(), duplicate, image })))
+}
+
 #[utoipa::path(
     get,
     path = "/random",
     tag = "images",
+    params(TagParams),
     responses(
-        (status = 200, description = "Get a random image", body = String)
+        (status = 200, description = "Get a random image", body = String),
+        (status = 400, description = "Invalid tag", body = Problem, content_type = "application/problem+json"),
+        (status = 404, description = "There are no images (with those tags) yet", body = Problem, content_type = "application/problem+json")
     )
 )]
-async fn get_random(Extension(supabase): Extension<Arc<SupabaseResources>>) -> Result<impl IntoResponse, super::Error> {
-    let img_link = query_as::<_, Object>("SELECT * FROM storage.objects WHERE bucket_id = $1 AND metadata->>'mimetype' != 'application/octet-stream' ORDER BY RANDOM() LIMIT 1")
-        .bind(&supabase.storage.bucket_id)
-        .fetch_one(&supabase.db)
+async fn get_random(Extension(jp2): Extension<Arc<Jp2Resources>>, Query(q): Query<TagParams>) -> Result<impl IntoResponse, super::Error> {
+    let img_link = query_as::<_, Image>(&format!("SELECT {} FROM images WHERE tags @> $1 ORDER BY RANDOM() LIMIT 1", IMAGE_COLUMNS))
+        .bind(q.tags()?)
+        .fetch_optional(&jp2.db)
         .await?
-        .to_link(&supabase.storage);
+        .ok_or(super::Error::NoImages)?
+        .to_link();
 
     Ok(Json(img_link))
 }
 
+#[utoipa::path(
+    get,
+    path = "/files/{name}",
+    tag = "images",
+    params(
+        ("name" = String, Path, description = "Image name, like `papaspin.gif`")
+    ),
+    responses(
+        (status = 200, description = "The image file"),
+        (status = 404, description = "Image not found", body = Problem, content_type = "application/problem+json")
+    )
+)]
+async fn get_file(Extension(jp2): Extension<Arc<Jp2Resources>>, Path(name): Path<String>) -> Result<impl IntoResponse, super::Error> {
+    let image = query_as::<_, Image>(&format!("SELECT {} FROM images WHERE name = $1", IMAGE_COLUMNS))
+        .bind(&name)
+        .fetch_optional(&jp2.db)
+        .await?
+        .ok_or(super::Error::ImageNotFound { name: name.clone() })?;
+
+    // the row can outlive the file, if it was removed from the blob store by hand
+    let blob = jp2.storage.get(&image.name).await?
+        .ok_or(super::Error::ImageNotFound { name })?;
+
+    let headers = [
+        (header::CONTENT_TYPE, image.content_type),
+        (header::CACHE_CONTROL, "public, max-age=604800".to_string())
+    ];
+
+    Ok((headers, blob.bytes))
+}
 
-async fn get_count(Extension(supabase): Extension<Arc<SupabaseResources>>) -> Result<impl IntoResponse, super::Error> {
-    let count = query_scalar("SELECT COUNT(*) FROM storage.objects WHERE bucket_id = $1 AND metadata->>'mimetype' != 'application/octet-stream'")
-        .bind(&supabase.storage.bucket_id)
-        .fetch_one(&supabase.db)
+#[utoipa::path(
+    get,
+    path = "/count",
+    tag = "images",
+    params(TagParams),
+    responses(
+        (status = 200, description = "How many images (with those tags) there are", body = CountResponse),
+        (status = 400, description = "Invalid tag", body = Problem, content_type = "application/problem+json")
+    )
+)]
+async fn get_count(Extension(jp2): Extension<Arc<Jp2Resources>>, Query(q): Query<TagParams>) -> Result<impl IntoResponse, super::Error> {
+    let count = query_scalar("SELECT COUNT(*) FROM images WHERE tags @> $1")
+        .bind(q.tags()?)
+        .fetch_one(&jp2.db)
         .await?;
 
     Ok(Json(CountResponse { count }))
-}
\ No newline at end of file
+}
+
+
+async fn find_by_hash(jp2: &Jp2Resources, sha256: &str) -> Result<Option<Image>, super::Error> {
+    let image = query_as::<_, Image>(&format!("SELECT {} FROM images WHERE sha256 = $1", IMAGE_COLUMNS))
+        .bind(sha256)
+        .fetch_optional(&jp2.db)
+        .await?;
+
+    Ok(image)
+}
+
+/// Each of `raw` is a comma separated list, the tags are trimmed, lowercased and deduplicated.
+fn parse_tags<'a>(raw: impl Iterator<Item = &'a str>) -> Result<Vec<String>, super::Error> {
+    let mut tags = Vec::new();
+
+    for tag in raw.flat_map(|r| r.split(',')).map(|t| t.trim().to_lowercase()).filter(|t| !t.is_empty()) {
+        let is_valid = tag.len() <= MAX_TAG_LEN && tag.chars().all(|c| c.is_ascii_alphanumeric() || c == '-' || c == '_');
+        if !is_valid {
+            return Err(super::Error::InvalidTag { tag });
+        }
+        if !tags.contains(&tag) {
+            tags.push(tag);
+        }
+    }
+
+    if tags.len() > MAX_TAGS {
+        return Err(super::Error::TooManyTags { max: MAX_TAGS });
+    }
+
+    Ok(tags)
+}
+
+fn extension(content_type: &str) -> &str {
+    match content_type {
+        "image/jpeg" => "jpg",
+        other => other.trim_start_matches("image/")
+    }
+}
@@ -1,37 +1,43 @@
 use std::sync::Arc;
-use axum::{response::IntoResponse, Extension, Json, Router, http::StatusCode, routing::get};
+use axum::{response::IntoResponse, Extension, Router, http::StatusCode, routing::get};
+use crate::web::extract::Json;
 use serde::{Deserialize, Serialize};
-use tower_http::services::ServeDir;
-use tracing::error;
+use tower_http::services::ServeFile;
 use utoipa::ToSchema;
 use utoipa::OpenApi;
-use self::helpers::utoipa_ext::nest_openapis_at_prefix;
 
-use super::SupabaseResources;
+use super::{auth::AuthLayer, error::{problem_response, ApiError, ErrorApi, Problem}, storage::StorageError, utoipa_ext::{merge_openapis, MergeError, NestedApi}, Jp2Resources};
 
 mod quotes;
 mod images;
-mod helpers;
 
 
-pub fn routes(supabase: Arc<SupabaseResources>) -> Router {
-    let openapi = build_openapi();
+pub fn routes(jp2: Arc<Jp2Resources>, auth: AuthLayer) -> Result<Router, MergeError> {
+    let openapi = build_openapi()?;
 
-    Router::new()   
-        .nest("/quotes", quotes::routes())
-        .nest("/images", images::routes())
-        .nest_service("/", ServeDir::new("public"))
+    let router = Router::new()   
+        .nest("/quotes", quotes::routes(auth.clone()))
+        .nest("/images", images::routes(auth))
+        .route_service("/", ServeFile::new("assets/index.html"))
         .route("/doc.json", get(|| async { Json(openapi) } ))
-        // .route("/", get(|| async { Redirect::permanent("/index.html") }))
-        .layer(Extension(supabase))
+        .layer(Extension(jp2));
+
+    Ok(router)
 }
 
 
-pub fn build_openapi() -> utoipa::openapi::OpenApi {
-    let quotes_api = quotes::QuotesApi::openapi();
-    let images_api = images::ImagesApi::openapi();
+pub fn build_openapi() -> Result<utoipa::openapi::OpenApi, MergeError> {
+    let info = quotes::QuotesApi::openapi().info;
+
+    merge_openapis(info, nested_apis().into_iter().chain([NestedApi::new("", ErrorApi::openapi())]))
+}
 
-    nest_openapis_at_prefix(quotes_api, images_api, "/jp2")
+/// The quotes and images apis at `/jp2/quotes` and `/jp2/images`, also used by the docs of the whole nexus.
+pub fn nested_apis() -> [NestedApi; 2] {
+    [
+        NestedApi::new("/jp2", quotes::QuotesApi::openapi()).with_tag_prefix(),
+        NestedApi::new("/jp2", images::ImagesApi::openapi()).with_tag_prefix()
+    ]
 }
 
 
@@ -43,28 +49,62 @@ pub struct CountResponse {
 
 #[derive(Debug, thiserror::Error)]
 pub enum Error {
-    #[error("Supabase jp2 error: {0}")]
-    SupabaseJp2Error(#[from] sqlx::Error),
+    #[error("Jp2 db error: {0}")]
+    Jp2DbError(#[from] sqlx::Error),
+    #[error("Jp2 storage error: {0}")]
+    Jp2StorageError(#[from] StorageError),
     #[error("Quote with id {id} not found")]
     QuoteWithIdNotFound { id: i64 },
     #[error("Invalid quote id passed: {id}")]
     InvalidQuoteId { id: String },
+    #[error("Image {name} not found")]
+    ImageNotFound { name: String },
+    #[error("There are no images yet")]
+    NoImages,
+    #[error("There are no approved quotes yet")]
+    NoQuotes,
+    #[error(transparent)]
+    ValidationError(#[from] validator::ValidationErrors),
+    #[error("Nothing to update, give a new quote or translation")]
+    NothingToUpdate,
+    #[error("No `file` in the form")]
+    MissingImageFile,
+    #[error("The image is bigger than {max_bytes} bytes")]
+    ImageTooLarge { max_bytes: usize },
+    #[error("Only png, jpeg, gif and webp images can be uploaded")]
+    UnsupportedImageType,
+    #[error("The image is corrupted, its dimensions can't be read")]
+    InvalidImage,
+    #[error("Invalid tag `{tag}`, tags are at most 32 lowercase letters, digits, `-` and `_`")]
+    InvalidTag { tag: String },
+    #[error("At most {max} tags are allowed")]
+    TooManyTags { max: usize },
+}
+
+impl ApiError for Error {
+    fn problem(&self) -> Problem {
+        match self {
+            Self::Jp2DbError(_) | Self::Jp2StorageError(_) => Problem::internal(),
+            Self::QuoteWithIdNotFound { id: _ } => Problem::new(StatusCode::NOT_FOUND, "quote_not_found", self),
+            Self::InvalidQuoteId { id: _ } => Problem::new(StatusCode::BAD_REQUEST, "invalid_quote_id", self),
+            Self::ImageNotFound { name: _ } => Problem::new(StatusCode::NOT_FOUND, "image_not_found", self),
+            Self::NoImages => Problem::new(StatusCode::NOT_FOUND, "no_images", self),
+            Self::NoQuotes => Problem::new(StatusCode::NOT_FOUND, "no_quotes", self),
+            Self::ValidationError(errors) => Problem::new(StatusCode::BAD_REQUEST, "validation_error", "Validation error")
+                .with_errors(errors),
+            Self::NothingToUpdate => Problem::new(StatusCode::BAD_REQUEST, "nothing_to_update", self),
+            Self::MissingImageFile => Problem::new(StatusCode::BAD_REQUEST, "missing_image_file", self),
+            Self::ImageTooLarge { max_bytes: _ } => Problem::new(StatusCode::PAYLOAD_TOO_LARGE, "image_too_large", self),
+            Self::UnsupportedImageType => Problem::new(StatusCode::UNSUPPORTED_MEDIA_TYPE, "unsupported_image_type", self),
+            Self::InvalidImage => Problem::new(StatusCode::BAD_REQUEST, "invalid_image", self),
+            Self::InvalidTag { tag: _ } => Problem::new(StatusCode::BAD_REQUEST, "invalid_tag", self),
+            Self::TooManyTags { max: _ } => Problem::new(StatusCode::BAD_REQUEST, "too_many_tags", self),
+        }
+    }
 }
 
 impl IntoResponse for Error {
     fn into_response(self) -> axum::response::Response {
-        error!("->> {}", self);
-
-        let body = Json(serde_json::json!({
-            "error": format!("{}", &self)
-        }));
-
-        let status_code = match &self {
-            Self::QuoteWithIdNotFound { id: _ } => StatusCode::NOT_FOUND,
-            Self::InvalidQuoteId { id: _ } => StatusCode::BAD_REQUEST,
-            _ => StatusCode::INTERNAL_SERVER_ERROR
-        };
-        
-        (status_code, body).into_response()
+        problem_response(&self)
     }
 }
\ No newline at end of file
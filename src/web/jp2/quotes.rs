@@ -1,26 +1,44 @@
 use std::sync::Arc;
 use serde::{Deserialize, Serialize};
-use axum::{extract::Path, response::IntoResponse, routing::get, Extension, Json, Router};
+use axum::{middleware::from_fn_with_state, response::IntoResponse, routing::{get, patch, post}, Extension, Router};
+use crate::web::extract::{Json, Path, Query};
 use sqlx::{query_as, query_scalar};
-use utoipa::{OpenApi, ToSchema};
+use utoipa::{IntoParams, OpenApi, ToSchema};
+use validator::Validate;
 
-use crate::web::SupabaseResources;
+use crate::web::{auth::{self, AuthLayer, AuthUser, BearerAuth}, Jp2Resources};
 
 use super::CountResponse;
 
+/// Approve, reject and edit the submitted quotes.
+pub const MODERATE_QUOTES: &str = "moderate:quotes";
+
+/// Everything but the `search` tsvector.
+const QUOTE_COLUMNS: &str = "id, quote, translation, status, submitted_by, moderated_by, created_at, updated_at";
+
+
+/// Only approved quotes are public, anyone logged in can submit one and the users with `moderate:quotes` moderate them.
+pub fn routes(auth: AuthLayer) -> Router {
+    let logged_in = || from_fn_with_state(auth.clone(), auth::auth_mw);
+    let moderator = || from_fn_with_state(MODERATE_QUOTES, auth::permission_mw);
 
-pub fn routes() -> Router {
     Router::new()
-        .route("/", get(get_all))
+        .route("/", get(get_all)
+            .merge(post(create_one).layer(logged_in())))
         .route("/random", get(get_random))
         .route("/count", get(get_count))
-        .route("/:id", get(get_one))
+        .route("/moderation", get(get_for_moderation).layer(moderator()).layer(logged_in()))
+        .route("/:id", get(get_one)
+            .merge(patch(update_one).layer(moderator()).layer(logged_in())))
+        .route("/:id/approve", post(approve).layer(moderator()).layer(logged_in()))
+        .route("/:id/reject", post(reject).layer(moderator()).layer(logged_in()))
 }
 
 #[derive(OpenApi)]
 #[openapi(
-    paths(get_all, get_random, get_one),
-    components(schemas(Quote)),
+    paths(get_all, create_one, get_random, get_count, get_one, get_for_moderation, update_one, approve, reject),
+    components(schemas(Quote, QuoteStatus, QuoteForCreate, QuoteForUpdate, super::CountResponse)),
+    modifiers(&BearerAuth),
     tags(
         (name = "quotes", description = "Quotes API endpoints"),
         (name = "images", description = "Images API endpoints")
@@ -32,37 +50,134 @@ pub struct QuotesApi;
 pub struct Quote {
     pub id: i64,
     pub quote: String,
+    pub translation: String,
+    pub status: QuoteStatus,
+    /// `None` for the quotes from before submissions.
+    pub submitted_by: Option<String>,
+    /// The moderator who approved or rejected it.
+    pub moderated_by: Option<String>,
+    #[schema(value_type = String, format = DateTime)]
+    pub created_at: chrono::DateTime<chrono::Utc>,
+    #[schema(value_type = String, format = DateTime)]
+    pub updated_at: chrono::DateTime<chrono::Utc>
+}
+
+#[derive(sqlx::Type, Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize, ToSchema)]
+#[serde(rename_all = "lowercase")]
+#[sqlx(type_name = "quote_status", rename_all = "lowercase")]
+pub enum QuoteStatus {
+    Pending,
+    Approved,
+    Rejected
+}
+
+#[derive(Debug, Clone, Deserialize, Validate, ToSchema)]
+pub struct QuoteForCreate {
+    #[validate(length(min = 1, max = 1000))]
+    pub quote: String,
+    #[validate(length(min = 1, max = 1000))]
     pub translation: String
 }
 
+/// Only the given fields are changed.
+#[derive(Debug, Clone, Deserialize, Validate, ToSchema)]
+pub struct QuoteForUpdate {
+    #[validate(length(min = 1, max = 1000))]
+    pub quote: Option<String>,
+    #[validate(length(min = 1, max = 1000))]
+    pub translation: Option<String>
+}
+
+#[derive(Debug, Deserialize, IntoParams)]
+#[into_params(parameter_in = Query)]
+pub struct SearchParams {
+    /// Full-text search over the quote and its translation, in `websearch` syntax (`"exact phrase"`, `-excluded`, `or`).
+    q: Option<String>
+}
+
+#[derive(Debug, Deserialize, IntoParams)]
+#[into_params(parameter_in = Query)]
+pub struct ModerationParams {
+    /// `pending` if not given.
+    #[param(inline)]
+    status: Option<QuoteStatus>
+}
+
+
 #[utoipa::path(
     get,
     path = "/",
+    params(SearchParams),
     responses(
-        (status = 200, description = "Get all quotes", body = [Quote])
+        (status = 200, description = "Get all approved quotes, the best matches first when searching", body = [Quote])
     ),
     tag = "quotes"
 )]
-async fn get_all(Extension(supabase): Extension<Arc<SupabaseResources>>) -> Result<Json<Vec<Quote>>, super::Error> {
-    let quotes = query_as::<_, Quote>("SELECT * FROM quote")
-        .fetch_all(&supabase.db)
-        .await?;
+async fn get_all(Extension(jp2): Extension<Arc<Jp2Resources>>, Query(q): Query<SearchParams>) -> Result<Json<Vec<Quote>>, super::Error> {
+    let search = q.q.as_deref().map(str::trim).filter(|q| !q.is_empty());
+
+    let quotes = match search {
+        Some(search) => {
+            let sql = format!(
+                "SELECT {} FROM quote WHERE status = 'approved' AND search @@ websearch_to_tsquery('simple', $1) \
+                ORDER BY ts_rank(search, websearch_to_tsquery('simple', $1)) DESC, id",
+                QUOTE_COLUMNS
+            );
+            query_as::<_, Quote>(&sql)
+                .bind(search)
+                .fetch_all(&jp2.db)
+                .await?
+        },
+        None => {
+            query_as::<_, Quote>(&format!("SELECT {} FROM quote WHERE status = 'approved' ORDER BY id", QUOTE_COLUMNS))
+                .fetch_all(&jp2.db)
+                .await?
+        }
+    };
 
     Ok(Json(quotes))
 }
 
+#[utoipa::path(
+    post,
+    path = "/",
+    request_body = QuoteForCreate,
+    responses(
+        (status = 200, description = "The submitted quote, pending until a moderator approves it", body = Quote),
+        (status = 400, description = "Validation error", body = Problem, content_type = "application/problem+json"),
+        (status = 401, description = "Missing or invalid token", body = Problem, content_type = "application/problem+json")
+    ),
+    security(("bearer_auth" = [])),
+    tag = "quotes"
+)]
+async fn create_one(Extension(jp2): Extension<Arc<Jp2Resources>>, auth_user: AuthUser, Json(quote): Json<QuoteForCreate>) -> Result<impl IntoResponse, super::Error> {
+    quote.validate()?;
+
+    let sql = format!("INSERT INTO quote (quote, translation, submitted_by) VALUES ($1, $2, $3) RETURNING {}", QUOTE_COLUMNS);
+    let created = query_as::<_, Quote>(&sql)
+        .bind(quote.quote.trim())
+        .bind(quote.translation.trim())
+        .bind(&auth_user.subject)
+        .fetch_one(&jp2.db)
+        .await?;
+
+    Ok(Json(created))
+}
+
 #[utoipa::path(
     get,
     path = "/random",
     responses(
-        (status = 200, description = "Get a random quote", body = Quote)
+        (status = 200, description = "Get a random approved quote", body = Quote),
+        (status = 404, description = "There are no approved quotes yet", body = Problem, content_type = "application/problem+json")
     ),
     tag = "quotes"
 )]
-async fn get_random(Extension(supabase): Extension<Arc<SupabaseResources>>) -> Result<impl IntoResponse, super::Error> {
-    let quote = query_as::<_, Quote>("SELECT * FROM quote ORDER BY RANDOM() LIMIT 1")
-        .fetch_one(&supabase.db)
-        .await?;
+async fn get_random(Extension(jp2): Extension<Arc<Jp2Resources>>) -> Result<impl IntoResponse, super::Error> {
+    let quote = query_as::<_, Quote>(&format!("SELECT {} FROM quote WHERE status = 'approved' ORDER BY RANDOM() LIMIT 1", QUOTE_COLUMNS))
+        .fetch_optional(&jp2.db)
+        .await?
+        .ok_or(super::Error::NoQuotes)?;
 
     Ok(Json(quote))
 }
@@ -71,32 +186,165 @@ async fn get_random(Extension(supabase): Extension<Arc<SupabaseResources>>) -> R
     get,
     path = "/{id}",
     responses(
-        (status = 200, description = "Get a quote by ID", body = Quote),
-        (status = 404, description = "Quote not found", body = ErrorResponse),
-        (status = 400, description = "Invalid quote ID", body = ErrorResponse)
+        (status = 200, description = "Get an approved quote by ID", body = Quote),
+        (status = 404, description = "Quote not found", body = Problem, content_type = "application/problem+json"),
+        (status = 400, description = "Invalid quote ID", body = Problem, content_type = "application/problem+json")
     ),
     tag = "quotes",
     params(
         ("id" = i64, Path, description = "Quote ID")
     )
 )]
-async fn get_one(Extension(supabase): Extension<Arc<SupabaseResources>>, Path(quote_id): Path<String>) -> Result<impl IntoResponse, super::Error> {
-    let quote_id = quote_id.parse::<i64>()
-        .map_err(|_| super::Error::InvalidQuoteId { id: quote_id })?;
+async fn get_one(Extension(jp2): Extension<Arc<Jp2Resources>>, Path(quote_id): Path<String>) -> Result<impl IntoResponse, super::Error> {
+    let quote_id = parse_quote_id(quote_id)?;
 
-    let quote = query_as::<_, Quote>("SELECT * FROM quote WHERE id = $1")
-        .bind(&quote_id)
-        .fetch_optional(&supabase.db)
+    let quote = query_as::<_, Quote>(&format!("SELECT {} FROM quote WHERE id = $1 AND status = 'approved'", QUOTE_COLUMNS))
+        .bind(quote_id)
+        .fetch_optional(&jp2.db)
         .await?
         .ok_or(super::Error::QuoteWithIdNotFound { id: quote_id })?;
 
     Ok(Json(quote))
 }
 
-async fn get_count(Extension(supabase): Extension<Arc<SupabaseResources>>) -> Result<impl IntoResponse, super::Error> {
-    let count = query_scalar("SELECT COUNT(*) FROM quote")
-        .fetch_one(&supabase.db)
+#[utoipa::path(
+    get,
+    path = "/count",
+    responses(
+        (status = 200, description = "How many approved quotes there are", body = CountResponse)
+    ),
+    tag = "quotes"
+)]
+async fn get_count(Extension(jp2): Extension<Arc<Jp2Resources>>) -> Result<impl IntoResponse, super::Error> {
+    let count = query_scalar("SELECT COUNT(*) FROM quote WHERE status = 'approved'")
+        .fetch_one(&jp2.db)
         .await?;
 
     Ok(Json(CountResponse { count }))
 }
+
+#[utoipa::path(
+    get,
+    path = "/moderation",
+    params(ModerationParams),
+    responses(
+        (status = 200, description = "Quotes with the given status, oldest first", body = [Quote]),
+        (status = 401, description = "Missing or invalid token", body = Problem, content_type = "application/problem+json"),
+        (status = 403, description = "Missing the `moderate:quotes` permission", body = Problem, content_type = "application/problem+json")
+    ),
+    security(("bearer_auth" = [])),
+    tag = "quotes"
+)]
+async fn get_for_moderation(Extension(jp2): Extension<Arc<Jp2Resources>>, Query(q): Query<ModerationParams>) -> Result<impl IntoResponse, super::Error> {
+    let quotes = query_as::<_, Quote>(&format!("SELECT {} FROM quote WHERE status = $1 ORDER BY created_at, id", QUOTE_COLUMNS))
+        .bind(q.status.unwrap_or(QuoteStatus::Pending))
+        .fetch_all(&jp2.db)
+        .await?;
+
+    Ok(Json(quotes))
+}
+
+#[utoipa::path(
+    patch,
+    path = "/{id}",
+    request_body = QuoteForUpdate,
+    params(
+        ("id" = i64, Path, description = "Quote ID")
+    ),
+    responses(
+        (status = 200, description = "The edited quote, its status stays the same", body = Quote),
+        (status = 400, description = "Invalid quote ID, validation error or nothing to update", body = Problem, content_type = "application/problem+json"),
+        (status = 401, description = "Missing or invalid token", body = Problem, content_type = "application/problem+json"),
+        (status = 403, description = "Missing the `moderate:quotes` permission", body = Problem, content_type = "application/problem+json"),
+        (status = 404, description = "Quote not found", body = Problem, content_type = "application/problem+json")
+    ),
+    security(("bearer_auth" = [])),
+    tag = "quotes"
+)]
+async fn update_one(Extension(jp2): Extension<Arc<Jp2Resources>>, Path(quote_id): Path<String>, Json(update): Json<QuoteForUpdate>) -> Result<impl IntoResponse, super::Error> {
+    let quote_id = parse_quote_id(quote_id)?;
+    update.validate()?;
+
+    if update.quote.is_none() && update.translation.is_none() {
+        return Err(super::Error::NothingToUpdate);
+    }
+
+    let sql = format!(
+        "UPDATE quote SET quote = COALESCE($1, quote), translation = COALESCE($2, translation), updated_at = now() WHERE id = $3 RETURNING {}",
+        QUOTE_COLUMNS
+    );
+    let updated = query_as::<_, Quote>(&sql)
+        .bind(update.quote.as_deref().map(str::trim))
+        .bind(update.translation.as_deref().map(str::trim))
+        .bind(quote_id)
+        .fetch_optional(&jp2.db)
+        .await?
+        .ok_or(super::Error::QuoteWithIdNotFound { id: quote_id })?;
+
+    Ok(Json(updated))
+}
+
+#[utoipa::path(
+    post,
+    path = "/{id}/approve",
+    params(
+        ("id" = i64, Path, description = "Quote ID")
+    ),
+    responses(
+        (status = 200, description = "The approved quote, public from now on", body = Quote),
+        (status = 400, description = "Invalid quote ID", body = Problem, content_type = "application/problem+json"),
+        (status = 401, description = "Missing or invalid token", body = Problem, content_type = "application/problem+json"),
+        (status = 403, description = "Missing the `moderate:quotes` permission", body = Problem, content_type = "application/problem+json"),
+        (status = 404, description = "Quote not found", body = Problem, content_type = "application/problem+json")
+    ),
+    security(("bearer_auth" = [])),
+    tag = "quotes"
+)]
+async fn approve(Extension(jp2): Extension<Arc<Jp2Resources>>, auth_user: AuthUser, Path(quote_id): Path<String>) -> Result<impl IntoResponse, super::Error> {
+    let quote = moderate(&jp2, quote_id, QuoteStatus::Approved, &auth_user.subject).await?;
+
+    Ok(Json(quote))
+}
+
+#[utoipa::path(
+    post,
+    path = "/{id}/reject",
+    params(
+        ("id" = i64, Path, description = "Quote ID")
+    ),
+    responses(
+        (status = 200, description = "The rejected quote, hidden from now on", body = Quote),
+        (status = 400, description = "Invalid quote ID", body = Problem, content_type = "application/problem+json"),
+        (status = 401, description = "Missing or invalid token", body = Problem, content_type = "application/problem+json"),
+        (status = 403, description = "Missing the `moderate:quotes` permission", body = Problem, content_type = "application/problem+json"),
+        (status = 404, description = "Quote not found", body = Problem, content_type = "application/problem+json")
+    ),
+    security(("bearer_auth" = [])),
+    tag = "quotes"
+)]
+async fn reject(Extension(jp2): Extension<Arc<Jp2Resources>>, auth_user: AuthUser, Path(quote_id): Path<String>) -> Result<impl IntoResponse, super::Error> {
+    let quote = moderate(&jp2, quote_id, QuoteStatus::Rejected, &auth_user.subject).await?;
+
+    Ok(Json(quote))
+}
+
+/// Approved quotes can be rejected later on and the other way around, so this doesn't care about the current status.
+async fn moderate(jp2: &Jp2Resources, quote_id: String, status: QuoteStatus, moderator_id: &str) -> Result<Quote, super::Error> {
+    let quote_id = parse_quote_id(quote_id)?;
+
+    let sql = format!("UPDATE quote SET status = $1, moderated_by = $2, updated_at = now() WHERE id = $3 RETURNING {}", QUOTE_COLUMNS);
+    let quote = query_as::<_, Quote>(&sql)
+        .bind(status)
+        .bind(moderator_id)
+        .bind(quote_id)
+        .fetch_optional(&jp2.db)
+        .await?
+        .ok_or(super::Error::QuoteWithIdNotFound { id: quote_id })?;
+
+    Ok(quote)
+}
+
+fn parse_quote_id(quote_id: String) -> Result<i64, super::Error> {
+    quote_id.parse::<i64>()
+        .map_err(|_| super::Error::InvalidQuoteId { id: quote_id })
+}
@@ -1,14 +1,24 @@
-use std::sync::{Arc, LazyLock};
-use axum::{Extension, Router};
+use std::{sync::{Arc, LazyLock}, time::Duration};
+use axum::{middleware::from_fn, Extension, Router};
 use bustimetravel::ROUTES;
-use serde::{Deserialize, Serialize};
+use cats::{prefetch::{self, CatPool, DEFAULT_PREFETCH_POOL_SIZE}, limits::{UnboxLimiter, DEFAULT_TRUSTED_PROXY_HOPS, DEFAULT_UNBOXES_PER_IP, DEFAULT_UNBOXES_PER_USER}, names::{PetNameGenerator, PET_NAMES_FILE}, rarities::RarityTable, source::cat_source_from_config, CatsState};
 use shuttle_runtime::SecretStore;
 use sqlx::PgPool;
+use storage::{BlobStore, LocalBlobStore, Storage, DEFAULT_BLOB_STORE_DIR};
+use auth::{jwks::{jwks_source_from_config, JwksCache, DEFAULT_JWKS_TTL_SECS}, AuthLayer, IssuerConfig, DEFAULT_LEEWAY_SECS};
 use tower_http::cors::{self, CorsLayer};
-use utoipa::ToSchema;
 use tracing::info;
 
-mod cats;
+pub mod auth;
+pub mod cats;
+mod docs;
+pub mod error;
+pub mod extract;
+pub mod multipart;
+pub mod request_id;
+pub mod storage;
+pub mod thumbnails;
+pub mod utoipa_ext;
 mod timetable;
 mod jp2;
 mod tf2sc;
@@ -34,49 +44,151 @@ macro_rules! senv {
 //     }};
 // }
 
-pub async fn setup_web_server(secret_store: &SecretStore) -> Result<Router, shuttle_runtime::Error> {
-    let cat_api_key = senv!(secret_store, CAT_API_KEY);
+/// Resources used by both the web server and the discord bot, so that they're only created once.
+#[derive(Debug, Clone)]
+pub struct SharedResources {
+    pub client: ClientWithKeys,
+    pub cats: CatsState,
+    pub blobs: Arc<dyn BlobStore>,
+}
+
+/// What `PUBLIC_BASE_URL` is if it's not set, the address `cargo shuttle run` serves on.
+const DEFAULT_PUBLIC_BASE_URL: &str = "http://localhost:8000";
+
+pub async fn setup_shared_resources(secret_store: &SecretStore) -> Result<SharedResources, shuttle_runtime::Error> {
+    // `thecatapi` or `fixture`, the fixtures don't need an api key or network
+    let cat_source = secret_store.get("CAT_SOURCE").unwrap_or("thecatapi".into());
+    let cat_api_key = if cat_source == "fixture" {
+        secret_store.get("CAT_API_KEY").unwrap_or_default()
+    } else {
+        senv!(secret_store, CAT_API_KEY)
+    };
+    let cat_fixtures_dir = secret_store.get("CAT_FIXTURES_DIR").unwrap_or("assets/cats".into());
+    // where this server is reachable from outside, for links that leave it like the fixture images in discord embeds
+    let public_base_url = secret_store.get("PUBLIC_BASE_URL").unwrap_or(DEFAULT_PUBLIC_BASE_URL.into());
     let mongo_uri = senv!(secret_store, MONGO_URI);
-    // let database_url = senv!(secret_store, DATABASE_URL);
-    // let supabase_url = senv!(secret_store, SUPABASE_URL);
-    let neon_url = senv!(secret_store, NEON_URL);
     let bus_api_key = senv!(secret_store, BUS_API_KEY);
-    
-    info!("PLEASE???");
-    LazyLock::force(&ROUTES);
-
-    info!("starting connections");
+    // randomuser.me is only asked for names of countries missing from the bundled corpus, and only if this is on
+    let pet_names_remote_fallback = secret_store.get("PET_NAMES_REMOTE_FALLBACK").is_some_and(|v| v == "true");
+    // how many cats can be unboxed per day, by a logged in user and by an ip
+    let unboxes_per_user = parse_secret_or(secret_store, "UNBOXES_PER_USER", DEFAULT_UNBOXES_PER_USER)?;
+    let unboxes_per_ip = parse_secret_or(secret_store, "UNBOXES_PER_IP", DEFAULT_UNBOXES_PER_IP)?;
+    // how many proxies in front of the service append to X-Forwarded-For, 0 if there are none and the peer address is the client
+    let trusted_proxy_hops = secret_store.get("TRUSTED_PROXY_HOPS")
+        .map(|v| v.parse::<usize>().map_err(|_| shuttle_runtime::CustomError::msg(format!("TRUSTED_PROXY_HOPS has to be a number, got `{}`", v))))
+        .transpose()?
+        .unwrap_or(DEFAULT_TRUSTED_PROXY_HOPS);
+    // how many ready to serve cats to keep around, 0 turns prefetching off
+    let prefetch_pool_size = secret_store.get("PREFETCH_POOL_SIZE")
+        .map(|v| v.parse::<usize>().map_err(|_| shuttle_runtime::CustomError::msg(format!("PREFETCH_POOL_SIZE has to be a number, got `{}`", v))))
+        .transpose()?
+        .unwrap_or(DEFAULT_PREFETCH_POOL_SIZE);
+    let blob_store_dir = secret_store.get("BLOB_STORE_DIR").unwrap_or(DEFAULT_BLOB_STORE_DIR.into());
 
     let mongo_client = mongodb::Client::with_uri_str(mongo_uri).await
         .map_err(|e| shuttle_runtime::Error::Database(format!("could not connect to mongo: {}", e)))?;
     info!("connected to mongo");
     let mongo_db = mongo_client.database("unboxcat");
 
-    // let supabase_db = PgPool::connect(&database_url).await
-    //     .map_err(|e| shuttle_runtime::Error::Database(format!("could not connect to supabase: {}", e)))?;
-    // info!("connected to postgres");
-    // let supabase_storage = Storage::new("cenzo", &supabase_url);
-    // let supabase = Arc::new(SupabaseResources::new(supabase_db, supabase_storage));
-    // info!("created supabase");
+    let client = ClientWithKeys::new(cat_api_key, bus_api_key);
+    info!("created new reqwest client");
+
+    let rarities = RarityTable::load(&mongo_db).await
+        .map_err(shuttle_runtime::CustomError::new)?;
+    info!("loaded rarities");
+
+    let names = PetNameGenerator::from_file(PET_NAMES_FILE, pet_names_remote_fallback)
+        .map_err(shuttle_runtime::CustomError::new)?;
+
+    let source = cat_source_from_config(&cat_source, &cat_fixtures_dir, &public_base_url, client.clone()).await
+        .map_err(shuttle_runtime::CustomError::new)?;
+    info!("using the `{}` cat source", cat_source);
+
+    let limiter = UnboxLimiter::new(unboxes_per_user, unboxes_per_ip, trusted_proxy_hops);
+    info!("unbox limits: {}/day per user, {}/day per ip, behind {} proxies", unboxes_per_user, unboxes_per_ip, trusted_proxy_hops);
+
+    let pool = CatPool::new(prefetch_pool_size);
+    let blobs: Arc<dyn BlobStore> = Arc::new(LocalBlobStore::new(&blob_store_dir));
+
+    let cats = CatsState::new(&mongo_db, rarities, names, source, limiter, pool, blobs.clone(), client.clone());
+    prefetch::spawn_refill(cats.clone());
+
+    Ok(SharedResources { client, cats, blobs })
+}
+
+/// Reads an optional numeric secret, crashes the program if it's there but not a valid number.
+fn parse_secret_or(secret_store: &SecretStore, key: &str, default: u32) -> Result<u32, shuttle_runtime::Error> {
+    match secret_store.get(key) {
+        Some(value) => value.parse::<u32>()
+            .ok()
+            .filter(|n| *n > 0)
+            .ok_or_else(|| shuttle_runtime::CustomError::msg(format!("{} has to be a positive number, got `{}`", key, value)).into()),
+        None => Ok(default)
+    }
+}
+
+pub async fn setup_web_server(secret_store: &SecretStore, shared: SharedResources) -> Result<Router, shuttle_runtime::Error> {
+    let neon_url = senv!(secret_store, NEON_URL);
+    // the auth0 tenant exactly as in the `iss` of its tokens, e.g. `https://dev-fg28cspzvpoubaeb.us.auth0.com/`,
+    // and the comma separated apis the tokens can be for
+    let auth_issuer = senv!(secret_store, AUTH_ISSUER);
+    let auth_audiences = senv!(secret_store, AUTH_AUDIENCE)
+        .split(',')
+        .map(str::trim)
+        .filter(|aud| !aud.is_empty())
+        .map(String::from)
+        .collect::<Vec<_>>();
+    let auth_leeway_secs = parse_secret_or(secret_store, "AUTH_LEEWAY_SECS", DEFAULT_LEEWAY_SECS)?;
+    // `file://path/to/jwks.json` works too, for testing without auth0
+    let auth_jwks_url = secret_store.get("AUTH_JWKS_URL")
+        .unwrap_or_else(|| format!("{}/.well-known/jwks.json", auth_issuer.trim_end_matches('/')));
+    let auth_jwks_ttl_secs = parse_secret_or(secret_store, "AUTH_JWKS_TTL_SECS", DEFAULT_JWKS_TTL_SECS)?;
+    
+    info!("PLEASE???");
+    LazyLock::force(&ROUTES);
+
+    info!("starting connections");
 
     let neon_db = PgPool::connect(&neon_url).await
         .map_err(|e| shuttle_runtime::Error::Database(format!("could not connect to neon: {}", e)))?;
     info!("connected to neon");
 
-    let client = ClientWithKeys::new(cat_api_key, bus_api_key);
-    info!("created new reqwest client");
+    // the jp2 and tf2sc tables, the tf2sc base migration also works on top of the ones made by hand before there were migrations
+    sqlx::migrate!().run(&neon_db).await
+        .map_err(|e| shuttle_runtime::Error::Database(format!("could not run the neon migrations: {}", e)))?;
+    info!("ran the neon migrations");
+
+    let SharedResources { client, cats, blobs } = shared;
+
+    let jwks = JwksCache::new(jwks_source_from_config(&auth_jwks_url, client.clone()), Duration::from_secs(auth_jwks_ttl_secs.into()));
+    let issuer = IssuerConfig::new(&auth_issuer, auth_audiences).with_leeway_secs(auth_leeway_secs.into());
+    let auth = AuthLayer::new(&issuer, jwks);
+    info!("accepting tokens of {} for {:?}, keys from {} cached for {}s", issuer.issuer, issuer.audiences, auth_jwks_url, auth_jwks_ttl_secs);
+
+    let jp2 = Arc::new(Jp2Resources::new(neon_db.clone(), Storage::new(JP2_BUCKET, blobs)));
+    let jp2_router = self::jp2::routes(jp2, auth.clone())
+        .map_err(shuttle_runtime::CustomError::new)?;
+
+    let cats_router = self::cats::routes(cats, auth.clone()).await
+        .map_err(|e| shuttle_runtime::Error::Database(format!("could not create mongo indexes: {}", e)))?;
+
+    let docs_router = self::docs::routes()
+        .map_err(shuttle_runtime::CustomError::new)?;
 
     let router = Router::new()
-        .nest("/cats", self::cats::routes(mongo_db))
+        .merge(docs_router)
+        .nest("/cats", cats_router)
         .nest("/timetable", self::timetable::routes())
-        // .nest("/jp2", self::jp2::routes(supabase))
-        .nest("/tf2sc", self::tf2sc::routes(neon_db))
+        .nest("/jp2", jp2_router)
+        .nest("/tf2sc", self::tf2sc::routes(neon_db, auth))
         .nest("/bustimetravel", self::bustimetravel::routes(client.clone()))
         .layer(Extension(client))
+        .layer(from_fn(request_id::request_id_mw))
         .layer(CorsLayer::new()
             .allow_origin(cors::Any)
             .allow_methods(cors::Any)
             .allow_headers(cors::Any)
+            .expose_headers(cors::Any)
         );
 
         /*
@@ -121,41 +233,18 @@ impl ClientWithKeys {
 }
 
 
+/// Where the jp2 images are kept in the blob store.
+const JP2_BUCKET: &str = "jp2";
+
+/// The jp2 quotes and image metadata are in neon, the image files in the blob store.
 #[derive(Debug, Clone)]
-pub struct SupabaseResources {
+pub struct Jp2Resources {
     pub db: PgPool,
     pub storage: Storage,
 }
 
-impl SupabaseResources {
+impl Jp2Resources {
     pub fn new(db: PgPool, storage: Storage) -> Self {
         Self { db, storage }
     }
 }
-
-
-#[derive(Debug, Clone)]
-pub struct Storage {
-    pub bucket_id: String,
-    pub supabase_url: String,
-    // future props for api key etc
-}
-
-impl Storage {
-    pub fn new(bucket_id: &str, supabase_url: &str) -> Self {
-        Storage { bucket_id: bucket_id.to_string(), supabase_url: supabase_url.to_string() }
-    }
-}
-
-
-#[derive(Debug, sqlx::FromRow, Serialize, Deserialize, ToSchema)]
-pub struct Object {
-    name: String,
-    bucket_id: String
-}
-
-impl Object {
-    pub fn to_link(&self, storage_data: &Storage) -> String {
-        format!("{}/storage/v1/object/public/{}/{}", storage_data.supabase_url, storage_data.bucket_id, self.name)
-    }
-}
\ No newline at end of file
@@ -0,0 +1,418 @@
+//! A small `multipart/form-data` extractor, the whole body is buffered so it's only meant for uploads that fit in memory.
+//!
+//! axum's own `Multipart` needs the `multipart` feature (and multer), this only does what the upload endpoints need.
+//! The body is capped by the route's `DefaultBodyLimit`, boundaries by RFC 2046's 70 characters and the headers of each part
+//! by `MAX_PART_HEADERS_LEN`. Truncated bodies are errors and mangled ones never panic, see the tests.
+
+use async_trait::async_trait;
+use axum::{body::Bytes, extract::{FromRequest, Request}, http::{header, StatusCode}, response::IntoResponse};
+
+use super::error::{problem_response, ApiError, Problem};
+
+/// RFC 2046 caps boundaries at 70 characters.
+const MAX_BOUNDARY_LEN: usize = 70;
+/// The headers of a single part, a content disposition and type are well under this.
+const MAX_PART_HEADERS_LEN: usize = 8 * 1024;
+
+
+#[derive(Debug, thiserror::Error)]
+pub enum MultipartError {
+    #[error("Expected a multipart/form-data body with a boundary")]
+    NotMultipart,
+    #[error("The body is too large")]
+    TooLarge,
+    #[error("Malformed multipart body: {0}")]
+    Malformed(&'static str),
+    #[error("Could not read the body")]
+    BodyUnreadable,
+}
+
+impl ApiError for MultipartError {
+    fn problem(&self) -> Problem {
+        match self {
+            Self::NotMultipart => Problem::new(StatusCode::UNSUPPORTED_MEDIA_TYPE, "not_multipart", self),
+            Self::TooLarge => Problem::new(StatusCode::PAYLOAD_TOO_LARGE, "body_too_large", self),
+            Self::Malformed(_) => Problem::new(StatusCode::BAD_REQUEST, "malformed_multipart", self),
+            Self::BodyUnreadable => Problem::new(StatusCode::BAD_REQUEST, "body_unreadable", self),
+        }
+    }
+}
+
+impl IntoResponse for MultipartError {
+    fn into_response(self) -> axum::response::Response {
+        problem_response(&self)
+    }
+}
+
+/// A single part of the form, either a plain field or a file.
+#[derive(Debug, Clone)]
+pub struct FormField {
+    pub name: String,
+    pub file_name: Option<String>,
+    pub content_type: Option<String>,
+    pub bytes: Bytes
+}
+
+impl FormField {
+    pub fn text(&self) -> Option<&str> {
+        std::str::from_utf8(&self.bytes).ok()
+    }
+}
+
+/// All the parts of a `multipart/form-data` body, in order.
+///
+/// The body size is capped by the `DefaultBodyLimit` of the route, a bigger body is a 413 `body_too_large`.
+#[derive(Debug, Clone)]
+pub struct FormData {
+    fields: Vec<FormField>
+}
+
+impl FormData {
+    /// The first part with that name.
+    pub fn field(&self, name: &str) -> Option<&FormField> {
+        self.fields.iter().find(|f| f.name == name)
+    }
+
+    /// Every part with that name, for fields that can be repeated.
+    pub fn fields<'a>(&'a self, name: &'a str) -> impl Iterator<Item = &'a FormField> + 'a {
+        self.fields.iter().filter(move |f| f.name == name)
+    }
+}
+
+#[async_trait]
+impl<S> FromRequest<S> for FormData
+where
+    S: Send + Sync,
+{
+    type Rejection = MultipartError;
+
+    async fn from_request(req: Request, state: &S) -> Result<Self, Self::Rejection> {
+        let boundary = req.headers()
+            .get(header::CONTENT_TYPE)
+            .and_then(|v| v.to_str().ok())
+            .and_then(boundary)
+            .ok_or(MultipartError::NotMultipart)?;
+
+        let body = Bytes::from_request(req, state).await
+            .map_err(|rejection| match rejection.status() {
+                StatusCode::PAYLOAD_TOO_LARGE => MultipartError::TooLarge,
+                _ => MultipartError::BodyUnreadable
+            })?;
+
+        let fields = parse(&body, &boundary)?;
+
+        Ok(Self { fields })
+    }
+}
+
+/// `multipart/form-data; boundary=abc` -> `abc`, quoted or not.
+///
+/// Boundaries that are too long or would span lines can't delimit anything and are refused.
+fn boundary(content_type: &str) -> Option<String> {
+    let mut params = split_params(content_type).into_iter().map(str::trim);
+
+    if !params.next()?.eq_ignore_ascii_case("multipart/form-data") {
+        return None;
+    }
+
+    params
+        .filter_map(|p| p.split_once('='))
+        .find(|(key, _)| key.trim().eq_ignore_ascii_case("boundary"))
+        .map(|(_, value)| unquote(value.trim()))
+        .filter(|b| !b.is_empty() && b.len() <= MAX_BOUNDARY_LEN && !b.contains(['\r', '\n']))
+}
+
+fn parse(body: &Bytes, boundary: &str) -> Result<Vec<FormField>, MultipartError> {
+    let delimiter = format!("--{}", boundary);
+    let part_delimiter = format!("\r\n--{}", boundary);
+
+    // anything before the first delimiter is a preamble and gets ignored
+    let start = find(body, delimiter.as_bytes(), 0).ok_or(MultipartError::Malformed("no opening boundary"))?;
+    let mut at = start + delimiter.len();
+    let mut fields = Vec::new();
+
+    loop {
+        if body[at..].starts_with(b"--") {
+            return Ok(fields);
+        }
+        if at == body.len() {
+            return Err(MultipartError::Malformed("no closing boundary"));
+        }
+        at += body.get(at..).filter(|rest| rest.starts_with(b"\r\n")).map(|_| 2)
+            .ok_or(MultipartError::Malformed("expected a line break after the boundary"))?;
+
+        // only looked for within the cap, so a body of one endless header isn't scanned to its end for every part
+        let headers_end = match find(&body[..body.len().min(at + MAX_PART_HEADERS_LEN + 4)], b"\r\n\r\n", at) {
+            Some(end) => end,
+            None if body.len() - at > MAX_PART_HEADERS_LEN => return Err(MultipartError::Malformed("part headers are too long")),
+            None => return Err(MultipartError::Malformed("part headers are not terminated"))
+        };
+        let headers = std::str::from_utf8(&body[at..headers_end]).map_err(|_| MultipartError::Malformed("part headers are not utf8"))?;
+
+        let content_start = headers_end + 4;
+        let content_end = find(body, part_delimiter.as_bytes(), content_start).ok_or(MultipartError::Malformed("no closing boundary"))?;
+
+        fields.push(field(headers, body.slice(content_start..content_end))?);
+
+        at = content_end + part_delimiter.len();
+    }
+}
+
+fn field(headers: &str, bytes: Bytes) -> Result<FormField, MultipartError> {
+    let mut name = None;
+    let mut file_name = None;
+    let mut content_type = None;
+
+    for line in headers.split("\r\n") {
+        let Some((key, value)) = line.split_once(':') else { continue };
+
+        if key.trim().eq_ignore_ascii_case("content-disposition") {
+            for param in split_params(value).into_iter().skip(1) {
+                let Some((key, value)) = param.split_once('=') else { continue };
+
+                match key.trim().to_ascii_lowercase().as_str() {
+                    "name" => name = Some(unquote(value.trim())),
+                    "filename" => file_name = Some(unquote(value.trim())),
+                    _ => {}
+                }
+            }
+        } else if key.trim().eq_ignore_ascii_case("content-type") {
+            content_type = Some(value.trim().to_string());
+        }
+    }
+
+    let name = name.ok_or(MultipartError::Malformed("a part has no name"))?;
+
+    Ok(FormField { name, file_name, content_type, bytes })
+}
+
+/// Splits a header value at its `;`s, except for the ones in quoted strings (`filename="a;b.png"`).
+fn split_params(value: &str) -> Vec<&str> {
+    let mut params = Vec::new();
+    let mut start = 0;
+    let mut quoted = false;
+    let mut escaped = false;
+
+    for (i, c) in value.char_indices() {
+        match c {
+            _ if escaped => escaped = false,
+            '\\' if quoted => escaped = true,
+            '"' => quoted = !quoted,
+            ';' if !quoted => {
+                params.push(&value[start..i]);
+                start = i + 1;
+            },
+            _ => {}
+        }
+    }
+    params.push(&value[start..]);
+
+    params
+}
+
+/// `"a \"b\""` -> `a "b"`, values that aren't quoted are kept as they are.
+fn unquote(value: &str) -> String {
+    let Some(inner) = value.strip_prefix('"').and_then(|v| v.strip_suffix('"')) else {
+        return value.to_string();
+    };
+
+    let mut unquoted = String::with_capacity(inner.len());
+    let mut chars = inner.chars();
+    while let Some(c) = chars.next() {
+        match c {
+            '\\' => unquoted.extend(chars.next()),
+            c => unquoted.push(c)
+        }
+    }
+
+    unquoted
+}
+
+fn find(haystack: &[u8], needle: &[u8], from: usize) -> Option<usize> {
+    haystack.get(from..)?
+        .windows(needle.len())
+        .position(|w| w == needle)
+        .map(|i| from + i)
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const BOUNDARY: &str = "xYzZY";
+
+    fn parsed(body: &'static str) -> Result<Vec<FormField>, MultipartError> {
+        parse(&Bytes::from_static(body.as_bytes()), BOUNDARY)
+    }
+
+    fn malformed(body: &'static str) -> &'static str {
+        match parsed(body) {
+            Err(MultipartError::Malformed(reason)) => reason,
+            other => panic!("expected a malformed body, got {:?}", other)
+        }
+    }
+
+    #[test]
+    fn boundaries_are_read_from_the_content_type() {
+        assert_eq!(boundary("multipart/form-data; boundary=abc").as_deref(), Some("abc"));
+        assert_eq!(boundary("Multipart/Form-Data;BOUNDARY=abc;charset=utf-8").as_deref(), Some("abc"));
+        assert_eq!(boundary(r#"multipart/form-data; boundary="a;b c""#).as_deref(), Some("a;b c"));
+
+        assert_eq!(boundary("multipart/form-data"), None);
+        assert_eq!(boundary("multipart/form-data; boundary="), None);
+        assert_eq!(boundary(r#"multipart/form-data; boundary="""#), None);
+        assert_eq!(boundary("multipart/mixed; boundary=abc"), None);
+        assert_eq!(boundary("application/json"), None);
+    }
+
+    #[test]
+    fn parts_are_parsed_in_order() {
+        let fields = parsed(concat!(
+            "preamble\r\n",
+            "--xYzZY\r\n",
+            "Content-Disposition: form-data; name=\"tags\"\r\n",
+            "\r\n",
+            "cats,dogs\r\n",
+            "--xYzZY\r\n",
+            "content-disposition: form-data; name=\"file\"; filename=\"cat.png\"\r\n",
+            "Content-Type: image/png\r\n",
+            "\r\n",
+            "PNG\r\n--not the boundary\r\n",
+            "--xYzZY--\r\n",
+            "epilogue"
+        )).unwrap();
+
+        assert_eq!(fields.len(), 2);
+        assert_eq!((fields[0].name.as_str(), fields[0].text()), ("tags", Some("cats,dogs")));
+        assert_eq!(fields[0].file_name, None);
+
+        assert_eq!(fields[1].name, "file");
+        assert_eq!(fields[1].file_name.as_deref(), Some("cat.png"));
+        assert_eq!(fields[1].content_type.as_deref(), Some("image/png"));
+        assert_eq!(&fields[1].bytes[..], b"PNG\r\n--not the boundary");
+    }
+
+    #[test]
+    fn quoted_params_can_hold_separators_and_escapes() {
+        let fields = parsed(concat!(
+            "--xYzZY\r\n",
+            "Content-Disposition: form-data; filename=\"a; name=b \\\"c\\\".png\"; NAME=file\r\n",
+            "\r\n",
+            "\r\n",
+            "--xYzZY--"
+        )).unwrap();
+
+        assert_eq!(fields[0].name, "file");
+        assert_eq!(fields[0].file_name.as_deref(), Some(r#"a; name=b "c".png"#));
+        assert!(fields[0].bytes.is_empty());
+    }
+
+    #[test]
+    fn an_empty_form_has_no_parts() {
+        assert!(parsed("--xYzZY--").unwrap().is_empty());
+    }
+
+    #[test]
+    fn a_body_without_the_boundary_is_malformed() {
+        assert_eq!(malformed(""), "no opening boundary");
+        assert_eq!(malformed("--other\r\n\r\n\r\n--other--"), "no opening boundary");
+        assert_eq!(malformed("--xYzZYjunk\r\n\r\n\r\n--xYzZY--"), "expected a line break after the boundary");
+    }
+
+    #[test]
+    fn a_missing_closing_boundary_is_malformed() {
+        assert_eq!(malformed("--xYzZY\r\nContent-Disposition: form-data; name=\"a\"\r\n\r\nvalue"), "no closing boundary");
+        assert_eq!(malformed("--xYzZY\r\nContent-Disposition: form-data; name=\"a\"\r\n\r\nvalue\r\n--xYzZY"), "no closing boundary");
+        assert_eq!(malformed("--xYzZY"), "no closing boundary");
+    }
+
+    #[test]
+    fn truncated_headers_are_malformed() {
+        assert_eq!(malformed("--xYzZY\r\nContent-Disposition: form-data; name=\"a\"\r\n"), "part headers are not terminated");
+        assert_eq!(malformed("--xYzZY\r\nContent-Disposition: form-da"), "part headers are not terminated");
+    }
+
+    #[test]
+    fn a_part_without_a_name_is_malformed() {
+        assert_eq!(malformed("--xYzZY\r\nContent-Disposition: form-data; filename=\"a.png\"\r\n\r\n\r\n--xYzZY--"), "a part has no name");
+        assert_eq!(malformed("--xYzZY\r\nContent-Type: text/plain\r\n\r\n\r\n--xYzZY--"), "a part has no name");
+    }
+
+    #[test]
+    fn oversized_part_headers_are_malformed() {
+        let header = format!("--xYzZY\r\nContent-Disposition: form-data; name=\"a\"; filename=\"{}\"", "a".repeat(MAX_PART_HEADERS_LEN));
+
+        let unterminated = Bytes::from(header.clone());
+        assert!(matches!(parse(&unterminated, BOUNDARY), Err(MultipartError::Malformed("part headers are too long"))));
+
+        let terminated = Bytes::from(format!("{}\r\n\r\n\r\n--xYzZY--", header));
+        assert!(matches!(parse(&terminated, BOUNDARY), Err(MultipartError::Malformed("part headers are too long"))));
+
+        // right at the cap is still fine
+        let name = "a".repeat(MAX_PART_HEADERS_LEN - "Content-Disposition: form-data; name=\"\"".len());
+        let at_the_cap = Bytes::from(format!("--xYzZY\r\nContent-Disposition: form-data; name=\"{}\"\r\n\r\n\r\n--xYzZY--", name));
+        assert_eq!(parse(&at_the_cap, BOUNDARY).unwrap()[0].name, name);
+    }
+
+    #[test]
+    fn long_or_multiline_boundaries_are_refused() {
+        let longest = "b".repeat(MAX_BOUNDARY_LEN);
+        assert_eq!(boundary(&format!("multipart/form-data; boundary={}", longest)), Some(longest.clone()));
+        assert_eq!(boundary(&format!("multipart/form-data; boundary={}b", longest)), None);
+        assert_eq!(boundary("multipart/form-data; boundary=\"a\r\nb\""), None);
+    }
+
+    const VALID: &str = concat!(
+        "--xYzZY\r\n",
+        "Content-Disposition: form-data; name=\"tags\"\r\n",
+        "\r\n",
+        "cats,dogs\r\n",
+        "--xYzZY\r\n",
+        "Content-Disposition: form-data; name=\"file\"; filename=\"cat.png\"\r\n",
+        "Content-Type: image/png\r\n",
+        "\r\n",
+        "PNG\r\n",
+        "--xYzZY--"
+    );
+
+    #[test]
+    fn every_truncation_of_a_body_is_malformed() {
+        // the last 2 bytes are the `--` that close the form, without them it's not over yet
+        for len in 0..VALID.len() - 1 {
+            let body = Bytes::from_static(&VALID.as_bytes()[..len]);
+            assert!(matches!(parse(&body, BOUNDARY), Err(MultipartError::Malformed(_))), "a body cut at {} was accepted", len);
+        }
+
+        assert_eq!(parse(&Bytes::from_static(VALID.as_bytes()), BOUNDARY).unwrap().len(), 2);
+    }
+
+    #[test]
+    fn mangled_bodies_never_panic() {
+        use rand::{rngs::StdRng, Rng, SeedableRng};
+
+        // the bits most likely to confuse the parser, spliced in at random
+        const PIECES: [&[u8]; 7] = [b"\r\n", b"\r\n\r\n", b"--", b"--xYzZY", b"\r\n--xYzZY", b"\"", b"; name="];
+
+        let mut rng = StdRng::seed_from_u64(2046);
+        for _ in 0..5_000 {
+            let mut body = VALID.as_bytes().to_vec();
+
+            for _ in 0..rng.gen_range(1..=4) {
+                let at = rng.gen_range(0..=body.len());
+                match rng.gen_range(0..4) {
+                    0 => body.truncate(at),
+                    1 => { body.drain(at..body.len().min(at + rng.gen_range(1..8))); },
+                    2 => { body.splice(at..at, PIECES[rng.gen_range(0..PIECES.len())].iter().copied()); },
+                    _ => if let Some(byte) = body.get_mut(at) { *byte = rng.gen() }
+                }
+            }
+
+            // any answer goes as long as it's not a panic, the parts can only ever be pieces of the body
+            let len = body.len();
+            if let Ok(fields) = parse(&Bytes::from(body), BOUNDARY) {
+                assert!(fields.iter().map(|f| f.bytes.len()).sum::<usize>() <= len);
+            }
+        }
+    }
+}
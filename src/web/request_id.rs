@@ -0,0 +1,39 @@
+use axum::{extract::Request, http::HeaderValue, middleware::Next, response::Response};
+
+pub const REQUEST_ID_HEADER: &str = "X-Request-Id";
+
+/// The request that is being handled, for the error responses.
+#[derive(Debug, Clone)]
+pub struct RequestContext {
+    pub id: String,
+    pub path: String
+}
+
+tokio::task_local! {
+    static REQUEST: RequestContext;
+}
+
+/// The request being handled by the current task, `None` outside of `request_id_mw` (e.g. in the discord bot or spawned tasks).
+pub fn current() -> Option<RequestContext> {
+    REQUEST.try_with(|request| request.clone()).ok()
+}
+
+/// Gives every request an id, the incoming `X-Request-Id` if the client or a proxy set a sane one.
+/// The id is sent back in the same header, and is in every error response.
+pub async fn request_id_mw(req: Request, next: Next) -> Response {
+    let id = req.headers()
+        .get(REQUEST_ID_HEADER)
+        .and_then(|v| v.to_str().ok())
+        .filter(|id| !id.is_empty() && id.len() <= 64 && id.chars().all(|c| c.is_ascii_alphanumeric() || c == '-'))
+        .map(|id| id.to_string())
+        .unwrap_or_else(|| format!("{:032x}", rand::random::<u128>()));
+
+    let request = RequestContext { id: id.clone(), path: req.uri().path().to_string() };
+    let mut res = REQUEST.scope(request, next.run(req)).await;
+
+    if let Ok(value) = HeaderValue::from_str(&id) {
+        res.headers_mut().insert(REQUEST_ID_HEADER, value);
+    }
+
+    res
+}
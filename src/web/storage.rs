@@ -0,0 +1,116 @@
+use std::{path::{Component, Path, PathBuf}, sync::Arc};
+use async_trait::async_trait;
+use tracing::info;
+
+/// Where blobs are stored if `BLOB_STORE_DIR` is not set.
+pub const DEFAULT_BLOB_STORE_DIR: &str = "data/blobs";
+
+/// A stored file and its content type.
+#[derive(Debug, Clone)]
+pub struct Blob {
+    pub bytes: Vec<u8>,
+    pub content_type: String
+}
+
+#[derive(Debug, thiserror::Error)]
+pub enum StorageError {
+    #[error("Invalid blob key `{key}`")]
+    InvalidKey { key: String },
+    #[error("Blob store io error: {0}")]
+    IoError(#[from] std::io::Error),
+}
+
+/// Somewhere to keep files that have to outlive the apis they came from, keyed by `/` separated paths like `cats/abc/original.jpg`.
+///
+/// Only the local filesystem for now, supabase storage would be another implementation.
+#[async_trait]
+pub trait BlobStore: Send + Sync + std::fmt::Debug {
+    async fn put(&self, key: &str, blob: Blob) -> Result<(), StorageError>;
+
+    async fn get(&self, key: &str) -> Result<Option<Blob>, StorageError>;
+}
+
+/// Stores each blob as a file under `root`, with the content type in a `.type` file next to it.
+#[derive(Debug, Clone)]
+pub struct LocalBlobStore {
+    root: PathBuf
+}
+
+impl LocalBlobStore {
+    pub fn new(root: &str) -> Self {
+        info!("storing blobs in {}", root);
+        Self { root: PathBuf::from(root) }
+    }
+
+    /// Only plain relative keys are allowed, so that nothing outside of `root` can be read or written.
+    fn path_for(&self, key: &str) -> Result<PathBuf, StorageError> {
+        let relative = Path::new(key);
+        let is_plain = !key.is_empty() && relative.components().all(|c| matches!(c, Component::Normal(_)));
+
+        if !is_plain {
+            return Err(StorageError::InvalidKey { key: key.to_string() });
+        }
+
+        Ok(self.root.join(relative))
+    }
+}
+
+#[async_trait]
+impl BlobStore for LocalBlobStore {
+    async fn put(&self, key: &str, blob: Blob) -> Result<(), StorageError> {
+        let path = self.path_for(key)?;
+        if let Some(parent) = path.parent() {
+            tokio::fs::create_dir_all(parent).await?;
+        }
+
+        tokio::fs::write(&path, &blob.bytes).await?;
+        tokio::fs::write(type_path(&path), &blob.content_type).await?;
+
+        Ok(())
+    }
+
+    async fn get(&self, key: &str) -> Result<Option<Blob>, StorageError> {
+        let path = self.path_for(key)?;
+
+        let bytes = match tokio::fs::read(&path).await {
+            Ok(bytes) => bytes,
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => return Ok(None),
+            Err(e) => return Err(e.into())
+        };
+        let content_type = tokio::fs::read_to_string(type_path(&path)).await
+            .unwrap_or("application/octet-stream".into());
+
+        Ok(Some(Blob { bytes, content_type }))
+    }
+}
+
+/// A bucket of a blob store, which is just a prefix of the keys, e.g. `jp2/papaspin.gif`.
+#[derive(Debug, Clone)]
+pub struct Storage {
+    pub bucket_id: String,
+    blobs: Arc<dyn BlobStore>
+}
+
+impl Storage {
+    pub fn new(bucket_id: &str, blobs: Arc<dyn BlobStore>) -> Self {
+        Self { bucket_id: bucket_id.to_string(), blobs }
+    }
+
+    pub async fn put(&self, name: &str, blob: Blob) -> Result<(), StorageError> {
+        self.blobs.put(&self.key(name), blob).await
+    }
+
+    pub async fn get(&self, name: &str) -> Result<Option<Blob>, StorageError> {
+        self.blobs.get(&self.key(name)).await
+    }
+
+    fn key(&self, name: &str) -> String {
+        format!("{}/{}", self.bucket_id, name)
+    }
+}
+
+fn type_path(path: &Path) -> PathBuf {
+    let mut type_path = path.as_os_str().to_owned();
+    type_path.push(".type");
+    PathBuf::from(type_path)
+}
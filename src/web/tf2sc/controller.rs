@@ -1,47 +1,65 @@
-use axum::{extract::{Path, Query, State}, response::IntoResponse, Json};
+use std::collections::HashMap;
+use axum::{extract::State, response::IntoResponse};
+use crate::web::extract::{Json, Path, Query};
 use serde::{Deserialize, Deserializer};
-use sqlx::{types::Uuid, PgPool};
-use strum_macros::{AsRefStr, EnumString};
+use sqlx::{types::Uuid, FromRow, PgExecutor, PgPool, Postgres, Transaction};
 use std::str::FromStr;
 use serde::de;
-use validator::Validate;
-use super::{auth::AuthUser, model::{FullLoadout, ItemSlot, Loadout, LoadoutForCreate, LoadoutForUpdate, Merc, MongoStyle, WeaponFromView}};
+use utoipa::IntoParams;
+use validator::{Validate, ValidationError, ValidationErrors};
 
-#[derive(Deserialize)]
+use crate::web::auth::AuthUser;
+
+use super::model::{FullLoadout, ItemSlot, Loadout, LoadoutForCreate, LoadoutForHide, LoadoutForUpdate, Merc, MongoStyle, MongoStyleWeapon, Page, WeaponForCreate, WeaponForUpdate, WeaponFromView};
+use super::query::{LoadoutCursor, LoadoutFilter, LoadoutQuery, Sort, SortBy, DEFAULT_PAGE_SIZE, MAX_PAGE_SIZE};
+
+#[derive(Deserialize, IntoParams)]
+#[into_params(parameter_in = Query)]
 pub struct MercSlotParams {
     merc: Option<Merc>,
     slot: Option<ItemSlot>
 }
 
-#[derive(Deserialize)]
+/// Query params of `GET /loadouts`.
+#[derive(Deserialize, IntoParams)]
+#[serde(rename_all = "camelCase")]
+#[into_params(parameter_in = Query)]
 pub struct LoadoutParams {
+    #[param(inline)]
     sort: Option<Sort>,
-    #[serde(rename = "sortBy")]
-    sort_by: Option<SortBy>
-}
-
-#[derive(Deserialize, EnumString, AsRefStr, Default)]
-#[serde(rename_all = "lowercase")]
-enum Sort {
-    #[default]
-    #[strum(serialize = "DESC")]
-    Desc,
-    #[strum(serialize = "ASC")]
-    Asc
+    /// `created_at` by default. Paging by `updated_at` can skip or repeat loadouts that are edited in the meantime.
+    #[param(inline)]
+    sort_by: Option<SortBy>,
+    merc: Option<Merc>,
+    user_id: Option<String>,
+    /// A weapon id, the loadouts that use it in any slot.
+    weapon: Option<i32>,
+    #[param(value_type = Option<String>, format = DateTime)]
+    created_after: Option<chrono::DateTime<chrono::Utc>>,
+    #[param(value_type = Option<String>, format = DateTime)]
+    created_before: Option<chrono::DateTime<chrono::Utc>>,
+    /// Searches the name and playstyle, `"quoted words"`, `or` and `-excluded` work like in a search engine.
+    q: Option<String>,
+    /// 20 by default, at most 100.
+    limit: Option<i64>,
+    /// The `nextCursor` of the previous page, with the same `sort` and `sortBy`.
+    cursor: Option<String>
 }
 
-#[derive(Deserialize, EnumString, AsRefStr, Default)]
-#[serde(rename_all = "lowercase")]
-enum SortBy {
-    #[strum(serialize = "created_at")]
-    Created,
-    #[default]
-    #[strum(serialize = "updated_at")]
-    Updated
+impl LoadoutParams {
+    fn filter(&self) -> LoadoutFilter {
+        LoadoutFilter {
+            merc: self.merc.clone(),
+            user_id: self.user_id.clone(),
+            weapon: self.weapon,
+            created_after: self.created_after,
+            created_before: self.created_before,
+            search: self.q.as_deref().map(str::trim).filter(|q| !q.is_empty()).map(String::from)
+        }
+    }
 }
 
 
-
 // might use it in the future
 #[allow(unused)]
 fn case_insensitive_option_merc<'de, D>(deserializer: D) -> Result<Option<Merc>, D::Error>
@@ -54,6 +72,15 @@ where
 }
 
 
+#[utoipa::path(
+    get,
+    path = "/weapons",
+    params(MercSlotParams),
+    responses(
+        (status = 200, description = "All weapons, optionally only the ones of a merc and/or slot", body = [MongoStyleWeapon])
+    ),
+    tag = "tf2sc"
+)]
 pub async fn get_all_weapons(State(db): State<PgPool>, Query(q): Query<MercSlotParams>) -> Result<impl IntoResponse, super::Error> {
     let MercSlotParams { 
         merc, 
@@ -85,6 +112,17 @@ pub async fn get_all_weapons(State(db): State<PgPool>, Query(q): Query<MercSlotP
     Ok(Json(weapons))
 }
 
+#[utoipa::path(
+    get,
+    path = "/weapons/{id}",
+    params(("id" = i32, Path, description = "Weapon id")),
+    responses(
+        (status = 200, description = "A single weapon", body = WeaponFromView),
+        (status = 400, description = "Invalid weapon id", body = Problem, content_type = "application/problem+json"),
+        (status = 404, description = "Weapon not found", body = Problem, content_type = "application/problem+json")
+    ),
+    tag = "tf2sc"
+)]
 pub async fn get_weapon(Path(id): Path<String>, State(db): State<PgPool>) -> Result<impl IntoResponse, super::Error> {
     let id = id.parse::<i32>()
         .map_err(|_| super::Error::InvalidWeaponId)?;
@@ -98,30 +136,66 @@ pub async fn get_weapon(Path(id): Path<String>, State(db): State<PgPool>) -> Res
     Ok(Json(weapon))
 }
 
+#[utoipa::path(
+    get,
+    path = "/loadouts",
+    params(LoadoutParams),
+    responses(
+        (status = 200, description = "A page of the loadouts that aren't hidden, with their weapons", body = LoadoutPage),
+        (status = 400, description = "Invalid cursor or query param", body = Problem, content_type = "application/problem+json")
+    ),
+    tag = "tf2sc"
+)]
 pub async fn get_all_loadouts(State(db): State<PgPool>, Query(q): Query<LoadoutParams>) -> Result<impl IntoResponse, super::Error> {
-    let LoadoutParams { 
-        sort, 
-        sort_by
-    }  = q;
+    let limit = q.limit.unwrap_or(DEFAULT_PAGE_SIZE).clamp(1, MAX_PAGE_SIZE);
+    let sort_by = q.sort_by.unwrap_or_default();
+    let sort = q.sort.unwrap_or_default();
+    let filter = q.filter();
 
-    let query = format!(
-        "SELECT * FROM full_loadouts ORDER BY {} {}",
-        sort_by.unwrap_or_default().as_ref(),
-        sort.unwrap_or_default().as_ref()
-    );
+    let total = LoadoutQuery::count(&filter)
+        .build_query_scalar::<i64>()
+        .fetch_one(&db)
+        .await?;
 
-    let loadouts = sqlx::query_as::<_, FullLoadout>(&query)
+    let mut query = LoadoutQuery::select(&filter);
+    if let Some(cursor) = &q.cursor {
+        query = query.after(&LoadoutCursor::decode(cursor, sort_by, sort)?);
+    }
+
+    // 1 extra to know if there's a next page
+    let mut items = query
+        .order_by(sort_by, sort)
+        .limit(limit + 1)
+        .build_query_as::<FullLoadout>()
         .fetch_all(&db)
         .await?;
 
-    Ok(Json(loadouts))
+    let next_cursor = if items.len() as i64 > limit {
+        items.truncate(limit as usize);
+        items.last().map(|last| LoadoutCursor::after(last, sort_by, sort).encode())
+    } else {
+        None
+    };
+
+    Ok(Json(Page { items, total: total as u64, next_cursor }))
 }
 
+#[utoipa::path(
+    get,
+    path = "/loadouts/{id}",
+    params(("id" = String, Path, description = "Loadout id, a uuid")),
+    responses(
+        (status = 200, description = "A single loadout, with its weapons", body = FullLoadout),
+        (status = 400, description = "Invalid loadout id", body = Problem, content_type = "application/problem+json"),
+        (status = 404, description = "Loadout not found or hidden", body = Problem, content_type = "application/problem+json")
+    ),
+    tag = "tf2sc"
+)]
 pub async fn get_loadout(Path(id): Path<String>, State(db): State<PgPool>) -> Result<impl IntoResponse, super::Error> {
     let id = id.parse::<Uuid>()
         .map_err(|_| super::Error::InvalidLoadoutId)?;
 
-    let loadout = sqlx::query_as::<_, FullLoadout>("SELECT * FROM full_loadouts WHERE id = $1")
+    let loadout = sqlx::query_as::<_, FullLoadout>("SELECT * FROM full_loadouts WHERE id = $1 AND NOT hidden")
         .bind(id)
         .fetch_optional(&db)
         .await?
@@ -130,11 +204,25 @@ pub async fn get_loadout(Path(id): Path<String>, State(db): State<PgPool>) -> Re
     Ok(Json(loadout))
 }
 
+#[utoipa::path(
+    post,
+    path = "/loadouts",
+    request_body = LoadoutForCreate,
+    responses(
+        (status = 200, description = "The created loadout", body = Loadout),
+        (status = 400, description = "Validation error", body = Problem, content_type = "application/problem+json"),
+        (status = 401, description = "Missing or invalid token", body = Problem, content_type = "application/problem+json"),
+        (status = 422, description = "A weapon doesn't exist, isn't usable by the merc or is in another slot, per field", body = Problem, content_type = "application/problem+json")
+    ),
+    security(("bearer_auth" = [])),
+    tag = "tf2sc"
+)]
 pub async fn create_loadout(State(db): State<PgPool>, auth_user: AuthUser, Json(loadout): Json<LoadoutForCreate>) -> Result<impl IntoResponse, super::Error> {
     loadout.validate()?;
-    
+    check_loadout_weapons(&db, &loadout.merc, loadout.primary, loadout.secondary, loadout.melee).await?;
+
     let created = sqlx::query_as::<_, Loadout>("INSERT INTO loadouts (user_id, merc, \"primary\", secondary, melee, name, playstyle) VALUES ($1, $2, $3, $4, $5, $6, $7) RETURNING *")
-        .bind(&auth_user.user_id)
+        .bind(&auth_user.subject)
         .bind(loadout.merc)
         .bind(loadout.primary)
         .bind(loadout.secondary)
@@ -142,11 +230,26 @@ pub async fn create_loadout(State(db): State<PgPool>, auth_user: AuthUser, Json(
         .bind(&loadout.name)
         .bind(&loadout.playstyle)
         .fetch_one(&db)
-        .await?;
+        .await
+        .map_err(loadout_weapons_error)?;
 
     Ok(Json(created))
 }
 
+#[utoipa::path(
+    delete,
+    path = "/loadouts/{id}",
+    params(("id" = String, Path, description = "Loadout id, a uuid")),
+    responses(
+        (status = 200, description = "The deleted loadout", body = Loadout),
+        (status = 400, description = "Invalid loadout id", body = Problem, content_type = "application/problem+json"),
+        (status = 401, description = "Missing or invalid token", body = Problem, content_type = "application/problem+json"),
+        (status = 403, description = "Not the owner of the loadout (without `moderate:loadouts`)", body = Problem, content_type = "application/problem+json"),
+        (status = 404, description = "Loadout not found", body = Problem, content_type = "application/problem+json")
+    ),
+    security(("bearer_auth" = [])),
+    tag = "tf2sc"
+)]
 pub async fn delete_loadout(Path(id): Path<String>, State(db): State<PgPool>) -> Result<impl IntoResponse, super::Error> {
     let id = id.parse::<Uuid>()
         .map_err(|_| super::Error::InvalidLoadoutId)?;
@@ -160,12 +263,45 @@ pub async fn delete_loadout(Path(id): Path<String>, State(db): State<PgPool>) ->
     Ok(Json(deleted_loadout))
 }
 
+#[utoipa::path(
+    put,
+    path = "/loadouts/{id}",
+    request_body = LoadoutForUpdate,
+    params(("id" = String, Path, description = "Loadout id, a uuid")),
+    responses(
+        (status = 200, description = "The updated loadout", body = Loadout),
+        (status = 400, description = "Invalid loadout id or validation error", body = Problem, content_type = "application/problem+json"),
+        (status = 401, description = "Missing or invalid token", body = Problem, content_type = "application/problem+json"),
+        (status = 403, description = "Not the owner of the loadout (without `moderate:loadouts`)", body = Problem, content_type = "application/problem+json"),
+        (status = 404, description = "Loadout not found", body = Problem, content_type = "application/problem+json"),
+        (status = 422, description = "A weapon doesn't exist, isn't usable by the merc or is in another slot, per field", body = Problem, content_type = "application/problem+json")
+    ),
+    security(("bearer_auth" = [])),
+    tag = "tf2sc"
+)]
 pub async fn update_loadout(Path(id): Path<String>, State(db): State<PgPool>, Json(loadout): Json<LoadoutForUpdate>) -> Result<impl IntoResponse, super::Error> {
     let id = id.parse::<Uuid>()
         .map_err(|_| super::Error::InvalidLoadoutId)?;
 
     loadout.validate()?;
-    
+
+    let mut tx = db.begin().await?;
+
+    // the weapons that are kept have to fit the new merc too
+    let current = sqlx::query_as::<_, Loadout>("SELECT * FROM loadouts WHERE id = $1 FOR UPDATE")
+        .bind(id)
+        .fetch_optional(&mut *tx)
+        .await?
+        .ok_or(super::Error::LoadoutNotFound { id })?;
+
+    check_loadout_weapons(
+        &mut *tx,
+        &loadout.merc,
+        loadout.primary.unwrap_or(current.primary),
+        loadout.secondary.unwrap_or(current.secondary),
+        loadout.melee.unwrap_or(current.melee)
+    ).await?;
+
     let query = r#"
         UPDATE loadouts
         SET 
@@ -187,9 +323,346 @@ pub async fn update_loadout(Path(id): Path<String>, State(db): State<PgPool>, Js
         .bind(loadout.name)
         .bind(loadout.playstyle)
         .bind(id)
+        .fetch_one(&mut *tx)
+        .await
+        .map_err(loadout_weapons_error)?;
+    tx.commit().await?;
+
+    Ok(Json(updated_loadout))
+}
+#[utoipa::path(
+    get,
+    path = "/loadouts/hidden",
+    responses(
+        (status = 200, description = "The hidden loadouts, most recently updated first", body = [FullLoadout]),
+        (status = 401, description = "Missing or invalid token", body = Problem, content_type = "application/problem+json"),
+        (status = 403, description = "Missing the `moderate:loadouts` permission", body = Problem, content_type = "application/problem+json")
+    ),
+    security(("bearer_auth" = [])),
+    tag = "tf2sc"
+)]
+pub async fn get_hidden_loadouts(State(db): State<PgPool>) -> Result<impl IntoResponse, super::Error> {
+    let loadouts = sqlx::query_as::<_, FullLoadout>("SELECT * FROM full_loadouts WHERE hidden ORDER BY updated_at DESC")
+        .fetch_all(&db)
+        .await?;
+
+    Ok(Json(loadouts))
+}
+
+#[utoipa::path(
+    post,
+    path = "/loadouts/{id}/hide",
+    request_body = LoadoutForHide,
+    params(("id" = String, Path, description = "Loadout id, a uuid")),
+    responses(
+        (status = 200, description = "The hidden loadout, it's gone from the public lists", body = Loadout),
+        (status = 400, description = "Invalid loadout id or validation error", body = Problem, content_type = "application/problem+json"),
+        (status = 401, description = "Missing or invalid token", body = Problem, content_type = "application/problem+json"),
+        (status = 403, description = "Missing the `moderate:loadouts` permission", body = Problem, content_type = "application/problem+json"),
+        (status = 404, description = "Loadout not found", body = Problem, content_type = "application/problem+json")
+    ),
+    security(("bearer_auth" = [])),
+    tag = "tf2sc"
+)]
+pub async fn hide_loadout(Path(id): Path<String>, State(db): State<PgPool>, auth_user: AuthUser, Json(hide): Json<LoadoutForHide>) -> Result<impl IntoResponse, super::Error> {
+    let id = id.parse::<Uuid>()
+        .map_err(|_| super::Error::InvalidLoadoutId)?;
+
+    hide.validate()?;
+
+    let hidden = sqlx::query_as::<_, Loadout>("UPDATE loadouts SET hidden = true, hidden_by = $1, hidden_reason = $2, updated_at = now() WHERE id = $3 RETURNING *")
+        .bind(&auth_user.subject)
+        .bind(&hide.reason)
+        .bind(id)
         .fetch_optional(&db)
         .await?
         .ok_or(super::Error::LoadoutNotFound { id })?;
 
-    Ok(Json(updated_loadout))
-}
\ No newline at end of file
+    Ok(Json(hidden))
+}
+
+#[utoipa::path(
+    post,
+    path = "/loadouts/{id}/unhide",
+    params(("id" = String, Path, description = "Loadout id, a uuid")),
+    responses(
+        (status = 200, description = "The loadout, public again", body = Loadout),
+        (status = 400, description = "Invalid loadout id", body = Problem, content_type = "application/problem+json"),
+        (status = 401, description = "Missing or invalid token", body = Problem, content_type = "application/problem+json"),
+        (status = 403, description = "Missing the `moderate:loadouts` permission", body = Problem, content_type = "application/problem+json"),
+        (status = 404, description = "Loadout not found", body = Problem, content_type = "application/problem+json")
+    ),
+    security(("bearer_auth" = [])),
+    tag = "tf2sc"
+)]
+pub async fn unhide_loadout(Path(id): Path<String>, State(db): State<PgPool>) -> Result<impl IntoResponse, super::Error> {
+    let id = id.parse::<Uuid>()
+        .map_err(|_| super::Error::InvalidLoadoutId)?;
+
+    let unhidden = sqlx::query_as::<_, Loadout>("UPDATE loadouts SET hidden = false, hidden_by = NULL, hidden_reason = NULL, updated_at = now() WHERE id = $1 RETURNING *")
+        .bind(id)
+        .fetch_optional(&db)
+        .await?
+        .ok_or(super::Error::LoadoutNotFound { id })?;
+
+    Ok(Json(unhidden))
+}
+
+#[utoipa::path(
+    post,
+    path = "/weapons",
+    request_body = WeaponForCreate,
+    responses(
+        (status = 200, description = "The added weapon", body = MongoStyleWeapon),
+        (status = 400, description = "Validation error", body = Problem, content_type = "application/problem+json"),
+        (status = 401, description = "Missing or invalid token", body = Problem, content_type = "application/problem+json"),
+        (status = 403, description = "Missing the `manage:weapons` permission", body = Problem, content_type = "application/problem+json"),
+        (status = 409, description = "A weapon with that id already exists", body = Problem, content_type = "application/problem+json")
+    ),
+    security(("bearer_auth" = [])),
+    tag = "tf2sc"
+)]
+pub async fn create_weapon(State(db): State<PgPool>, Json(weapon): Json<WeaponForCreate>) -> Result<impl IntoResponse, super::Error> {
+    weapon.validate()?;
+
+    let mut tx = db.begin().await?;
+
+    sqlx::query("INSERT INTO weapons (id, name, stock, item_name, item_slot, image_url, image_url_large) VALUES ($1, $2, $3, $4, $5, $6, $7)")
+        .bind(weapon.id)
+        .bind(&weapon.name)
+        .bind(weapon.stock)
+        .bind(&weapon.item_name)
+        .bind(&weapon.item_slot)
+        .bind(&weapon.image_url)
+        .bind(&weapon.image_url_large)
+        .execute(&mut *tx)
+        .await
+        .map_err(|e| match e.as_database_error().and_then(|db_e| db_e.code()).as_deref() {
+            Some(UNIQUE_VIOLATION) => super::Error::WeaponAlreadyExists { id: weapon.id },
+            _ => e.into()
+        })?;
+
+    insert_used_by_classes(&mut tx, weapon.id, &weapon.used_by_classes).await?;
+    insert_per_class_loadout_slots(&mut tx, weapon.id, &weapon.per_class_loadout_slots).await?;
+    tx.commit().await?;
+
+    Ok(Json(fetch_mongo_style_weapon(&db, weapon.id).await?))
+}
+
+#[utoipa::path(
+    put,
+    path = "/weapons/{id}",
+    request_body = WeaponForUpdate,
+    params(("id" = i32, Path, description = "Weapon id")),
+    responses(
+        (status = 200, description = "The updated weapon, existing loadouts with it are left as they are", body = MongoStyleWeapon),
+        (status = 400, description = "Invalid weapon id, validation error or nothing to update", body = Problem, content_type = "application/problem+json"),
+        (status = 401, description = "Missing or invalid token", body = Problem, content_type = "application/problem+json"),
+        (status = 403, description = "Missing the `manage:weapons` permission", body = Problem, content_type = "application/problem+json"),
+        (status = 404, description = "Weapon not found", body = Problem, content_type = "application/problem+json")
+    ),
+    security(("bearer_auth" = [])),
+    tag = "tf2sc"
+)]
+pub async fn update_weapon(Path(id): Path<String>, State(db): State<PgPool>, Json(weapon): Json<WeaponForUpdate>) -> Result<impl IntoResponse, super::Error> {
+    let id = id.parse::<i32>()
+        .map_err(|_| super::Error::InvalidWeaponId)?;
+
+    weapon.validate()?;
+
+    let WeaponForUpdate { name, stock, item_name, item_slot, image_url, image_url_large, used_by_classes, per_class_loadout_slots } = weapon;
+    let nothing_to_update = name.is_none() && stock.is_none() && item_name.is_none() && item_slot.is_none()
+        && image_url.is_none() && image_url_large.is_none() && used_by_classes.is_none() && per_class_loadout_slots.is_none();
+
+    if nothing_to_update {
+        return Err(super::Error::NothingToUpdate);
+    }
+
+    let query = r#"
+        UPDATE weapons
+        SET
+            name = COALESCE($1, name),
+            stock = COALESCE($2, stock),
+            item_name = COALESCE($3, item_name),
+            item_slot = COALESCE($4, item_slot),
+            image_url = COALESCE($5, image_url),
+            image_url_large = COALESCE($6, image_url_large)
+        WHERE id = $7
+    "#;
+
+    let mut tx = db.begin().await?;
+
+    let updated = sqlx::query(query)
+        .bind(name)
+        .bind(stock)
+        .bind(item_name)
+        .bind(item_slot)
+        .bind(image_url)
+        .bind(image_url_large)
+        .bind(id)
+        .execute(&mut *tx)
+        .await?;
+
+    if updated.rows_affected() == 0 {
+        return Err(super::Error::WeaponNotFound { id });
+    }
+
+    // each table is only replaced if its own field was given
+    if let Some(used_by_classes) = &used_by_classes {
+        sqlx::query("DELETE FROM weapon_used_by_classes WHERE weapon_id = $1").bind(id).execute(&mut *tx).await?;
+        insert_used_by_classes(&mut tx, id, used_by_classes).await?;
+    }
+    if let Some(per_class_loadout_slots) = &per_class_loadout_slots {
+        sqlx::query("DELETE FROM weapon_per_class_loadout_slots WHERE weapon_id = $1").bind(id).execute(&mut *tx).await?;
+        insert_per_class_loadout_slots(&mut tx, id, per_class_loadout_slots).await?;
+    }
+
+    tx.commit().await?;
+
+    Ok(Json(fetch_mongo_style_weapon(&db, id).await?))
+}
+
+#[utoipa::path(
+    delete,
+    path = "/weapons/{id}",
+    params(("id" = i32, Path, description = "Weapon id")),
+    responses(
+        (status = 200, description = "The removed weapon", body = MongoStyleWeapon),
+        (status = 400, description = "Invalid weapon id", body = Problem, content_type = "application/problem+json"),
+        (status = 401, description = "Missing or invalid token", body = Problem, content_type = "application/problem+json"),
+        (status = 403, description = "Missing the `manage:weapons` permission", body = Problem, content_type = "application/problem+json"),
+        (status = 404, description = "Weapon not found", body = Problem, content_type = "application/problem+json"),
+        (status = 409, description = "Some loadouts still use the weapon", body = Problem, content_type = "application/problem+json")
+    ),
+    security(("bearer_auth" = [])),
+    tag = "tf2sc"
+)]
+pub async fn delete_weapon(Path(id): Path<String>, State(db): State<PgPool>) -> Result<impl IntoResponse, super::Error> {
+    let id = id.parse::<i32>()
+        .map_err(|_| super::Error::InvalidWeaponId)?;
+
+    let weapon = fetch_mongo_style_weapon(&db, id).await?;
+
+    let mut tx = db.begin().await?;
+    sqlx::query("DELETE FROM weapon_used_by_classes WHERE weapon_id = $1").bind(id).execute(&mut *tx).await?;
+    sqlx::query("DELETE FROM weapon_per_class_loadout_slots WHERE weapon_id = $1").bind(id).execute(&mut *tx).await?;
+    sqlx::query("DELETE FROM weapons WHERE id = $1")
+        .bind(id)
+        .execute(&mut *tx)
+        .await
+        .map_err(|e| match e.as_database_error().and_then(|db_e| db_e.code()).as_deref() {
+            Some(FOREIGN_KEY_VIOLATION) => super::Error::WeaponInUse { id },
+            _ => e.into()
+        })?;
+    tx.commit().await?;
+
+    Ok(Json(weapon))
+}
+
+
+const UNIQUE_VIOLATION: &str = "23505";
+const FOREIGN_KEY_VIOLATION: &str = "23503";
+/// Raised by the `check_loadout_weapons` trigger, for the primary, secondary and melee.
+const INVALID_LOADOUT_WEAPON: [(&str, &str); 3] = [("TF001", "primary"), ("TF002", "secondary"), ("TF003", "melee")];
+
+#[derive(FromRow)]
+struct WeaponSlot {
+    id: i32,
+    name: String,
+    item_slot: ItemSlot,
+    merc: Option<Merc>
+}
+
+/// Checks that each weapon exists, is usable by `merc` and goes in its slot for `merc`, with an error per field that doesn't.
+///
+/// The trigger on `loadouts` checks the same, but only tells which slot is wrong and not why.
+async fn check_loadout_weapons<'e>(db: impl PgExecutor<'e>, merc: &Merc, primary: i32, secondary: i32, melee: i32) -> Result<(), super::Error> {
+    let fields = [("primary", primary, ItemSlot::Primary), ("secondary", secondary, ItemSlot::Secondary), ("melee", melee, ItemSlot::Melee)];
+
+    let rows = sqlx::query_as::<_, WeaponSlot>("SELECT id, name, item_slot, merc FROM weapon_details WHERE id = ANY($1)")
+        .bind(fields.iter().map(|(_, id, _)| *id).collect::<Vec<_>>())
+        .fetch_all(db)
+        .await?;
+
+    let mut errors = ValidationErrors::new();
+
+    for (field, id, slot) in fields {
+        // a row per merc that can use the weapon, with the slot for that merc, or a single row with no merc for all-class weapons
+        let weapon_rows = rows.iter().filter(|w| w.id == id).collect::<Vec<_>>();
+        let usable = weapon_rows.iter().filter(|w| w.merc.is_none() || w.merc.as_ref() == Some(merc)).collect::<Vec<_>>();
+
+        let mut error = match (weapon_rows.first(), usable.first()) {
+            (None, _) => ValidationError::new("weapon_not_found")
+                .with_message(format!("There's no weapon with id {}", id).into()),
+            (Some(weapon), None) => ValidationError::new("not_usable_by_merc")
+                .with_message(format!("The {} can't be used by the {}", weapon.name, merc).into()),
+            (Some(_), Some(_)) if usable.iter().any(|w| w.item_slot == slot) => continue,
+            (Some(_), Some(weapon)) => {
+                let mut error = ValidationError::new("wrong_slot")
+                    .with_message(format!("The {} is a {} for the {}, not a {}", weapon.name, weapon.item_slot, merc, slot).into());
+                error.add_param("slot".into(), &weapon.item_slot);
+                error
+            }
+        };
+
+        error.add_param("weaponId".into(), &id);
+        errors.add(field, error);
+    }
+
+    if errors.is_empty() {
+        Ok(())
+    } else {
+        Err(super::Error::InvalidLoadoutWeapons(errors))
+    }
+}
+
+/// For writes to `loadouts`, when a weapon changed between `check_loadout_weapons` and the write.
+fn loadout_weapons_error(e: sqlx::Error) -> super::Error {
+    let code = e.as_database_error().and_then(|db_e| db_e.code());
+
+    match INVALID_LOADOUT_WEAPON.iter().find(|(tf_code, _)| code.as_deref() == Some(*tf_code)) {
+        Some((_, field)) => {
+            let mut errors = ValidationErrors::new();
+            errors.add(field, ValidationError::new("invalid_weapon").with_message("The weapon doesn't fit this slot of the merc".into()));
+            super::Error::InvalidLoadoutWeapons(errors)
+        },
+        None => e.into()
+    }
+}
+
+async fn insert_used_by_classes(tx: &mut Transaction<'_, Postgres>, id: i32, used_by_classes: &[Merc]) -> Result<(), super::Error> {
+    for merc in used_by_classes {
+        sqlx::query("INSERT INTO weapon_used_by_classes (weapon_id, merc) VALUES ($1, $2)")
+            .bind(id)
+            .bind(merc)
+            .execute(&mut **tx)
+            .await?;
+    }
+
+    Ok(())
+}
+
+async fn insert_per_class_loadout_slots(tx: &mut Transaction<'_, Postgres>, id: i32, per_class_loadout_slots: &HashMap<Merc, ItemSlot>) -> Result<(), super::Error> {
+    for (merc, slot) in per_class_loadout_slots {
+        sqlx::query("INSERT INTO weapon_per_class_loadout_slots (weapon_id, merc, loadout_slot) VALUES ($1, $2, $3)")
+            .bind(id)
+            .bind(merc)
+            .bind(slot)
+            .execute(&mut **tx)
+            .await?;
+    }
+
+    Ok(())
+}
+
+/// A weapon in the same shape as `GET /weapons` returns them.
+async fn fetch_mongo_style_weapon(db: &PgPool, id: i32) -> Result<MongoStyleWeapon, super::Error> {
+    sqlx::query_as::<_, WeaponFromView>("SELECT * FROM weapon_details WHERE id = $1")
+        .bind(id)
+        .fetch_all(db)
+        .await?
+        .to_mongo_style()
+        .into_iter()
+        .next()
+        .ok_or(super::Error::WeaponNotFound { id })
+}
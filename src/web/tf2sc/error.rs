@@ -1,8 +1,8 @@
 use axum::response::IntoResponse;
-use axum::Json;
 use axum::http::StatusCode;
 use sqlx::types::Uuid;
-use tracing::error;
+
+use crate::web::error::{problem_response, ApiError, Problem};
 
 #[derive(Debug, thiserror::Error)]
 pub enum Error {
@@ -21,61 +21,47 @@ pub enum Error {
     LoadoutNotFound { id: Uuid },
     #[error("Validation error: {0}")]
     ValidationError(#[from] validator::ValidationErrors),
-    #[error("Auth Error: {0}")]
-    AuthError(#[from] AuthError),
     #[error("You don't own this resource")]
-    NotOwned
-}
+    NotOwned,
+    #[error("Nothing to update")]
+    NothingToUpdate,
+    #[error("Invalid cursor")]
+    InvalidCursor,
 
-impl IntoResponse for Error {
-    fn into_response(self) -> axum::response::Response {
-        error!("->> {}", self);
+    // 409s
+    #[error("Weapon with id {id} already exists")]
+    WeaponAlreadyExists { id: i32 },
+    #[error("Weapon with id {id} is still used by some loadouts")]
+    WeaponInUse { id: i32 },
 
-        let body = Json (serde_json::json!({
-            "error": format!("{}", &self)
-        }));
-
-        let status_code = match &self {
-            Self::WeaponNotFound { id: _ } | Self::LoadoutNotFound { id: _ } => StatusCode::NOT_FOUND,
-            Self::InvalidWeaponId | Self::InvalidLoadoutId | Self::ValidationError(_) => StatusCode::BAD_REQUEST,
-            Self::NeonTf2scError(_) => StatusCode::INTERNAL_SERVER_ERROR,
-            Self::AuthError(err) => match err {
-                AuthError::MissingToken => StatusCode::FORBIDDEN,
-                _ => StatusCode::FORBIDDEN
-            },
-            Self::NotOwned => StatusCode::UNAUTHORIZED
-        };
-        
-        (status_code, body).into_response()
-    }
+    // 422s
+    #[error("Some weapons don't fit the loadout")]
+    InvalidLoadoutWeapons(validator::ValidationErrors)
 }
 
-#[derive(Debug, thiserror::Error)]
-pub enum AuthError {
-    #[error("Missing Token")]
-    MissingToken,
-    #[error("Missing Header")]
-    MissingHeader,
-    #[error("Invalid Header")]
-    InvalidHeader,
-    #[error("Invalid Token")]
-    InvalidToken,
-    #[error("Couldn't fetch auth stuff")]
-    FetchError,
-    #[error("Key Mismatch")]
-    KeyMismatch
+impl ApiError for Error {
+    fn problem(&self) -> Problem {
+        match self {
+            Self::NeonTf2scError(_) => Problem::internal(),
+            Self::WeaponNotFound { id: _ } => Problem::new(StatusCode::NOT_FOUND, "weapon_not_found", self),
+            Self::LoadoutNotFound { id: _ } => Problem::new(StatusCode::NOT_FOUND, "loadout_not_found", self),
+            Self::InvalidWeaponId => Problem::new(StatusCode::BAD_REQUEST, "invalid_weapon_id", self),
+            Self::InvalidLoadoutId => Problem::new(StatusCode::BAD_REQUEST, "invalid_loadout_id", self),
+            Self::ValidationError(errors) => Problem::new(StatusCode::BAD_REQUEST, "validation_error", "Validation error")
+                .with_errors(errors),
+            Self::NotOwned => Problem::new(StatusCode::FORBIDDEN, "not_owned", self),
+            Self::NothingToUpdate => Problem::new(StatusCode::BAD_REQUEST, "nothing_to_update", self),
+            Self::InvalidCursor => Problem::new(StatusCode::BAD_REQUEST, "invalid_cursor", self),
+            Self::WeaponAlreadyExists { id: _ } => Problem::new(StatusCode::CONFLICT, "weapon_already_exists", self),
+            Self::WeaponInUse { id: _ } => Problem::new(StatusCode::CONFLICT, "weapon_in_use", self),
+            Self::InvalidLoadoutWeapons(errors) => Problem::new(StatusCode::UNPROCESSABLE_ENTITY, "invalid_loadout_weapons", self)
+                .with_errors(errors)
+        }
+    }
 }
 
-impl IntoResponse for AuthError {
+impl IntoResponse for Error {
     fn into_response(self) -> axum::response::Response {
-        error!("->> {}", self);
-
-        let body = Json (serde_json::json!({
-            "error": "Unauthorized"
-        }));
-
-        (StatusCode::UNAUTHORIZED, body).into_response()
+        problem_response(&self)
     }
 }
-
-
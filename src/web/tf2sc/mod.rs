@@ -1,31 +1,57 @@
-use axum::middleware::{from_fn, from_fn_with_state};
 use axum::Router;
 use axum::routing::{get, post, put};
 use sqlx::PgPool;
+use utoipa::OpenApi;
+
+use crate::web::auth::{AuthLayer, BearerAuth};
 
 mod controller;
 mod model;
 mod error;
 mod auth;
+mod query;
 
+use auth::{Access, MANAGE_WEAPONS, MODERATE_LOADOUTS};
 use error::Error;
 
-pub fn routes(db: PgPool) -> Router {
-    let public_routes = Router::new()
-        .route("/weapons", get(controller::get_all_weapons))
-        .route("/weapons/:id", get(controller::get_weapon))
-        .route("/loadouts", get(controller::get_all_loadouts))
-        .route("/ladouts/:id", get(controller::get_loadout));
+#[derive(OpenApi)]
+#[openapi(
+    paths(
+        controller::get_all_weapons, controller::get_weapon,
+        controller::get_all_loadouts, controller::get_loadout,
+        controller::create_loadout, controller::update_loadout, controller::delete_loadout,
+        controller::get_hidden_loadouts, controller::hide_loadout, controller::unhide_loadout,
+        controller::create_weapon, controller::update_weapon, controller::delete_weapon
+    ),
+    components(schemas(
+        model::MongoStyleWeapon, model::WeaponFromView, model::Weapon, model::ItemSlot, model::Merc,
+        model::Loadout, model::FullLoadout, model::LoadoutForCreate, model::LoadoutForUpdate, model::LoadoutForHide,
+        model::WeaponForCreate, model::WeaponForUpdate, model::LoadoutPage
+    )),
+    modifiers(&BearerAuth),
+    tags((name = "tf2sc", description = "TF2 weapons and community loadouts"))
+)]
+pub struct Tf2scApi;
 
-    let auth_routes = Router::new()
-        .route("/loadouts", post(controller::create_loadout));
+/// Each route with who can use it, the middlewares come from the `Access`.
+pub fn routes(db: PgPool, auth: AuthLayer) -> Router {
+    use Access::*;
 
-    let ownership_routes = Router::new()
-        .route("/loadouts/:id", put(controller::update_loadout).delete(controller::delete_loadout));
+    let routes = [
+        ("/weapons", get(controller::get_all_weapons), Public),
+        ("/weapons", post(controller::create_weapon), Permission(MANAGE_WEAPONS)),
+        ("/weapons/:id", get(controller::get_weapon), Public),
+        ("/weapons/:id", put(controller::update_weapon).delete(controller::delete_weapon), Permission(MANAGE_WEAPONS)),
+        ("/loadouts", get(controller::get_all_loadouts), Public),
+        ("/loadouts", post(controller::create_loadout), LoggedIn),
+        ("/loadouts/hidden", get(controller::get_hidden_loadouts), Permission(MODERATE_LOADOUTS)),
+        ("/loadouts/:id", get(controller::get_loadout), Public),
+        ("/loadouts/:id", put(controller::update_loadout).delete(controller::delete_loadout), LoadoutOwnerOr(MODERATE_LOADOUTS)),
+        ("/loadouts/:id/hide", post(controller::hide_loadout), Permission(MODERATE_LOADOUTS)),
+        ("/loadouts/:id/unhide", post(controller::unhide_loadout), Permission(MODERATE_LOADOUTS)),
+    ];
 
-    Router::new()
-        .merge(public_routes)
-        .merge(auth_routes.layer(from_fn(auth::auth_mw)))
-        .merge(ownership_routes.layer(from_fn_with_state(db.clone(), auth::loadout_ownership_mw)).layer(from_fn(auth::auth_mw)))  // i think the order has to be reversed like this for auth to be applied first
+    routes.into_iter()
+        .fold(Router::new(), |router, (path, route, access)| router.route(path, access.guard(route, &auth, &db)))
         .with_state(db)
 }
@@ -5,6 +5,7 @@ use sqlx::types::Uuid;
 use sqlx::FromRow;
 use sqlx::Type;
 use sqlx::types::Json;
+use utoipa::ToSchema;
 use sqlx;
 use strum_macros::Display;
 use strum_macros::EnumString;
@@ -12,7 +13,7 @@ use itertools::Itertools;
 use validator::Validate;
 
 
-#[derive(Debug, Clone, FromRow, Serialize)]
+#[derive(Debug, Clone, FromRow, Serialize, ToSchema)]
 pub struct WeaponFromView {
     id: i32,
     name: String,
@@ -62,7 +63,7 @@ impl MongoStyle for Vec<WeaponFromView> {
     }
 }
 
-#[derive(Debug, Clone, FromRow, Serialize)]
+#[derive(Debug, Clone, FromRow, Serialize, ToSchema)]
 pub struct MongoStyleWeapon {
     #[serde(rename = "_id")]
     id: i32,
@@ -73,6 +74,7 @@ pub struct MongoStyleWeapon {
     image_url: String,
     image_url_large: String,
     used_by_classes: Option<Vec<Merc>>,
+    #[schema(value_type = Option<HashMap<String, ItemSlot>>)]
     per_class_loadout_slots: Option<HashMap<Merc, ItemSlot>>
 }
 
@@ -150,16 +152,17 @@ impl MongoStyleWeaponBuilder {
     }
 }
 
-#[derive(Type, Debug, Clone, Deserialize, Serialize)]
+#[derive(Type, Debug, Clone, Deserialize, Serialize, Display, PartialEq, Eq, ToSchema)]
 #[serde(rename_all = "lowercase")]
 #[sqlx(type_name = "item_slot", rename_all = "lowercase")]
+#[strum(serialize_all = "lowercase")]
 pub enum ItemSlot {
     Primary,
     Secondary,
     Melee
 }
 
-#[derive(Type, Debug, Clone, Deserialize, Serialize, EnumString, Display, PartialEq, Eq, Hash)]
+#[derive(Type, Debug, Clone, Deserialize, Serialize, EnumString, Display, PartialEq, Eq, Hash, ToSchema)]
 #[sqlx(type_name = "merc")]
 #[strum(serialize_all = "PascalCase")]
 pub enum Merc {
@@ -174,11 +177,13 @@ pub enum Merc {
     Spy
 }
 
-#[derive(Debug, Clone, FromRow, Deserialize, Serialize)]
+#[derive(Debug, Clone, FromRow, Deserialize, Serialize, ToSchema)]
 pub struct Loadout {
     #[serde(rename(serialize = "_id"))]
+    #[schema(rename = "_id", value_type = String)]
     pub id: Uuid,
     #[serde(rename(serialize = "userId"))]
+    #[schema(rename = "userId")]
     pub user_id: String,
     pub merc: Merc,
     pub primary: i32,
@@ -187,12 +192,22 @@ pub struct Loadout {
     pub name: String,
     pub playstyle: String,
     #[serde(rename(serialize = "createdAt"))]
+    #[schema(rename = "createdAt", value_type = String, format = DateTime)]
     pub created_at: chrono::DateTime<chrono::Utc>,
     #[serde(rename(serialize = "updatedAt"))]
-    pub updated_at: chrono::DateTime<chrono::Utc>
+    #[schema(rename = "updatedAt", value_type = String, format = DateTime)]
+    pub updated_at: chrono::DateTime<chrono::Utc>,
+    /// Hidden loadouts are only visible to their owner (through edits) and to moderators.
+    pub hidden: bool,
+    #[serde(rename(serialize = "hiddenBy"))]
+    #[schema(rename = "hiddenBy")]
+    pub hidden_by: Option<String>,
+    #[serde(rename(serialize = "hiddenReason"))]
+    #[schema(rename = "hiddenReason")]
+    pub hidden_reason: Option<String>
 }
 
-#[derive(Debug, Clone, FromRow, Deserialize, Serialize)]
+#[derive(Debug, Clone, FromRow, Deserialize, Serialize, ToSchema)]
 pub struct Weapon {
     id: i32,
     name: String,
@@ -203,25 +218,51 @@ pub struct Weapon {
     image_url_large: String
 }
 
-#[derive(Debug, Clone, FromRow, Deserialize, Serialize)]
+#[derive(Debug, Clone, FromRow, Deserialize, Serialize, ToSchema)]
 pub struct FullLoadout {
     #[serde(rename(serialize = "_id"))]
+    #[schema(rename = "_id", value_type = String)]
     pub id: Uuid,
     #[serde(rename(serialize = "userId"))]
+    #[schema(rename = "userId")]
     pub user_id: String,
     pub merc: Merc,
+    #[schema(value_type = Weapon)]
     pub primary: Json<Weapon>,
+    #[schema(value_type = Weapon)]
     pub secondary: Json<Weapon>,
+    #[schema(value_type = Weapon)]
     pub melee: Json<Weapon>,
     pub name: String,
     pub playstyle: String,
     #[serde(rename(serialize = "createdAt"))]
+    #[schema(rename = "createdAt", value_type = String, format = DateTime)]
     pub created_at: chrono::DateTime<chrono::Utc>,
     #[serde(rename(serialize = "updatedAt"))]
-    pub updated_at: chrono::DateTime<chrono::Utc>
+    #[schema(rename = "updatedAt", value_type = String, format = DateTime)]
+    pub updated_at: chrono::DateTime<chrono::Utc>,
+    /// Hidden loadouts are only visible to their owner (through edits) and to moderators.
+    pub hidden: bool,
+    #[serde(rename(serialize = "hiddenBy"))]
+    #[schema(rename = "hiddenBy")]
+    pub hidden_by: Option<String>,
+    #[serde(rename(serialize = "hiddenReason"))]
+    #[schema(rename = "hiddenReason")]
+    pub hidden_reason: Option<String>
 }
 
-#[derive(Debug, Clone, Deserialize, Validate)]
+/// A page of results, `next_cursor` is `None` on the last page.
+#[derive(Debug, Clone, Serialize, ToSchema)]
+#[serde(rename_all = "camelCase")]
+#[aliases(LoadoutPage = Page<FullLoadout>)]
+pub struct Page<T> {
+    pub items: Vec<T>,
+    /// How many match the filters, over all pages.
+    pub total: u64,
+    pub next_cursor: Option<String>
+}
+
+#[derive(Debug, Clone, Deserialize, Validate, ToSchema)]
 pub struct LoadoutForCreate {
     pub merc: Merc,
     pub primary: i32,
@@ -233,7 +274,7 @@ pub struct LoadoutForCreate {
     pub playstyle: String
 }
 
-#[derive(Debug, Clone, Deserialize, Validate)]
+#[derive(Debug, Clone, Deserialize, Validate, ToSchema)]
 pub struct LoadoutForUpdate {
     pub merc: Merc,
     pub primary: Option<i32>,
@@ -245,3 +286,52 @@ pub struct LoadoutForUpdate {
     pub playstyle: Option<String>
 }
 
+
+#[derive(Debug, Clone, Deserialize, Validate, ToSchema)]
+pub struct LoadoutForHide {
+    /// Shown to the owner.
+    #[validate(length(min = 3, max = 500))]
+    pub reason: Option<String>
+}
+
+#[derive(Debug, Clone, Deserialize, Validate, ToSchema)]
+pub struct WeaponForCreate {
+    /// The item's defindex.
+    #[validate(range(min = 0))]
+    pub id: i32,
+    #[validate(length(min = 1))]
+    pub name: String,
+    pub stock: bool,
+    #[validate(length(min = 1))]
+    pub item_name: String,
+    pub item_slot: ItemSlot,
+    #[validate(url)]
+    pub image_url: String,
+    #[validate(url)]
+    pub image_url_large: String,
+    /// Empty for all-class weapons.
+    #[serde(default)]
+    pub used_by_classes: Vec<Merc>,
+    /// Only for weapons that go in a different slot for some mercs, like the shotgun.
+    #[serde(default)]
+    #[schema(value_type = HashMap<String, ItemSlot>)]
+    pub per_class_loadout_slots: HashMap<Merc, ItemSlot>
+}
+
+/// Only the given fields are changed, the classes and slots are replaced as a whole.
+#[derive(Debug, Clone, Deserialize, Validate, ToSchema)]
+pub struct WeaponForUpdate {
+    #[validate(length(min = 1))]
+    pub name: Option<String>,
+    pub stock: Option<bool>,
+    #[validate(length(min = 1))]
+    pub item_name: Option<String>,
+    pub item_slot: Option<ItemSlot>,
+    #[validate(url)]
+    pub image_url: Option<String>,
+    #[validate(url)]
+    pub image_url_large: Option<String>,
+    pub used_by_classes: Option<Vec<Merc>>,
+    #[schema(value_type = Option<HashMap<String, ItemSlot>>)]
+    pub per_class_loadout_slots: Option<HashMap<Merc, ItemSlot>>
+}
@@ -0,0 +1,207 @@
+use base64::{engine::general_purpose::URL_SAFE_NO_PAD, Engine};
+use serde::{Deserialize, Serialize};
+use sqlx::{postgres::PgRow, types::Uuid, FromRow, Postgres, QueryBuilder};
+use strum_macros::{AsRefStr, EnumString};
+use utoipa::ToSchema;
+
+use super::model::{FullLoadout, Merc};
+
+pub const DEFAULT_PAGE_SIZE: i64 = 20;
+pub const MAX_PAGE_SIZE: i64 = 100;
+
+#[derive(Debug, Clone, Copy, Deserialize, Serialize, EnumString, AsRefStr, Default, PartialEq, Eq, ToSchema)]
+#[serde(rename_all = "lowercase")]
+pub enum Sort {
+    #[default]
+    #[strum(serialize = "DESC")]
+    Desc,
+    #[strum(serialize = "ASC")]
+    Asc
+}
+
+impl Sort {
+    /// How the keyset of the next page compares to the one of the cursor.
+    fn keyset_op(self) -> &'static str {
+        match self {
+            Self::Desc => "<",
+            Self::Asc => ">"
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, Deserialize, Serialize, EnumString, AsRefStr, Default, PartialEq, Eq, ToSchema)]
+#[serde(rename_all = "lowercase")]
+pub enum SortBy {
+    #[default]
+    #[strum(serialize = "created_at")]
+    Created,
+    #[strum(serialize = "updated_at")]
+    Updated
+}
+
+impl SortBy {
+    fn value_of(self, loadout: &FullLoadout) -> chrono::DateTime<chrono::Utc> {
+        match self {
+            Self::Created => loadout.created_at,
+            Self::Updated => loadout.updated_at
+        }
+    }
+}
+
+/// What the public loadouts can be narrowed down to, every filter that's set has to match.
+#[derive(Debug, Clone, Default)]
+pub struct LoadoutFilter {
+    pub merc: Option<Merc>,
+    pub user_id: Option<String>,
+    /// Used as the primary, secondary or melee.
+    pub weapon: Option<i32>,
+    /// Inclusive.
+    pub created_after: Option<chrono::DateTime<chrono::Utc>>,
+    /// Exclusive.
+    pub created_before: Option<chrono::DateTime<chrono::Utc>>,
+    /// A websearch style query (`"quoted words"`, `or`, `-not`) over the name and playstyle.
+    pub search: Option<String>
+}
+
+/// Where a page ends, the sort value and the id of its last loadout, with the id breaking ties.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct LoadoutCursor {
+    sort_by: SortBy,
+    sort: Sort,
+    at: chrono::DateTime<chrono::Utc>,
+    id: Uuid
+}
+
+impl LoadoutCursor {
+    pub fn after(loadout: &FullLoadout, sort_by: SortBy, sort: Sort) -> Self {
+        Self { sort_by, sort, at: sort_by.value_of(loadout), id: loadout.id }
+    }
+
+    pub fn encode(&self) -> String {
+        // serializing these can't fail
+        URL_SAFE_NO_PAD.encode(serde_json::to_vec(self).unwrap_or_default())
+    }
+
+    /// A cursor from a page sorted by something else (or the other way around) doesn't point anywhere in this one,
+    /// so it's rejected too.
+    pub fn decode(cursor: &str, sort_by: SortBy, sort: Sort) -> Result<Self, super::Error> {
+        URL_SAFE_NO_PAD.decode(cursor).ok()
+            .and_then(|json| serde_json::from_slice::<Self>(&json).ok())
+            .filter(|cursor| cursor.sort_by == sort_by && cursor.sort == sort)
+            .ok_or(super::Error::InvalidCursor)
+    }
+}
+
+/// Builds the queries over `full_loadouts`, hidden loadouts are always left out.
+///
+/// Values are always bound, only the column and direction names of `SortBy` and `Sort` end up in the sql.
+pub struct LoadoutQuery(QueryBuilder<'static, Postgres>);
+
+impl LoadoutQuery {
+    pub fn select(filter: &LoadoutFilter) -> Self {
+        Self::new("SELECT * FROM full_loadouts", filter)
+    }
+
+    pub fn count(filter: &LoadoutFilter) -> Self {
+        Self::new("SELECT COUNT(*) FROM full_loadouts", filter)
+    }
+
+    fn new(select: &str, filter: &LoadoutFilter) -> Self {
+        let mut builder = QueryBuilder::new(select);
+        builder.push(" WHERE NOT hidden");
+
+        if let Some(merc) = &filter.merc {
+            builder.push(" AND merc = ").push_bind(merc.clone());
+        }
+        if let Some(user_id) = &filter.user_id {
+            builder.push(" AND user_id = ").push_bind(user_id.clone());
+        }
+        if let Some(weapon) = filter.weapon {
+            builder.push(" AND ").push_bind(weapon).push(" IN (\"primary\", secondary, melee)");
+        }
+        if let Some(after) = filter.created_after {
+            builder.push(" AND created_at >= ").push_bind(after);
+        }
+        if let Some(before) = filter.created_before {
+            builder.push(" AND created_at < ").push_bind(before);
+        }
+        if let Some(search) = &filter.search {
+            // the same expression as the index on `loadouts`, or the index isn't used
+            builder.push(" AND to_tsvector('simple', name || ' ' || playstyle) @@ websearch_to_tsquery('simple', ")
+                .push_bind(search.clone())
+                .push(")");
+        }
+
+        Self(builder)
+    }
+
+    /// Only the loadouts that come after the cursor, in the order it was made for.
+    pub fn after(mut self, cursor: &LoadoutCursor) -> Self {
+        self.0.push(format_args!(" AND ({}, id) {} (", cursor.sort_by.as_ref(), cursor.sort.keyset_op()))
+            .push_bind(cursor.at)
+            .push(", ")
+            .push_bind(cursor.id)
+            .push(")");
+        self
+    }
+
+    pub fn order_by(mut self, sort_by: SortBy, sort: Sort) -> Self {
+        self.0.push(format_args!(" ORDER BY {} {}, id {}", sort_by.as_ref(), sort.as_ref(), sort.as_ref()));
+        self
+    }
+
+    pub fn limit(mut self, limit: i64) -> Self {
+        self.0.push(" LIMIT ").push_bind(limit);
+        self
+    }
+
+    pub fn build_query_as<T>(&mut self) -> sqlx::query::QueryAs<'_, Postgres, T, sqlx::postgres::PgArguments>
+    where
+        T: for<'r> FromRow<'r, PgRow>
+    {
+        self.0.build_query_as()
+    }
+
+    pub fn build_query_scalar<T>(&mut self) -> sqlx::query::QueryScalar<'_, Postgres, T, sqlx::postgres::PgArguments>
+    where
+        (T,): for<'r> FromRow<'r, PgRow>
+    {
+        self.0.build_query_scalar()
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use super::super::Error;
+
+    fn cursor(sort_by: SortBy, sort: Sort) -> LoadoutCursor {
+        LoadoutCursor { sort_by, sort, at: chrono::DateTime::from_timestamp(1_700_000_000, 0).unwrap(), id: Uuid::nil() }
+    }
+
+    #[test]
+    fn cursors_round_trip() {
+        let original = cursor(SortBy::Created, Sort::Asc);
+
+        assert_eq!(LoadoutCursor::decode(&original.encode(), SortBy::Created, Sort::Asc).unwrap(), original);
+    }
+
+    #[test]
+    fn cursors_of_another_order_are_rejected() {
+        let encoded = cursor(SortBy::Created, Sort::Desc).encode();
+
+        assert!(matches!(LoadoutCursor::decode(&encoded, SortBy::Updated, Sort::Desc), Err(Error::InvalidCursor)));
+        assert!(matches!(LoadoutCursor::decode(&encoded, SortBy::Created, Sort::Asc), Err(Error::InvalidCursor)));
+    }
+
+    #[test]
+    fn garbage_cursors_are_rejected() {
+        // a cursor from before the direction was part of it
+        let without_sort = URL_SAFE_NO_PAD.encode(r#"{"sort_by":"created","at":"2023-11-14T22:13:20Z","id":"00000000-0000-0000-0000-000000000000"}"#);
+
+        for garbage in ["", "not base64!", &URL_SAFE_NO_PAD.encode("not json"), &without_sort] {
+            assert!(matches!(LoadoutCursor::decode(garbage, SortBy::Created, Sort::Desc), Err(Error::InvalidCursor)), "{}", garbage);
+        }
+    }
+}
@@ -0,0 +1,186 @@
+use std::io::Cursor;
+use image::{imageops::FilterType, DynamicImage, ImageFormat, ImageReader, Limits};
+
+const PNG_SIGNATURE: &[u8] = b"\x89PNG\r\n\x1a\n";
+/// Wider or taller images are not decoded.
+const MAX_DIMENSION: u32 = 8192;
+/// How much a single decode can allocate, the decoded pixels included, so that a huge image can't eat all the memory.
+const MAX_DECODE_ALLOC: u64 = 64 * 1024 * 1024;
+
+/// The content type of an image, from its first few bytes.
+pub fn sniff_content_type(bytes: &[u8]) -> Option<&'static str> {
+    if bytes.starts_with(PNG_SIGNATURE) {
+        Some("image/png")
+    } else if bytes.starts_with(&[0xFF, 0xD8, 0xFF]) {
+        Some("image/jpeg")
+    } else if bytes.starts_with(b"GIF87a") || bytes.starts_with(b"GIF89a") {
+        Some("image/gif")
+    } else if bytes.len() >= 12 && &bytes[..4] == b"RIFF" && &bytes[8..12] == b"WEBP" {
+        Some("image/webp")
+    } else {
+        None
+    }
+}
+
+/// The width and height of a png, jpeg, gif or webp, read from its header without decoding it.
+///
+/// `None` if the header is cut off or it's not one of those.
+pub fn dimensions(bytes: &[u8]) -> Option<(u32, u32)> {
+    sniff_content_type(bytes)?;
+
+    let (width, height) = ImageReader::new(Cursor::new(bytes)).with_guessed_format().ok()?
+        .into_dimensions().ok()?;
+
+    (width > 0 && height > 0).then_some((width, height))
+}
+
+/// Shrinks an image to fit in a `size`x`size` box, keeping its aspect ratio, and encodes it as a png.
+/// Images that already fit are only re-encoded, never scaled up.
+///
+/// `None` if the image can't be decoded (or is over the limits), the caller should fall back to the original then.
+pub fn thumbnail(bytes: &[u8], size: u32) -> Option<Vec<u8>> {
+    let image = decode(bytes)?;
+
+    encode_png(&resize_to_fit(image, size))
+}
+
+
+fn limits() -> Limits {
+    let mut limits = Limits::default();
+    limits.max_image_width = Some(MAX_DIMENSION);
+    limits.max_image_height = Some(MAX_DIMENSION);
+    limits.max_alloc = Some(MAX_DECODE_ALLOC);
+    limits
+}
+
+/// A png, jpeg, gif (the first frame) or webp, the format is sniffed from the content.
+fn decode(bytes: &[u8]) -> Option<DynamicImage> {
+    let mut reader = ImageReader::new(Cursor::new(bytes)).with_guessed_format().ok()?;
+    reader.limits(limits());
+
+    reader.decode().ok()
+}
+
+fn resize_to_fit(image: DynamicImage, size: u32) -> DynamicImage {
+    if image.width() <= size && image.height() <= size {
+        return image;
+    }
+
+    image.resize(size, size, FilterType::Triangle)
+}
+
+fn encode_png(image: &DynamicImage) -> Option<Vec<u8>> {
+    let mut out = Cursor::new(Vec::new());
+    image.write_to(&mut out, ImageFormat::Png).ok()?;
+
+    Some(out.into_inner())
+}
+
+
+#[cfg(test)]
+mod tests {
+    use image::{ImageBuffer, Rgb, Rgba};
+
+    use super::*;
+
+    fn encoded(image: DynamicImage, format: ImageFormat) -> Vec<u8> {
+        let mut out = Cursor::new(Vec::new());
+        image.write_to(&mut out, format).unwrap();
+        out.into_inner()
+    }
+
+    fn gradient(width: u32, height: u32) -> DynamicImage {
+        DynamicImage::ImageRgb8(ImageBuffer::from_fn(width, height, |x, y| Rgb([(x % 256) as u8, (y % 256) as u8, 128])))
+    }
+
+    fn decoded_png(bytes: &[u8]) -> DynamicImage {
+        assert_eq!(sniff_content_type(bytes), Some("image/png"));
+        image::load_from_memory_with_format(bytes, ImageFormat::Png).unwrap()
+    }
+
+    #[test]
+    fn jpegs_are_shrunk_to_fit_keeping_the_aspect_ratio() {
+        let jpeg = encoded(gradient(600, 400), ImageFormat::Jpeg);
+
+        let thumb = decoded_png(&thumbnail(&jpeg, 150).unwrap());
+        assert_eq!((thumb.width(), thumb.height()), (150, 100));
+
+        let tall = encoded(gradient(200, 800), ImageFormat::Jpeg);
+        let thumb = decoded_png(&thumbnail(&tall, 400).unwrap());
+        assert_eq!((thumb.width(), thumb.height()), (100, 400));
+    }
+
+    #[test]
+    fn every_sniffed_format_decodes() {
+        for format in [ImageFormat::Png, ImageFormat::Jpeg, ImageFormat::Gif, ImageFormat::WebP] {
+            let bytes = encoded(DynamicImage::ImageRgba8(ImageBuffer::from_pixel(64, 32, Rgba([10, 20, 30, 255]))), format);
+            assert!(sniff_content_type(&bytes).is_some(), "{:?} isn't sniffed", format);
+
+            let thumb = decoded_png(&thumbnail(&bytes, 16).unwrap_or_else(|| panic!("{:?} didn't decode", format)));
+            assert_eq!((thumb.width(), thumb.height()), (16, 8));
+        }
+    }
+
+    #[test]
+    fn small_images_are_not_scaled_up() {
+        let png = encoded(gradient(40, 30), ImageFormat::Png);
+
+        let thumb = decoded_png(&thumbnail(&png, 150).unwrap());
+        assert_eq!((thumb.width(), thumb.height()), (40, 30));
+        assert_eq!(thumb.to_rgb8(), gradient(40, 30).to_rgb8());
+    }
+
+    #[test]
+    fn resizing_averages_the_pixels() {
+        // a 2x1 block of black and white becomes one grey pixel
+        let image = DynamicImage::ImageRgb8(ImageBuffer::from_fn(200, 100, |x, _| if x % 2 == 0 { Rgb([0, 0, 0]) } else { Rgb([255, 255, 255]) }));
+
+        let thumb = resize_to_fit(image, 2).to_rgb8();
+        assert_eq!(thumb.dimensions(), (2, 1));
+        assert!(thumb.pixels().all(|p| (100..=155).contains(&p[0])), "{:?}", thumb);
+    }
+
+    #[test]
+    fn images_over_the_limits_are_not_decoded() {
+        let wide = encoded(DynamicImage::ImageLuma8(ImageBuffer::new(MAX_DIMENSION + 1, 1)), ImageFormat::Png);
+        assert!(thumbnail(&wide, 150).is_none());
+
+        // within the dimensions, but 8192 * 4096 * 4 bytes of rgba is over the allocation limit
+        let big = encoded(DynamicImage::ImageRgba8(ImageBuffer::new(MAX_DIMENSION, MAX_DIMENSION / 2)), ImageFormat::Png);
+        assert!(thumbnail(&big, 150).is_none());
+    }
+
+    #[test]
+    fn dimensions_are_read_from_the_header() {
+        for format in [ImageFormat::Png, ImageFormat::Jpeg, ImageFormat::Gif, ImageFormat::WebP] {
+            let bytes = encoded(gradient(300, 200), format);
+            assert_eq!(dimensions(&bytes), Some((300, 200)), "{:?}", format);
+        }
+
+        // only the header is read, so an image that's too big to decode still has dimensions
+        let wide = encoded(DynamicImage::ImageLuma8(ImageBuffer::new(MAX_DIMENSION + 1, 1)), ImageFormat::Png);
+        assert_eq!(dimensions(&wide), Some((MAX_DIMENSION + 1, 1)));
+    }
+
+    #[test]
+    fn dimensions_of_garbage_are_none() {
+        assert_eq!(dimensions(b"definitely not an image"), None);
+        assert_eq!(dimensions(&[]), None);
+
+        // a png signature with the header cut off
+        let png = encoded(gradient(300, 200), ImageFormat::Png);
+        assert_eq!(dimensions(&png[..12]), None);
+
+        // a bmp header, which isn't one of the accepted types
+        assert_eq!(dimensions(b"BM\x3a\0\0\0\0\0\0\0\x36\0\0\0\x28\0\0\0\x01\0\0\0\x01\0\0\0\x01\0\x18\0"), None);
+    }
+
+    #[test]
+    fn garbage_is_not_decoded() {
+        assert!(thumbnail(b"definitely not an image", 150).is_none());
+
+        let mut truncated = encoded(gradient(100, 100), ImageFormat::Png);
+        truncated.truncate(truncated.len() / 2);
+        assert!(thumbnail(&truncated, 150).is_none());
+    }
+}
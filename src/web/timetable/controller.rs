@@ -1,7 +1,9 @@
-use axum::{response::IntoResponse, Extension, Json};
+use axum::{response::IntoResponse, Extension};
+use crate::web::extract::Json;
 use chrono::NaiveDate;
 use serde::{Deserialize, Serialize};
 use tracing::info;
+use utoipa::ToSchema;
 
 use crate::web::{timetable::{parsing::get_all_lessons, url::TimetableUrl, weekday::get_week_number_for_date}, ClientWithKeys};
 
@@ -14,18 +16,34 @@ use super::parsing::Lesson;
 // - better error reporting at the scraper level, with some parsing/validation entry and some lifetime structs
 // - allow to return poisoned timetables, aka ones with mising data chunks
 
-#[derive(Debug, Deserialize, Serialize)]
+#[derive(Debug, Deserialize, Serialize, ToSchema)]
+#[schema(as = LessonsRequest)]
 pub struct RequestBody {
     #[serde(rename = "timetableId")]
     timetable_id: String,
+    /// Any day of the week to get the lessons of.
+    #[schema(value_type = String, format = Date, example = "2024-10-09")]
     date: NaiveDate
 }
 
-#[derive(Debug, Serialize)]
-struct ResponseBody {
+#[derive(Debug, Serialize, ToSchema)]
+#[schema(as = LessonsResponse)]
+pub struct ResponseBody {
     lessons: Vec<Lesson>
 }
 
+#[utoipa::path(
+    post,
+    path = "/lessons",
+    request_body = LessonsRequest,
+    responses(
+        (status = 200, description = "The lessons of the week that has the given date", body = LessonsResponse),
+        (status = 502, description = "The timetable site returned something that couldn't be parsed", body = Problem, content_type = "application/problem+json"),
+        (status = 503, description = "The timetable site is unavailable", body = Problem, content_type = "application/problem+json"),
+        (status = 504, description = "The timetable site timed out", body = Problem, content_type = "application/problem+json")
+    ),
+    tag = "timetable"
+)]
 pub async fn get_lessons(Extension(client): Extension<ClientWithKeys>, Json(payload): Json<RequestBody>) -> Result<impl IntoResponse, super::Error> {
     let RequestBody { timetable_id, date } = payload;
 
@@ -1,5 +1,6 @@
-use axum::{http::StatusCode, response::IntoResponse, routing::post, Json, Router};
-use tracing::error;
+use axum::{response::IntoResponse, routing::post, Router};
+use utoipa::OpenApi;
+use super::error::{problem_response, ApiError, Problem};
 
 mod controller;
 mod parsing;
@@ -7,12 +8,22 @@ mod url;
 mod weekday;
 
 
+#[derive(OpenApi)]
+#[openapi(
+    paths(controller::get_lessons),
+    components(schemas(controller::RequestBody, controller::ResponseBody, parsing::Lesson, parsing::LessonDetails, parsing::RoomDetails)),
+    tags((name = "timetable", description = "Scraped university timetables"))
+)]
+pub struct TimetableApi;
+
 pub fn routes() -> Router {
     Router::new()
         .route("/lessons", post(controller::get_lessons))
 }
 
 
+const UPSTREAM: &str = "The timetable site";
+
 #[derive(Debug, thiserror::Error)]
 pub enum Error {
     #[error("Weekday error: {0}")]
@@ -23,19 +34,19 @@ pub enum Error {
     TimetableReqwestError(#[from] reqwest::Error),
 }
 
+impl ApiError for Error {
+    fn problem(&self) -> Problem {
+        match self {
+            Self::TimetableReqwestError(e) => Problem::upstream(UPSTREAM, e),
+            // both mean the timetable site changed or answered with something unexpected
+            Self::WeekDayError(_) | Self::ParsingError(_) => Problem::bad_gateway(UPSTREAM),
+        }
+    }
+}
+
 impl IntoResponse for Error {
     fn into_response(self) -> axum::response::Response {
-        error!("->> {}", self);
-
-        let body = Json(serde_json::json!({
-            "error": format!("{}", &self)
-        }));
-
-        let status_code = match &self {
-            _ => StatusCode::INTERNAL_SERVER_ERROR
-        };
-        
-        (status_code, body).into_response()
+        problem_response(&self)
     }
 }
 
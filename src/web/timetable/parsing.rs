@@ -3,6 +3,7 @@ use serde::Serialize;
 use tracing::{self, debug};
 use chrono::{Days, Duration, NaiveDate, NaiveDateTime, NaiveTime, TimeDelta};
 use scraper::{Element, ElementRef, Html, Selector};
+use utoipa::ToSchema;
 
 
 // TODO:
@@ -87,11 +88,13 @@ fn get_monday_date(document: &Html) -> Result<NaiveDate, ParsingError> {
     Ok(date)
 }
 
-#[derive(Debug, Clone, Serialize)]
+#[derive(Debug, Clone, Serialize, ToSchema)]
 pub struct Lesson {
     #[serde(rename = "startDate")]
+    #[schema(value_type = String, format = DateTime, example = "2024-10-07T09:00:00")]
     start_date: NaiveDateTime,
     #[serde(rename = "endDate")]
+    #[schema(value_type = String, format = DateTime, example = "2024-10-07T11:00:00")]
     end_date: NaiveDateTime,
     details: LessonDetails
 }
@@ -117,7 +120,7 @@ impl Lesson {
 }
 
 
-#[derive(Debug, Clone, Serialize)]
+#[derive(Debug, Clone, Serialize, ToSchema)]
 pub struct LessonDetails {
     subject: String,
     #[serde(rename = "roomDetails")]
@@ -138,7 +141,7 @@ impl LessonDetails {
     }
 }
 
-#[derive(Debug, Clone, Serialize)]
+#[derive(Debug, Clone, Serialize, ToSchema)]
 pub struct RoomDetails {
     id: String,
     desc: String,
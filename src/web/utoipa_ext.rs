@@ -0,0 +1,293 @@
+//! Merges the openapi docs of separate apis into one, until utoipa's `nest` gets published.
+//!
+//! Unlike `OpenApi::merge`, nothing is silently dropped: a path, schema, response or security scheme that's defined by
+//! more than 1 api (differently) is an error, so that one service can't hide the docs of another.
+
+use std::collections::BTreeMap;
+use utoipa::openapi::{path::PathItemType, Components, Info, OpenApi, OpenApiBuilder, PathItem, Paths};
+
+
+#[derive(Debug, thiserror::Error)]
+pub enum MergeError {
+    #[error("`{method} {path}` is documented by more than 1 api")]
+    PathConflict { method: &'static str, path: String },
+    #[error("Schema `{name}` is defined differently by more than 1 api")]
+    SchemaConflict { name: String },
+    #[error("Response `{name}` is defined differently by more than 1 api")]
+    ResponseConflict { name: String },
+    #[error("Security scheme `{name}` is defined differently by more than 1 api")]
+    SecuritySchemeConflict { name: String },
+    #[error("`{path}` has no tag, so it can't be prefixed by it")]
+    MissingTag { path: String },
+}
+
+/// An api to merge, and the prefix its paths get.
+pub struct NestedApi {
+    prefix: String,
+    api: OpenApi,
+    tag_prefix: bool
+}
+
+impl NestedApi {
+    /// `/random` at `/cats` becomes `/cats/random`, an empty prefix leaves the paths as they are.
+    pub fn new(prefix: &str, api: OpenApi) -> Self {
+        Self { prefix: prefix.trim_end_matches('/').to_string(), api, tag_prefix: false }
+    }
+
+    /// Also puts the tag of each path between the prefix and the path, so `/random` with a `quotes` tag at `/jp2`
+    /// becomes `/jp2/quotes/random`.
+    ///
+    /// Every path then needs a tag, and all the methods of a path need the same one.
+    pub fn with_tag_prefix(mut self) -> Self {
+        self.tag_prefix = true;
+        self
+    }
+
+    fn into_paths(self) -> Result<Vec<(String, PathItem)>, MergeError> {
+        self.api.paths.paths.into_iter()
+            .map(|(path, item)| {
+                let tag = if self.tag_prefix {
+                    // why tf is it an Option<Vec<_>> ??? it's always a 1-element vec anyway
+                    let tag = item.operations.values()
+                        .find_map(|operation| operation.tags.as_ref()?.first().cloned())
+                        .ok_or(MergeError::MissingTag { path: path.clone() })?;
+                    format!("/{}", tag)
+                } else {
+                    String::new()
+                };
+
+                // `/` at `/dogs` is just `/dogs`, not `/dogs/`
+                let path_rest = if path == "/" { "" } else { &path };
+                let full_path = format!("{}{}{}", self.prefix, tag, path_rest);
+
+                Ok((if full_path.is_empty() { "/".to_string() } else { full_path }, item))
+            })
+            .collect()
+    }
+}
+
+/// Merges all the `apis` into a single document described by `info`.
+///
+/// Different methods of the same path can come from different apis, the same method can't. Tags are kept once by name,
+/// identical schemas, responses and security schemes (like the shared `Problem`) are fine to have in more than 1 api.
+pub fn merge_openapis(info: Info, apis: impl IntoIterator<Item = NestedApi>) -> Result<OpenApi, MergeError> {
+    let mut paths = Paths::new();
+    let mut components = Components::new();
+    let mut tags = Vec::new();
+    let mut security = Vec::new();
+
+    for nested in apis {
+        let api_components = nested.api.components.clone().unwrap_or_default();
+        let api_tags = nested.api.tags.clone().unwrap_or_default();
+        let api_security = nested.api.security.clone().unwrap_or_default();
+
+        for (path, item) in nested.into_paths()? {
+            merge_path(&mut paths, path, item)?;
+        }
+
+        merge_named(&mut components.schemas, api_components.schemas, |name| MergeError::SchemaConflict { name })?;
+        merge_named(&mut components.responses, api_components.responses, |name| MergeError::ResponseConflict { name })?;
+        merge_named(&mut components.security_schemes, api_components.security_schemes, |name| MergeError::SecuritySchemeConflict { name })?;
+
+        for tag in api_tags {
+            if !tags.iter().any(|t: &utoipa::openapi::Tag| t.name == tag.name) {
+                tags.push(tag);
+            }
+        }
+        for requirement in api_security {
+            if !security.contains(&requirement) {
+                security.push(requirement);
+            }
+        }
+    }
+
+    let openapi = OpenApiBuilder::new()
+        .info(info)
+        .paths(paths)
+        .components(Some(components))
+        .tags((!tags.is_empty()).then_some(tags))
+        .security((!security.is_empty()).then_some(security))
+        .build();
+
+    Ok(openapi)
+}
+
+fn merge_path(paths: &mut Paths, path: String, item: PathItem) -> Result<(), MergeError> {
+    let Some(existing) = paths.paths.get_mut(&path) else {
+        paths.paths.insert(path, item);
+        return Ok(());
+    };
+
+    for (method, operation) in item.operations {
+        if existing.operations.contains_key(&method) {
+            return Err(MergeError::PathConflict { method: method_name(&method), path });
+        }
+        existing.operations.insert(method, operation);
+    }
+
+    Ok(())
+}
+
+fn merge_named<V: PartialEq>(into: &mut BTreeMap<String, V>, from: BTreeMap<String, V>, conflict: impl Fn(String) -> MergeError) -> Result<(), MergeError> {
+    for (name, value) in from {
+        match into.get(&name) {
+            Some(existing) if *existing != value => return Err(conflict(name)),
+            Some(_) => {},
+            None => { into.insert(name, value); }
+        }
+    }
+
+    Ok(())
+}
+
+fn method_name(method: &PathItemType) -> &'static str {
+    match method {
+        PathItemType::Get => "GET",
+        PathItemType::Post => "POST",
+        PathItemType::Put => "PUT",
+        PathItemType::Delete => "DELETE",
+        PathItemType::Options => "OPTIONS",
+        PathItemType::Head => "HEAD",
+        PathItemType::Patch => "PATCH",
+        PathItemType::Trace => "TRACE",
+        PathItemType::Connect => "CONNECT",
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+    use utoipa::openapi::{
+        path::OperationBuilder,
+        schema::{ObjectBuilder, SchemaType},
+        security::{Http, HttpAuthScheme, SecurityScheme},
+        ComponentsBuilder, InfoBuilder, PathsBuilder, Response, Schema
+    };
+
+    use super::*;
+
+    fn info() -> Info {
+        InfoBuilder::new().title("test").version("1").build()
+    }
+
+    /// An api with a single `method` at `path`, tagged with `tag` if there is one.
+    fn api(path: &str, method: PathItemType, tag: Option<&str>) -> OpenApi {
+        let mut operation = OperationBuilder::new();
+        if let Some(tag) = tag {
+            operation = operation.tag(tag);
+        }
+
+        OpenApiBuilder::new()
+            .paths(PathsBuilder::new().path(path, PathItem::new(method, operation.build())))
+            .build()
+    }
+
+    fn with_components(mut api: OpenApi, components: ComponentsBuilder) -> OpenApi {
+        api.components = Some(components.build());
+        api
+    }
+
+    fn schema(schema_type: SchemaType) -> Schema {
+        Schema::Object(ObjectBuilder::new().schema_type(schema_type).build())
+    }
+
+    fn merged_paths(apis: Vec<NestedApi>) -> Vec<String> {
+        merge_openapis(info(), apis).unwrap().paths.paths.into_keys().collect()
+    }
+
+    #[test]
+    fn paths_are_prefixed() {
+        let paths = merged_paths(vec![
+            NestedApi::new("/cats/", api("/random", PathItemType::Get, Some("cats"))),
+            NestedApi::new("/dogs", api("/", PathItemType::Get, None)),
+            NestedApi::new("", api("/", PathItemType::Get, None)),
+        ]);
+
+        assert_eq!(paths, ["/", "/cats/random", "/dogs"]);
+    }
+
+    #[test]
+    fn different_methods_of_a_path_are_merged() {
+        let merged = merge_openapis(info(), [
+            NestedApi::new("/cats", api("/", PathItemType::Get, None)),
+            NestedApi::new("/cats", api("/", PathItemType::Post, None)),
+        ]).unwrap();
+
+        let methods = merged.paths.paths["/cats"].operations.keys().map(method_name).collect::<Vec<_>>();
+        assert_eq!(methods, ["GET", "POST"]);
+    }
+
+    #[test]
+    fn the_same_method_of_a_path_conflicts() {
+        let res = merge_openapis(info(), [
+            NestedApi::new("/cats", api("/random", PathItemType::Get, None)),
+            NestedApi::new("", api("/cats/random", PathItemType::Get, None)),
+        ]);
+
+        assert!(matches!(res, Err(MergeError::PathConflict { method: "GET", path }) if path == "/cats/random"));
+    }
+
+    #[test]
+    fn the_tag_goes_between_the_prefix_and_the_path() {
+        let paths = merged_paths(vec![
+            NestedApi::new("/jp2", api("/random", PathItemType::Get, Some("quotes"))).with_tag_prefix(),
+            NestedApi::new("/jp2", api("/", PathItemType::Get, Some("images"))).with_tag_prefix(),
+            NestedApi::new("/jp2", api("/random", PathItemType::Get, Some("images"))),
+        ]);
+
+        assert_eq!(paths, ["/jp2/images", "/jp2/quotes/random", "/jp2/random"]);
+    }
+
+    #[test]
+    fn tag_prefixes_need_a_tag() {
+        let res = merge_openapis(info(), [
+            NestedApi::new("/jp2", api("/random", PathItemType::Get, None)).with_tag_prefix()
+        ]);
+
+        assert!(matches!(res, Err(MergeError::MissingTag { path }) if path == "/random"));
+    }
+
+    #[test]
+    fn identical_components_are_kept_once() {
+        let components = || ComponentsBuilder::new()
+            .schema("Problem", schema(SchemaType::Object))
+            .response("NotFound", Response::new("Not found"))
+            .security_scheme("bearer_auth", SecurityScheme::Http(Http::new(HttpAuthScheme::Bearer)));
+
+        let merged = merge_openapis(info(), [
+            NestedApi::new("/cats", with_components(api("/", PathItemType::Get, None), components().schema("Cat", schema(SchemaType::Object)))),
+            NestedApi::new("/dogs", with_components(api("/", PathItemType::Get, None), components())),
+        ]).unwrap();
+
+        let components = merged.components.unwrap();
+        assert_eq!(components.schemas.keys().collect::<Vec<_>>(), ["Cat", "Problem"]);
+        assert_eq!(components.responses.len(), 1);
+        assert_eq!(components.security_schemes.len(), 1);
+    }
+
+    #[test]
+    fn different_components_with_the_same_name_conflict() {
+        let merge = |first: ComponentsBuilder, second: ComponentsBuilder| merge_openapis(info(), [
+            NestedApi::new("/cats", with_components(api("/", PathItemType::Get, None), first)),
+            NestedApi::new("/dogs", with_components(api("/", PathItemType::Get, None), second)),
+        ]);
+
+        let res = merge(
+            ComponentsBuilder::new().schema("Id", schema(SchemaType::String)),
+            ComponentsBuilder::new().schema("Id", schema(SchemaType::Integer))
+        );
+        assert!(matches!(res, Err(MergeError::SchemaConflict { name }) if name == "Id"));
+
+        let res = merge(
+            ComponentsBuilder::new().response("NotFound", Response::new("Cat not found")),
+            ComponentsBuilder::new().response("NotFound", Response::new("Dog not found"))
+        );
+        assert!(matches!(res, Err(MergeError::ResponseConflict { name }) if name == "NotFound"));
+
+        let res = merge(
+            ComponentsBuilder::new().security_scheme("auth", SecurityScheme::Http(Http::new(HttpAuthScheme::Bearer))),
+            ComponentsBuilder::new().security_scheme("auth", SecurityScheme::Http(Http::new(HttpAuthScheme::Basic)))
+        );
+        assert!(matches!(res, Err(MergeError::SecuritySchemeConflict { name }) if name == "auth"));
+    }
+}